@@ -3,39 +3,600 @@
 //! Implements a stop-and-wait ARQ using postcard + COBS as a serialisation mechanism.
 //!
 //! See README.md for more details.
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+/// Send a diagnostic message to the logger, if there is one. The arguments
+/// aren't even formatted if there isn't.
+macro_rules! trace {
+    ($illyria:expr, $($arg:tt)*) => {
+        if let Some(logger) = &$illyria.logger {
+            logger.trace(format_args!($($arg)*));
+        }
+    };
+}
 
 /// Object for holding protocol state.
-pub struct Illyria<TXM, RXM, TXO, RXI, TXLEN, RXLEN>
-where
-    TXM: serde::ser::Serialize,
-    RXM: serde::de::DeserializeOwned,
+///
+/// `TXLEN` and `RXLEN` are the sizes of the transmit and receive buffers, in
+/// bytes. `run_rx_slice` can queue up `RXQ - 1` received messages (as with
+/// `heapless::spsc::Queue`), and `LARGE` is the size of the scratch buffers
+/// used by `send_large`.
+///
+/// `WINDOW` is how many messages `send` can have in flight at once. The
+/// default of 0 (or 1) gives stop-and-wait. With more, each message is
+/// numbered, the far end ACKs the next number it wants, and if an ACK doesn't
+/// arrive everything from the oldest un-ACKed message onwards is sent again
+/// (go-back-N). See "Sliding Window" in the README.
+///
+/// `ACK` and `RAW` are the types of the functions given to `set_on_ack` and
+/// `set_on_raw_frame`.
+pub struct Illyria<
+    TXM,
+    RXM,
+    TXO,
+    RXI,
+    const TXLEN: usize,
+    const RXLEN: usize,
+    CODEC = Postcard,
+    const RXQ: usize = 2,
+    const LARGE: usize = 0,
+    LOG = NoLogger,
+    const WINDOW: usize = 0,
+    ACK = AckCallback,
+    RAW = RawFrameCallback,
+> where
+    CODEC: PayloadCodec<TXM, RXM>,
     TXO: embedded_hal::serial::Write<u8>,
     RXI: embedded_hal::serial::Read<u8>,
     TXO::Error: core::fmt::Debug,
     RXI::Error: core::fmt::Debug,
-    RXLEN: heapless::ArrayLength<u8>,
-    TXLEN: heapless::ArrayLength<u8>,
+    LOG: Logger,
+    ACK: FnMut(Colour),
+    RAW: FnMut(&[u8], u32),
 {
     poll_limit: u32,
+    timeout_mode: TimeoutMode,
+    backoff: BackoffPolicy,
+    tx_sent_at: u64,
+    rx_slice_budget: Option<usize>,
     writer: TXO,
     reader: RXI,
-    tx_buffer: heapless::Vec<u8, TXLEN>,
-    sframe_pending: Option<&'static [u8]>,
+    tx_buffer: TxBuffer<TXLEN>,
+    /// The numbered frames `send` has queued in windowed mode, oldest first
+    /// from `window_head`. Each stays here until it has been ACKed.
+    window: [heapless::Vec<u8, TXLEN>; WINDOW],
+    window_head: usize,
+    window_len: usize,
+    /// The number the next frame queued in the window gets.
+    tx_seq: u8,
+    /// The number of the next frame in the window to put on the wire.
+    window_next: u8,
+    /// How many times `run_tx` has been polled since the window was last
+    /// sent (or ACKed).
+    window_polls: u32,
+    /// The number of the next numbered frame we want from the far end.
+    rx_seq: u8,
+    sframe_pending: Option<u8>,
+    /// The checksum of the frame the pending ACK is for.
+    ack_checksum: u32,
+    sframe: [u8; CHECKSUM_OVERHEAD + 2 * MAX_CHECKSUM_LEN],
+    checksum_covers: ChecksumCovers,
+    crc_seed: u16,
+    checksum_width: ChecksumWidth,
+    double_delimiters: bool,
+    framing: Framing,
+    /// How much of the sync word we've sent (see `Framing::LengthPrefixed`).
+    tx_sync_sent: usize,
+    nack_unknown_frames: bool,
+    /// The frame type our pending NACK-unsupported is for.
+    unsupported_frame: u8,
+    nack_truncated_frames: bool,
+    coloured_acks: bool,
+    piggyback_acks: bool,
+    ack_checksums: bool,
+    glitch_limit: Option<u32>,
+    glitch_gap_ms: u64,
+    crc_failures: u32,
+    rx_resyncing: bool,
+    tx_zero_sent: bool,
+    flush_on_send: bool,
+    flush_at_frame_end: bool,
+    /// The writer wasn't ready to flush at the end of the last frame.
+    tx_flush_pending: bool,
+    max_flush_failures: Option<u32>,
+    flush_failures: u32,
+    /// The last delimiter of the frame has gone, and we're waiting for a
+    /// flush to succeed (see `set_max_flush_failures`).
+    tx_verifying: bool,
+    /// The tx_buffer holds a frame which is already COBS encoded.
+    tx_forwarding: bool,
+    rx_zero_pending: bool,
     rx_buffer: heapless::Vec<u8, RXLEN>,
+    rx_queue: heapless::spsc::Queue<(RXM, Colour), RXQ>,
+    large_tx: heapless::Vec<u8, LARGE>,
+    large_tx_sent: usize,
+    large_rx: heapless::Vec<u8, LARGE>,
+    large_rx_discard: bool,
+    rx_payload_large: bool,
     tx_state: TxState,
     next_tx_colour: Colour,
+    next_broadcast_colour: Colour,
+    broadcast_repeats: u8,
     rx_state: RxState,
+    cobs_decoder: cobs::Decoder,
     rx_colour: Colour,
-    _phantom: core::marker::PhantomData<(TXM, RXM)>,
+    rx_last_colour: Option<Colour>,
+    last_broadcast: Option<Colour>,
+    rx_payload_ready: bool,
+    tx_paused: bool,
+    peer_paused: bool,
+    paused_at: u64,
+    held_polls: u32,
+    rx_paused: bool,
+    rx_recovered: bool,
+    flow_pending: Option<u8>,
+    frames_received: u32,
+    last_rx_frame: Option<u8>,
+    stats: Stats,
+    bytes_transmitted: u64,
+    bytes_received: u64,
+    clock: Option<Clock>,
+    max_in_flight_ms: Option<u64>,
+    max_nacks: Option<u32>,
+    max_retries: Option<u32>,
+    retries: u32,
+    link_dead: bool,
+    connected: bool,
+    nacks: u32,
+    max_duplicates: Option<u32>,
+    duplicates: u32,
+    duplicate_policy: DuplicatePolicy,
+    tx_queued_at: u64,
+    ack_delay_ms: Option<u64>,
+    keepalive_ms: Option<u64>,
+    tx_last_byte_at: u64,
+    ack_scheduled_at: u64,
+    last_rx_byte_at: Option<u64>,
+    on_ack: Option<ACK>,
+    tx_result: Option<TxResult>,
+    on_raw_frame: Option<RAW>,
+    logger: Option<LOG>,
+    tx_wrote_byte: bool,
+    /// Everything we've written since the last `drain_tx`.
+    #[cfg(feature = "testing")]
+    tx_capture: alloc::vec::Vec<u8>,
+    _phantom: core::marker::PhantomData<(TXM, RXM, CODEC)>,
+}
+
+/// Builds an Illyria object, from `Illyria::builder`. `POLL_LIMIT` says
+/// whether a poll limit has been given yet - `build` needs one.
+pub struct Builder<I, const POLL_LIMIT: bool> {
+    illyria: I,
+}
+
+impl<
+        TXM,
+        RXM,
+        TXO,
+        RXI,
+        const TXLEN: usize,
+        const RXLEN: usize,
+        CODEC,
+        const RXQ: usize,
+        const LARGE: usize,
+        LOG,
+        const WINDOW: usize,
+        ACK,
+        RAW,
+        const POLL_LIMIT: bool,
+    >
+    Builder<
+        Illyria<TXM, RXM, TXO, RXI, TXLEN, RXLEN, CODEC, RXQ, LARGE, LOG, WINDOW, ACK, RAW>,
+        POLL_LIMIT,
+    >
+where
+    CODEC: PayloadCodec<TXM, RXM>,
+    TXO: embedded_hal::serial::Write<u8>,
+    RXI: embedded_hal::serial::Read<u8>,
+    TXO::Error: core::fmt::Debug,
+    RXI::Error: core::fmt::Debug,
+    LOG: Logger,
+    ACK: FnMut(Colour),
+    RAW: FnMut(&[u8], u32),
+{
+    /// Set the poll limit (see `new`).
+    #[allow(clippy::type_complexity)]
+    pub fn poll_limit(
+        mut self,
+        poll_limit: u32,
+    ) -> Builder<
+        Illyria<TXM, RXM, TXO, RXI, TXLEN, RXLEN, CODEC, RXQ, LARGE, LOG, WINDOW, ACK, RAW>,
+        true,
+    > {
+        self.illyria.set_poll_limit(poll_limit);
+        Builder {
+            illyria: self.illyria,
+        }
+    }
+
+    /// Limit how many times a frame is re-sent (see `set_max_retries`). The
+    /// default is to re-send forever.
+    pub fn max_retries(mut self, max: u32) -> Self {
+        self.illyria.set_max_retries(Some(max));
+        self
+    }
+
+    /// Set the colour of the first I-Frame we send. The default is Purple,
+    /// which the far end always accepts.
+    pub fn initial_colour(mut self, colour: Colour) -> Self {
+        self.illyria.next_tx_colour = colour;
+        self
+    }
+
+    /// Give it a clock (see `set_clock`).
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.illyria.set_clock(clock);
+        self
+    }
+
+    /// Choose how frames are marked out (see `set_framing`).
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.illyria.set_framing(framing);
+        self
+    }
+
+    /// Mark frames with two zero bytes (see `set_double_delimiters`).
+    pub fn double_delimiters(mut self, double: bool) -> Self {
+        self.illyria.set_double_delimiters(double);
+        self
+    }
+
+    /// Choose which bytes the checksum covers (see `set_checksum_covers`).
+    pub fn checksum_covers(mut self, covers: ChecksumCovers) -> Self {
+        self.illyria.set_checksum_covers(covers);
+        self
+    }
+
+    /// Choose which checksum each frame carries (see
+    /// `set_checksum_width`).
+    pub fn checksum_width(mut self, width: ChecksumWidth) -> Self {
+        self.illyria.set_checksum_width(width);
+        self
+    }
+
+    /// Set the seed for the checksum (see `set_crc_seed`).
+    pub fn crc_seed(mut self, seed: u16) -> Self {
+        self.illyria.set_crc_seed(seed);
+        self
+    }
+
+    /// Send coloured ACKs (see `set_coloured_acks`).
+    pub fn coloured_acks(mut self, coloured: bool) -> Self {
+        self.illyria.set_coloured_acks(coloured);
+        self
+    }
+
+    /// Let ACKs ride along with I-Frames (see `set_piggyback_acks`).
+    pub fn piggyback_acks(mut self, piggyback: bool) -> Self {
+        self.illyria.set_piggyback_acks(piggyback);
+        self
+    }
+
+    /// Put checksums in our ACKs (see `set_ack_checksums`).
+    pub fn ack_checksums(mut self, ack_checksums: bool) -> Self {
+        self.illyria.set_ack_checksums(ack_checksums);
+        self
+    }
+
+    /// Hold back each ACK for up to `delay` (see `set_ack_delay`).
+    pub fn ack_delay(mut self, delay: core::time::Duration) -> Self {
+        self.illyria.set_ack_delay(Some(delay));
+        self
+    }
+
+    /// NACK frame types we don't understand (see
+    /// `set_nack_unknown_frames`).
+    pub fn nack_unknown_frames(mut self, nack: bool) -> Self {
+        self.illyria.set_nack_unknown_frames(nack);
+        self
+    }
+
+    /// NACK every truncated frame (see `set_nack_truncated_frames`).
+    pub fn nack_truncated_frames(mut self, nack: bool) -> Self {
+        self.illyria.set_nack_truncated_frames(nack);
+        self
+    }
+
+    /// Send a zero byte every `interval` while idle (see
+    /// `set_idle_keepalive`).
+    pub fn idle_keepalive(mut self, interval: core::time::Duration) -> Self {
+        self.illyria.set_idle_keepalive(Some(interval));
+        self
+    }
+
+    /// Choose whether to flush the writer in `send` (see
+    /// `set_flush_on_send`).
+    pub fn flush_on_send(mut self, flush: bool) -> Self {
+        self.illyria.set_flush_on_send(flush);
+        self
+    }
+
+    /// Flush the writer at the end of each frame (see
+    /// `set_flush_at_frame_end`).
+    pub fn flush_at_frame_end(mut self, flush: bool) -> Self {
+        self.illyria.set_flush_at_frame_end(flush);
+        self
+    }
+
+    /// Limit how many flushes in a row may fail (see
+    /// `set_max_flush_failures`).
+    pub fn max_flush_failures(mut self, max: u32) -> Self {
+        self.illyria.set_max_flush_failures(Some(max));
+        self
+    }
+
+    /// Limit how long a frame may wait to be ACKed (see
+    /// `set_max_in_flight_time`).
+    pub fn max_in_flight_time(mut self, max: core::time::Duration) -> Self {
+        self.illyria.set_max_in_flight_time(Some(max));
+        self
+    }
+
+    /// Limit how many NACKs in a row a frame may get (see `set_max_nacks`).
+    pub fn max_nacks(mut self, max: u32) -> Self {
+        self.illyria.set_max_nacks(Some(max));
+        self
+    }
+
+    /// Limit how many duplicates in a row we may receive (see
+    /// `set_max_duplicates`).
+    pub fn max_duplicates(mut self, max: u32) -> Self {
+        self.illyria.set_max_duplicates(Some(max));
+        self
+    }
+
+    /// Choose what to do with duplicates (see `set_duplicate_policy`).
+    pub fn duplicate_policy(mut self, policy: DuplicatePolicy) -> Self {
+        self.illyria.set_duplicate_policy(policy);
+        self
+    }
+
+    /// Choose how we wait for an ACK (see `set_timeout_mode`).
+    pub fn timeout_mode(mut self, mode: TimeoutMode) -> Self {
+        self.illyria.set_timeout_mode(mode);
+        self
+    }
+
+    /// Choose how the wait grows with each re-send (see `set_backoff`).
+    pub fn backoff(mut self, backoff: BackoffPolicy) -> Self {
+        self.illyria.set_backoff(backoff);
+        self
+    }
+
+    /// Recover from glitches on the line (see `set_glitch_recovery`).
+    pub fn glitch_recovery(mut self, after_failures: u32, idle_gap: core::time::Duration) -> Self {
+        self.illyria
+            .set_glitch_recovery(Some(after_failures), idle_gap);
+        self
+    }
+
+    /// Limit how much `run_rx_slice` does at once (see
+    /// `set_rx_slice_budget`).
+    pub fn rx_slice_budget(mut self, max_bytes: usize) -> Self {
+        self.illyria.set_rx_slice_budget(Some(max_bytes));
+        self
+    }
+
+    /// Set the logger (see `set_logger`).
+    pub fn logger(mut self, logger: LOG) -> Self {
+        self.illyria.set_logger(Some(logger));
+        self
+    }
+
+    /// Set a function to call when a message is ACKed (see `set_on_ack`).
+    pub fn on_ack(mut self, on_ack: ACK) -> Self {
+        self.illyria.set_on_ack(Some(on_ack));
+        self
+    }
+
+    /// Set a function to call with every frame received (see
+    /// `set_on_raw_frame`).
+    pub fn on_raw_frame(mut self, on_raw_frame: RAW) -> Self {
+        self.illyria.set_on_raw_frame(Some(on_raw_frame));
+        self
+    }
+}
+
+impl<
+        TXM,
+        RXM,
+        TXO,
+        RXI,
+        const TXLEN: usize,
+        const RXLEN: usize,
+        CODEC,
+        const RXQ: usize,
+        const LARGE: usize,
+        LOG,
+        const WINDOW: usize,
+        ACK,
+        RAW,
+    >
+    Builder<
+        Illyria<TXM, RXM, TXO, RXI, TXLEN, RXLEN, CODEC, RXQ, LARGE, LOG, WINDOW, ACK, RAW>,
+        true,
+    >
+where
+    CODEC: PayloadCodec<TXM, RXM>,
+    TXO: embedded_hal::serial::Write<u8>,
+    RXI: embedded_hal::serial::Read<u8>,
+    TXO::Error: core::fmt::Debug,
+    RXI::Error: core::fmt::Debug,
+    LOG: Logger,
+    ACK: FnMut(Colour),
+    RAW: FnMut(&[u8], u32),
+{
+    /// Finish building.
+    pub fn build(
+        self,
+    ) -> Illyria<TXM, RXM, TXO, RXI, TXLEN, RXLEN, CODEC, RXQ, LARGE, LOG, WINDOW, ACK, RAW> {
+        self.illyria
+    }
+}
+
+/// A function which returns the current time, in milliseconds. It doesn't
+/// matter when zero was, but it must not go backwards (wrapping around is
+/// fine).
+pub type Clock = fn() -> u64;
+
+/// How frames are marked out in the byte stream, from `set_framing`. Both
+/// ends of the link must use the same framing.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Framing {
+    /// Each frame is COBS encoded, so it contains no zero bytes, and a zero
+    /// byte marks each end. This is the default. A receiver can always find
+    /// the start of the next frame, whatever happened to the last one.
+    Cobs,
+    /// Each frame is sent as it is, after the two byte `SYNC_WORD`, and the
+    /// receiver uses the frame's length byte to find the end. This saves the
+    /// COBS overhead, but if bytes are lost the receiver may not find the
+    /// next sync word until after the next frame has started. It suits
+    /// reliable wired links.
+    LengthPrefixed,
+}
+
+/// What `run_rx` does with a duplicate I-Frame, from
+/// `set_duplicate_policy`. Either way, the duplicate is ACKed (the far end
+/// needs to know it arrived) and its payload isn't handed back again.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Drop it without a word, so each message is delivered at most once.
+    /// This is the default.
+    Drop,
+    /// Return `RxEvent::Duplicate`, so you can keep an eye on how often the
+    /// far end has to re-send.
+    Report,
+}
+
+/// The bytes which start every frame with `Framing::LengthPrefixed`.
+pub const SYNC_WORD: [u8; 2] = [0xAA, 0x55];
+
+/// How we decide that an ACK isn't coming, and a frame must be re-sent.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TimeoutMode {
+    /// Count calls to `run_tx`, up to the poll limit. This is the default,
+    /// and needs no clock.
+    Polls,
+    /// Wait this long after the frame was sent. This needs a clock (see
+    /// `set_clock`) - without one, we count polls instead.
+    Elapsed(core::time::Duration),
+}
+
+/// How the wait for an ACK changes as a frame is re-sent, from
+/// `set_backoff`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BackoffPolicy {
+    /// Wait the same time before every re-send. This is the default.
+    Constant,
+    /// Multiply the wait by `base` after each re-send, up to `max` times the
+    /// first wait. With a `base` of 2, we wait one timeout before the first
+    /// re-send, two before the second, four before the third, and so on.
+    Exponential { base: u32, max: u32 },
 }
 
+/// A function which is called when the far end ACKs one of our messages. It
+/// is given the colour the message was sent with.
+///
+/// This is the default for `ACK`. Any `FnMut(Colour)` will do instead, such
+/// as a closure which captures some state, or a `&mut dyn FnMut(Colour)`.
+pub type AckCallback = fn(Colour);
+
+/// A function which is called with every complete frame we receive, before
+/// its checksum is checked. It is given the frame type, length and payload
+/// (un-COBS'd), and the checksum which came with them.
+///
+/// This is the default for `RAW`. Any `FnMut(&[u8], u32)` will do instead.
+pub type RawFrameCallback = fn(&[u8], u32);
+
+/// The first frame-type byte reserved for application-defined control frames.
+/// Every frame-type byte from here up to and including 0xFF can be used with
+/// `send_control`.
+pub const CONTROL_FRAME_FIRST: u8 = 0x80;
+
 #[derive(Debug)]
 pub enum WaitingForAckNack {
     Yes,
     No,
 }
 
+/// What happened to the last frame we sent, from `take_tx_result`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TxResult {
+    /// The far end ACKed it.
+    Acked,
+    /// The far end NACKed it, so we'll send it again.
+    Nacked,
+    /// Nothing came back in time, so we're sending it again.
+    TimedOut,
+    /// An ACK or NACK arrived which wasn't for the frame in flight (if any),
+    /// so we ignored it.
+    Ignored,
+    /// The far end doesn't understand this type of frame (see
+    /// `set_nack_unknown_frames`), so we dropped it rather than send it
+    /// again.
+    Unsupported,
+}
+
+/// Counts of what's happened on the link, from `stats`. They all wrap
+/// around at `u32::MAX`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Stats {
+    /// I-Frames we've put on the wire, including re-sends.
+    pub frames_sent: u32,
+    /// I-Frames the far end has ACKed.
+    pub frames_acked: u32,
+    /// I-Frames we've had to re-send, after a NACK or a timeout.
+    pub retransmits: u32,
+    /// Frames which arrived with a bad checksum.
+    pub crc_errors: u32,
+    /// Duplicate I-Frames (coloured or numbered) we've ACKed and thrown
+    /// away.
+    pub duplicates_dropped: u32,
+    /// The coloured duplicates which had a good checksum and the colour of
+    /// the last I-Frame we accepted. The far end re-sent them because it
+    /// never got our ACK, so these count ACKs lost on the way back, rather
+    /// than frames lost on the way here. A duplicate of any other colour
+    /// means the far end is out of step, and isn't counted here.
+    pub colour_mismatches: u32,
+    /// NACKs we've sent.
+    pub nacks_sent: u32,
+    /// NACKs we've received, whether or not we were expecting one.
+    pub nacks_received: u32,
+    /// ACKs we've received, whether or not we were expecting one.
+    pub acks_received: u32,
+    /// Frames cut short by a frame delimiter.
+    pub framing_errors: u32,
+}
+
+/// How the link looks from the TX side, from `link_state`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LinkState {
+    /// Nothing to send.
+    Idle,
+    /// A frame is being sent, or waiting to be ACKed.
+    InFlight,
+    /// The frame has been re-sent this many times in a row without an ACK.
+    Retrying(u32),
+    /// The far end NACKed the frame, and it's waiting to be sent again (for
+    /// example, while TX is paused, or while we send an ACK of our own).
+    AwaitingResend,
+    /// We gave up re-sending a frame (see `set_max_retries`).
+    Dead,
+}
+
 /// The possible errors Illyria can return
 #[derive(Debug)]
 pub enum Error<TXE, RXE>
@@ -46,36 +607,382 @@ where
     TransportWouldBlock,
     PacketInFlight,
     MessageTooLarge,
-    Postcard(postcard::Error),
+    InvalidFrameType,
+    /// The frame in the TX buffer was not acknowledged within the maximum
+    /// in-flight time, and has been dropped.
+    FrameExpired,
+    /// The far end NACKed the frame in the TX buffer more times in a row
+    /// than the NACK limit allows, and it has been dropped.
+    TooManyNacks,
+    /// The far end didn't ACK the frame in the TX buffer, however many
+    /// times we re-sent it (see `set_max_retries`). The frame has been
+    /// dropped, and the link is dead.
+    LinkDead,
+    /// The writer failed to flush more times in a row than
+    /// `set_max_flush_failures` allows. The transmitter is probably broken.
+    /// The frame we were sending has been dropped.
+    WriterFault,
+    /// A message arrived which is too big for the buffer given to
+    /// `poll_into`.
+    BufferTooSmall,
+    /// `poll_into` can't hand back messages which `run_rx_slice` (or one of
+    /// the other functions which queue messages) has already decoded. Get
+    /// them from `run_rx` or `poll` first.
+    MessagesQueued,
+    Codec(CodecError),
     Writer(TXE),
     Reader(RXE),
 }
 
+/// The things `run_rx` can hand back to the application.
+#[derive(Debug)]
+pub enum RxEvent<'a, RXM> {
+    /// A new (i.e. not duplicate) message has been received.
+    Message(RXM),
+    /// An application-defined control frame has been received. The payload
+    /// is only valid until the next call to `run_rx`.
+    Control { type_byte: u8, payload: &'a [u8] },
+    /// A frame delimiter arrived part way through a frame, before we had
+    /// received all the bytes the frame's length byte promised. Bytes have
+    /// probably been lost on the wire. The partial frame has been dropped.
+    TruncatedFrame,
+    /// A whole frame arrived, but with a bad checksum. Bytes have probably
+    /// been corrupted on the wire. The frame has been NACKed and dropped.
+    CorruptFrame,
+    /// The far end sent a RESET or SYNC frame, so we have called `resync`.
+    /// Anything we had queued to send has been dropped.
+    Reset,
+    /// Too many frames in a row have failed their checksum (see
+    /// `set_glitch_recovery`). Everything received is now ignored until the
+    /// line has been quiet for a while.
+    GlitchRecovery,
+    /// A frame arrived with a valid checksum, but of a different width to
+    /// ours (see `set_checksum_width`). The far end is configured
+    /// differently to us. The frame has been dropped.
+    ChecksumWidthMismatch,
+    /// Too many I-Frames in a row have been dropped as duplicates (see
+    /// `set_max_duplicates`). The link might be stuck.
+    LinkStalled,
+    /// The far end re-sent a message we had already received, probably
+    /// because our ACK got lost. It has been ACKed again, but its payload
+    /// isn't handed back. Only returned with `DuplicatePolicy::Report`.
+    Duplicate { colour: Colour },
+    /// A frame arrived with a valid checksum, but a frame type we don't
+    /// understand. The far end is probably running a newer version of the
+    /// protocol. The frame has been dropped.
+    UnknownFrame(u8),
+}
+
+/// What `run_rx` returns, for a writer of type `TXO` and a reader of type
+/// `RXI`.
+pub type RxResult<'a, M, TXO, RXI> = Result<
+    Option<RxEvent<'a, M>>,
+    Error<
+        <TXO as embedded_hal::serial::Write<u8>>::Error,
+        <RXI as embedded_hal::serial::Read<u8>>::Error,
+    >,
+>;
+
+/// What `poll` returns, for a writer of type `TXO` and a reader of type
+/// `RXI`.
+pub type PollResult<M, TXO, RXI> = Result<
+    Option<ReceivedMessage<M>>,
+    Error<
+        <TXO as embedded_hal::serial::Write<u8>>::Error,
+        <RXI as embedded_hal::serial::Read<u8>>::Error,
+    >,
+>;
+
+/// How far `run_rx_n` got.
+#[derive(Debug)]
+pub enum RxProgress<'a, RXM> {
+    /// The reader had nothing more for us. Any bytes read before it blocked
+    /// have been processed.
+    Blocked,
+    /// A frame was completed, and this is what `run_rx` would have returned
+    /// for it. Any bytes after it are left for the next call.
+    FrameReady(RxEvent<'a, RXM>),
+    /// We read this many bytes (the whole budget) without completing a
+    /// frame.
+    BytesConsumed(usize),
+}
+
+/// A message from `poll`, with a little more about how it arrived.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceivedMessage<M> {
+    pub message: M,
+    /// The colour of the frame it arrived in (the last fragment, for a large
+    /// message).
+    pub colour: Colour,
+    /// The far end re-sent a message we had already received - its ACK
+    /// probably got lost. The application has already seen this one.
+    pub duplicate: bool,
+}
+
+/// A snapshot of what the TX state machine is doing, from
+/// `tx_state_debug`. This is for diagnostics only - the details may change.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TxStateInfo {
+    Idle,
+    SendingDelimiterStart {
+        frame: FrameKind,
+    },
+    SendingCobsHeader {
+        frame: FrameKind,
+    },
+    /// `sent` is how many bytes of the (un-COBS'd) frame have been sent.
+    SendingPayload {
+        frame: FrameKind,
+        sent: usize,
+    },
+    SendingDelimiterEnd {
+        frame: FrameKind,
+    },
+    WaitingForAckNack {
+        num_polls: u32,
+    },
+}
+
+/// The sort of frame the TX state machine is sending.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FrameKind {
+    /// A frame which must be ACKed - a message, or a RESET.
+    IFrame,
+    /// An application-defined control frame.
+    Control,
+    /// A broadcast message, which isn't ACKed.
+    Broadcast,
+    /// A frame passed to `forward_frame`.
+    Forwarded,
+    /// An ACK or NACK.
+    SFrame,
+}
+
+/// A snapshot of what the RX state machine is doing, from
+/// `rx_state_debug`. This is for diagnostics only - the details may change.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RxStateInfo {
+    /// With `Framing::LengthPrefixed`, this means we want the start of the
+    /// sync word.
+    WantFrameDelimiter,
+    WantCobsHeader,
+    /// With `Framing::LengthPrefixed`, we want the end of the sync word.
+    WantSync,
+    WantFrameType,
+    WantLength {
+        frame_type: u8,
+    },
+    /// `received` counts the frame type and length bytes, as well as the
+    /// payload bytes received so far.
+    WantPayload {
+        frame_type: u8,
+        length: usize,
+        received: usize,
+    },
+    /// `received` is how many checksum bytes have arrived so far.
+    WantChecksum {
+        frame_type: u8,
+        received: usize,
+    },
+}
+
+/// Converts messages to and from the bytes carried in the payload of an
+/// I-Frame. The framing, checksum and retry logic don't care what's in the
+/// payload, so you can use any serialisation format you like. The default is
+/// `Postcard`.
+pub trait PayloadCodec<TXM, RXM> {
+    /// Encode `message` into `buffer`, returning how many bytes were used.
+    fn encode(message: &TXM, buffer: &mut [u8]) -> Result<usize, CodecError>;
+
+    /// Decode a message from the payload of a received I-Frame.
+    fn decode(buffer: &[u8]) -> Result<RXM, CodecError>;
+
+    /// Decode a message into an existing one, so any storage it has can be
+    /// re-used. The default just calls `decode` and overwrites `message`.
+    fn decode_into(buffer: &[u8], message: &mut RXM) -> Result<(), CodecError> {
+        *message = Self::decode(buffer)?;
+        Ok(())
+    }
+}
+
+/// The errors a `PayloadCodec` can report.
+#[derive(Debug)]
+pub enum CodecError {
+    /// The encoded message did not fit in the buffer.
+    BufferFull,
+    /// The payload did not contain a valid message.
+    Invalid,
+    /// The postcard codec failed.
+    Postcard(postcard::Error),
+}
+
+/// A `PayloadCodec` which uses `postcard`. Messages must implement serde's
+/// `Serialize` (to send) and `DeserializeOwned` (to receive).
+#[derive(Debug, Copy, Clone)]
+pub struct Postcard;
+
+impl<TXM, RXM> PayloadCodec<TXM, RXM> for Postcard
+where
+    TXM: serde::ser::Serialize,
+    RXM: serde::de::DeserializeOwned,
+{
+    fn encode(message: &TXM, buffer: &mut [u8]) -> Result<usize, CodecError> {
+        postcard::to_slice(message, buffer)
+            .map(|buf| buf.len())
+            .map_err(CodecError::Postcard)
+    }
+
+    fn decode(buffer: &[u8]) -> Result<RXM, CodecError> {
+        postcard::from_bytes(buffer).map_err(CodecError::Postcard)
+    }
+
+    /// Uses serde's in-place deserialisation. Types which don't support it
+    /// (which includes `#[derive(Deserialize)]` types, unless serde_derive's
+    /// `deserialize_in_place` feature is on) are just overwritten.
+    fn decode_into(buffer: &[u8], message: &mut RXM) -> Result<(), CodecError> {
+        let mut deserializer = postcard::Deserializer::from_bytes(buffer);
+        serde::Deserialize::deserialize_in_place(&mut deserializer, message)
+            .map_err(CodecError::Postcard)
+    }
+}
+
+/// Somewhere to send diagnostic messages, such as state changes and frames
+/// arriving. Implement this to send them to a UART, a ring buffer, or
+/// wherever you like, and pass it to `set_logger`. Nothing is formatted
+/// unless a logger has been set.
+pub trait Logger {
+    /// Handle one message. The default does nothing.
+    fn trace(&self, _args: core::fmt::Arguments) {}
+}
+
+/// A `Logger` which throws everything away. This is the default.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoLogger;
+
+impl Logger for NoLogger {}
+
+/// A future which is pending the first time it is polled, and ready the
+/// next. `send_frame` and `recv_frame` await this whenever the transport
+/// would block, so other tasks get a go before we try again. It wakes us
+/// straight away, so we never sleep.
+#[cfg(feature = "async")]
+struct YieldNow(bool);
+
+#[cfg(feature = "async")]
+impl core::future::Future for YieldNow {
+    type Output = ();
+
+    fn poll(
+        mut self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<()> {
+        if self.0 {
+            core::task::Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            core::task::Poll::Pending
+        }
+    }
+}
+
+/// A `Logger` which prints everything to stdout. Handy for debugging a link
+/// on a PC.
+#[cfg(feature = "std")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct StdoutLogger;
+
+#[cfg(feature = "std")]
+impl Logger for StdoutLogger {
+    fn trace(&self, args: core::fmt::Arguments) {
+        println!("{}", args);
+    }
+}
+
+/// What a received byte completed, if anything.
+enum Received {
+    Nothing,
+    /// A new message is waiting to be decoded from the receive buffer (or
+    /// the large message buffer).
+    Message,
+    /// A duplicate I-Frame was dropped. Its payload is still in the receive
+    /// buffer.
+    Duplicate,
+    Control(u8),
+    Unknown(u8),
+    Truncated,
+    Corrupt,
+    Reset,
+    GlitchRecovery,
+    ChecksumWidthMismatch,
+    Stalled,
+}
+
 #[derive(Debug, Copy, Clone)]
 enum Payload {
     IFrame,
-    SFrame(&'static [u8]),
+    Control,
+    /// Sent the given number of times more after this one.
+    Broadcast {
+        repeats: u8,
+    },
+    /// Already COBS encoded, so sent as-is.
+    Forward,
+    /// A copy of a numbered frame from the window.
+    Windowed,
+    /// An ACK or NACK. If we were waiting for an ACK of our own when we
+    /// started sending it, we go back to waiting afterwards, having been
+    /// polled the given number of times.
+    SFrame {
+        resume_polls: Option<u32>,
+    },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 enum TxState {
     Idle,
-    SendingDelimiterStart { payload: Payload },
-    SendingCobsHeader { payload: Payload },
-    SendingPayload { payload: Payload, sent: usize },
-    SendingDelimiterEnd { payload: Payload },
-    WaitingForAckNack { num_polls: u32 },
+    SendingDelimiterStart {
+        payload: Payload,
+    },
+    SendingCobsHeader {
+        payload: Payload,
+    },
+    /// `left` is how many more data bytes there are in the current COBS
+    /// block, and `full` says whether it's a full one (code 0xFF).
+    SendingPayload {
+        payload: Payload,
+        sent: usize,
+        left: u8,
+        full: bool,
+    },
+    SendingDelimiterEnd {
+        payload: Payload,
+    },
+    WaitingForAckNack {
+        num_polls: u32,
+    },
 }
 
 #[derive(Debug)]
 enum RxState {
     WantFrameDelimiter,
     WantCobsHeader,
-    WantFrameType { cobs: u8 },
-    WantLength { cobs: u8, frame: u8 },
-    WantPayload { cobs: u8, frame: u8, length: usize },
-    WantChecksumFirst { cobs: u8, frame: u8 },
-    WantChecksumSecond { cobs: u8, frame: u8, csum_first: u8 },
+    /// Have the first byte of the sync word, want the second (see
+    /// `Framing::LengthPrefixed`).
+    WantSync,
+    WantFrameType,
+    WantLength {
+        frame: u8,
+    },
+    WantPayload {
+        frame: u8,
+        length: usize,
+    },
+    WantChecksum {
+        frame: u8,
+        csum: u32,
+        received: usize,
+    },
 }
 
 /// We colour our packets in order to detect duplicates. There are red packets
@@ -83,7 +990,7 @@ enum RxState {
 /// wants next, with a special case of 'Purple' to handle the case of either
 /// end rebooting and not knowing what should be sent/received next.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum Colour {
+pub enum Colour {
     /// Red packets will only be seen by a Red or Purple receiver. A Blue receiver will drop them as duplicates.
     Red,
     /// Blue packets will only be seen by a Blue or Purple receiver. A Red receiver will drop them as duplicates.
@@ -95,6 +1002,18 @@ enum Colour {
     Purple,
 }
 
+impl From<Payload> for FrameKind {
+    fn from(payload: Payload) -> FrameKind {
+        match payload {
+            Payload::IFrame | Payload::Windowed => FrameKind::IFrame,
+            Payload::Control => FrameKind::Control,
+            Payload::Broadcast { .. } => FrameKind::Broadcast,
+            Payload::Forward => FrameKind::Forwarded,
+            Payload::SFrame { .. } => FrameKind::SFrame,
+        }
+    }
+}
+
 impl Colour {
     fn next(self) -> Colour {
         match self {
@@ -109,991 +1028,9018 @@ impl Colour {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-struct Checksum(u16);
+/// The type of a frame, as given by its header byte (see the README for the
+/// values).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FrameType {
+    /// A whole message, or the last part of a large one.
+    IFrame(Colour),
+    /// An I-Frame which also ACKs the last frame the far end sent us (see
+    /// `set_piggyback_acks`).
+    IFrameAck(Colour),
+    /// Part of a large message, with more to follow.
+    Fragment(Colour),
+    /// Acknowledges an I-Frame. ACKs are Purple unless coloured ACKs are
+    /// turned on.
+    Ack(Colour),
+    Nack,
+    /// Tells the sender that we don't understand the frame type carried in
+    /// its one payload byte, so there's no point sending it again.
+    NackUnsupported,
+    Reset,
+    /// A message which isn't ACKed. Broadcasts are never Purple, as a Purple
+    /// frame is never treated as a duplicate - a Purple broadcast is sent as
+    /// a Blue one.
+    Broadcast(Colour),
+    /// A numbered I-Frame, sent in windowed mode (see `WINDOW`). The first
+    /// payload byte is the sequence number.
+    SeqIFrame,
+    /// Acknowledges every numbered I-Frame before the one whose number it
+    /// carries.
+    SeqAck,
+    /// Asks the sender not to start any new I-Frames, as a frame didn't fit
+    /// in our receive buffer.
+    Pause,
+    /// Lets the sender start new I-Frames again, after a `Pause`.
+    Resume,
+    /// Starts the link (see `connect`). The receiver resets its ARQ state,
+    /// and answers with a `SyncAck`.
+    Sync,
+    /// Answers a `Sync`, once the receiver has reset its ARQ state.
+    SyncAck,
+    /// An application-defined control frame, with a header byte of
+    /// `CONTROL_FRAME_FIRST` or higher.
+    Control(u8),
+}
 
-impl Checksum {
-    fn generate(data: &[u8]) -> Checksum {
-        let result = Checksum(crc::crc16::checksum_x25(data));
-        result
+impl FrameType {
+    /// Work out the frame type from a header byte. Returns `None` for a
+    /// header we don't understand.
+    pub fn from_u8(header: u8) -> Option<FrameType> {
+        let frame_type = match header {
+            HEADER_RED_IFRAME => FrameType::IFrame(Colour::Red),
+            HEADER_BLUE_IFRAME => FrameType::IFrame(Colour::Blue),
+            HEADER_PURPLE_IFRAME => FrameType::IFrame(Colour::Purple),
+            HEADER_RED_IFRAME_ACK => FrameType::IFrameAck(Colour::Red),
+            HEADER_BLUE_IFRAME_ACK => FrameType::IFrameAck(Colour::Blue),
+            HEADER_PURPLE_IFRAME_ACK => FrameType::IFrameAck(Colour::Purple),
+            HEADER_RED_FRAGMENT => FrameType::Fragment(Colour::Red),
+            HEADER_BLUE_FRAGMENT => FrameType::Fragment(Colour::Blue),
+            HEADER_PURPLE_FRAGMENT => FrameType::Fragment(Colour::Purple),
+            HEADER_RED_ACK => FrameType::Ack(Colour::Red),
+            HEADER_BLUE_ACK => FrameType::Ack(Colour::Blue),
+            HEADER_ACK => FrameType::Ack(Colour::Purple),
+            HEADER_NACK => FrameType::Nack,
+            HEADER_NACK_UNSUPPORTED => FrameType::NackUnsupported,
+            HEADER_RESET => FrameType::Reset,
+            HEADER_RED_BROADCAST => FrameType::Broadcast(Colour::Red),
+            HEADER_BLUE_BROADCAST => FrameType::Broadcast(Colour::Blue),
+            HEADER_SEQ_IFRAME => FrameType::SeqIFrame,
+            HEADER_SEQ_ACK => FrameType::SeqAck,
+            HEADER_PAUSE => FrameType::Pause,
+            HEADER_RESUME => FrameType::Resume,
+            HEADER_SYNC => FrameType::Sync,
+            HEADER_SYNCACK => FrameType::SyncAck,
+            CONTROL_FRAME_FIRST..=0xFF => FrameType::Control(header),
+            _ => return None,
+        };
+        Some(frame_type)
     }
 
-    fn validate(self, data: &[u8]) -> bool {
-        crc::crc16::checksum_x25(data) == self.0
+    /// The header byte for this frame type.
+    pub fn to_u8(self) -> u8 {
+        match self {
+            FrameType::IFrame(Colour::Red) => HEADER_RED_IFRAME,
+            FrameType::IFrame(Colour::Blue) => HEADER_BLUE_IFRAME,
+            FrameType::IFrame(Colour::Purple) => HEADER_PURPLE_IFRAME,
+            FrameType::IFrameAck(Colour::Red) => HEADER_RED_IFRAME_ACK,
+            FrameType::IFrameAck(Colour::Blue) => HEADER_BLUE_IFRAME_ACK,
+            FrameType::IFrameAck(Colour::Purple) => HEADER_PURPLE_IFRAME_ACK,
+            FrameType::Fragment(Colour::Red) => HEADER_RED_FRAGMENT,
+            FrameType::Fragment(Colour::Blue) => HEADER_BLUE_FRAGMENT,
+            FrameType::Fragment(Colour::Purple) => HEADER_PURPLE_FRAGMENT,
+            FrameType::Ack(Colour::Red) => HEADER_RED_ACK,
+            FrameType::Ack(Colour::Blue) => HEADER_BLUE_ACK,
+            FrameType::Ack(Colour::Purple) => HEADER_ACK,
+            FrameType::Nack => HEADER_NACK,
+            FrameType::NackUnsupported => HEADER_NACK_UNSUPPORTED,
+            FrameType::Reset => HEADER_RESET,
+            FrameType::Broadcast(Colour::Red) => HEADER_RED_BROADCAST,
+            FrameType::Broadcast(Colour::Blue) | FrameType::Broadcast(Colour::Purple) => {
+                HEADER_BLUE_BROADCAST
+            }
+            FrameType::SeqIFrame => HEADER_SEQ_IFRAME,
+            FrameType::SeqAck => HEADER_SEQ_ACK,
+            FrameType::Pause => HEADER_PAUSE,
+            FrameType::Resume => HEADER_RESUME,
+            FrameType::Sync => HEADER_SYNC,
+            FrameType::SyncAck => HEADER_SYNCACK,
+            FrameType::Control(header) => header,
+        }
     }
+}
 
-    fn first_byte(self) -> u8 {
-        (self.0 >> 8) as u8
-    }
+/// What kind of frame we received most recently, from `last_rx_frame`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RxFrameKind {
+    /// A frame type we understand.
+    Known(FrameType),
+    /// A valid frame with a header byte we don't understand. If you see
+    /// these, the far end is probably running a different version of the
+    /// protocol.
+    Unknown(u8),
+}
 
-    fn second_byte(self) -> u8 {
-        self.0 as u8
+impl From<u8> for RxFrameKind {
+    fn from(header: u8) -> RxFrameKind {
+        match FrameType::from_u8(header) {
+            Some(frame_type) => RxFrameKind::Known(frame_type),
+            None => RxFrameKind::Unknown(header),
+        }
     }
 }
 
-impl<TXM, RXM, TXO, RXI, TXLEN, RXLEN> Illyria<TXM, RXM, TXO, RXI, TXLEN, RXLEN>
-where
-    TXM: serde::ser::Serialize,
-    RXM: serde::de::DeserializeOwned,
-    TXO: embedded_hal::serial::Write<u8>,
-    RXI: embedded_hal::serial::Read<u8>,
-    TXO::Error: core::fmt::Debug,
-    RXI::Error: core::fmt::Debug,
-    RXLEN: heapless::ArrayLength<u8>,
-    TXLEN: heapless::ArrayLength<u8>,
-{
-    const FRAME_TYPE_IDX: usize = 0;
-    const PAYLOAD_LENGTH_IDX: usize = 1;
-    const DATA_IDX: usize = 2;
-
-    /// We checksum the payload length, plus 2 bytes (the frame type and the
-    /// length byte)
-    const CHECKSUM_OVERHEAD: usize = 2;
-
-    /// Frame overhead comprises the checksum overhead, plus two bytes of
-    /// checksum.
-    const FRAME_OVERHEAD: usize = Self::CHECKSUM_OVERHEAD + 2;
-
-    const HEADER_RED_IFRAME: u8 = 0x21;
-    const HEADER_BLUE_IFRAME: u8 = 0x11;
-    const HEADER_PURPLE_IFRAME: u8 = 0x01;
-    const HEADER_ACK: u8 = 0x02;
-    const HEADER_NACK: u8 = 0x03;
+/// Which bytes the checksum is calculated over. Both ends of the link must
+/// agree on this, or every frame will fail its checksum.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChecksumCovers {
+    /// The frame type, length and payload, before COBS encoding. This is the
+    /// default.
+    Decoded,
+    /// The frame type, length and payload after COBS encoding them on their
+    /// own (i.e. as if they were the whole frame). This is for talking to
+    /// devices which checksum what they see on the wire.
+    Encoded,
+}
 
-    /// Manually encoded Red ACK packet, which never changes. We could render it
-    /// into the tx_buffer but keeping it separate lets us cache a packet for
-    /// TX while we send an ACK.
-    const SFRAME_ACK: [u8; 4] = [Self::HEADER_ACK, 0, 0x3C, 0xF7];
+/// Which checksum each frame carries, and so how big it is. Both ends of the
+/// link must agree on this, or every frame will be rejected.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChecksumWidth {
+    /// Two bytes of CRC-16/X.25. This is the default.
+    Crc16,
+    /// Two bytes of CRC-16/CCITT-FALSE, for talking to devices which use
+    /// that instead.
+    Crc16CcittFalse,
+    /// Four bytes of CRC-32 (the IEEE polynomial, as used by Ethernet).
+    Crc32,
+    /// An algorithm of your own - see `ChecksumWidth::custom`.
+    Custom(ChecksumAlgorithm),
+}
 
-    /// Manually encoded Purple NACK packet, which never changes. We could render it
-    /// into the tx_buffer but keeping it separate lets us cache a packet for
-    /// TX while we send an NACK.
-    const SFRAME_NACK: [u8; 4] = [Self::HEADER_NACK, 0, 0x3C, 0xF7];
+impl ChecksumWidth {
+    /// Use your own `FrameChecksum` implementation.
+    ///
+    /// Panics if `C::width()` isn't between one and four bytes.
+    pub fn custom<C: FrameChecksum + 'static>() -> ChecksumWidth {
+        ChecksumWidth::Custom(ChecksumAlgorithm::of::<C>())
+    }
 
-    pub fn new(writer: TXO, reader: RXI, poll_limit: u32) -> Illyria<TXM, RXM, TXO, RXI, TXLEN, RXLEN> {
-        Illyria {
-            poll_limit,
-            writer,
-            reader,
-            tx_buffer: heapless::Vec::new(),
-            sframe_pending: None,
-            rx_buffer: heapless::Vec::new(),
-            tx_state: TxState::Idle,
-            next_tx_colour: Colour::Purple,
-            rx_state: RxState::WantFrameDelimiter,
-            rx_colour: Colour::Purple,
-            _phantom: core::marker::PhantomData
+    fn algorithm(self) -> ChecksumAlgorithm {
+        match self {
+            ChecksumWidth::Crc16 => ChecksumAlgorithm::of::<Crc16X25>(),
+            ChecksumWidth::Crc16CcittFalse => ChecksumAlgorithm::of::<Crc16CcittFalse>(),
+            ChecksumWidth::Crc32 => ChecksumAlgorithm::of::<Crc32Ieee>(),
+            ChecksumWidth::Custom(algorithm) => algorithm,
         }
     }
 
-    pub fn space(&self) -> usize {
-        self.tx_buffer.capacity() - Self::FRAME_OVERHEAD
+    /// How many bytes of checksum there are.
+    fn len(self) -> usize {
+        self.algorithm().width
     }
 
-    pub fn send(&mut self, message: &TXM) -> Result<(), Error<TXO::Error, RXI::Error>> {
-        if self.tx_buffer.len() != 0 {
-            return Err(Error::PacketInFlight);
-        }
-        match self.tx_state {
-            TxState::Idle
-            | TxState::SendingDelimiterStart {
-                payload: Payload::SFrame(_),
-                ..
-            }
-            | TxState::SendingCobsHeader {
-                payload: Payload::SFrame(_),
-                ..
-            }
-            | TxState::SendingPayload {
-                payload: Payload::SFrame(_),
-                ..
-            }
-            | TxState::SendingDelimiterEnd {
-                payload: Payload::SFrame(_),
-                ..
-            } => {
-                let _err = self.writer.flush();
-                self.tx_buffer
-                    .resize_default(self.tx_buffer.capacity())
-                    .unwrap();
-                let usable = self.tx_buffer.len() - 2;
-                match postcard::to_slice(message, &mut self.tx_buffer[Self::DATA_IDX..usable])
-                    .map(|buf| buf.len())
-                {
-                    Ok(payload_len) => {
-                        // Build a complete frame (it definitely fits)
-                        self.tx_buffer[Self::FRAME_TYPE_IDX] = match self.next_tx_colour {
-                            Colour::Red => Self::HEADER_RED_IFRAME,
-                            Colour::Blue => Self::HEADER_BLUE_IFRAME,
-                            Colour::Purple => Self::HEADER_PURPLE_IFRAME,
-                        };
-                        self.tx_buffer[Self::PAYLOAD_LENGTH_IDX] = payload_len as u8;
-                        let checksum_idx =
-                            Self::FRAME_TYPE_IDX + Self::CHECKSUM_OVERHEAD + payload_len;
-                        let checksum =
-                            Checksum::generate(&self.tx_buffer[Self::FRAME_TYPE_IDX..checksum_idx]);
-                        self.tx_buffer[checksum_idx] = checksum.first_byte();
-                        self.tx_buffer[checksum_idx + 1] = checksum.second_byte();
-                        self.tx_buffer.truncate(Self::FRAME_OVERHEAD + payload_len);
-                        Ok(())
-                    }
-                    Err(e) => Err(Error::Postcard(e)),
-                }
-            }
-            _ => Err(Error::PacketInFlight),
-        }
+    fn update(self, crc: u32, bytes: &[u8]) -> u32 {
+        (self.algorithm().update)(crc, bytes)
     }
 
-    pub fn reset(&mut self) {
-        self.tx_state = TxState::Idle;
+    /// Turn the result of `update` into the checksum we send.
+    fn finish(self, crc: u32) -> u32 {
+        (self.algorithm().finish)(crc)
     }
+}
 
-    fn writer_write(&mut self, byte: u8) -> Result<(), Error<TXO::Error, RXI::Error>> {
-        match self.writer.write(byte) {
-            Ok(()) => Ok(()),
-            Err(nb::Error::WouldBlock) => Err(Error::TransportWouldBlock),
-            Err(nb::Error::Other(e)) => Err(Error::Writer(e)),
-        }
+/// A checksum algorithm for the end of each frame. Implement this and pass
+/// `ChecksumWidth::custom::<YourChecksum>()` to `set_checksum_width` to use
+/// one we don't have built in.
+pub trait FrameChecksum {
+    /// The checksum itself, e.g. `u16`. It must be able to hold any number
+    /// which fits in `width()` bytes.
+    type Value: Copy + Into<u32> + core::convert::TryFrom<u32>;
+
+    /// How many bytes of checksum each frame carries. At most four.
+    fn width() -> usize;
+
+    /// Carry on checksumming from `crc` over `data`. The first call gets the
+    /// seed (see `set_crc_seed`), which is usually zero, and the result is
+    /// passed back in to checksum more bytes.
+    fn update(crc: Self::Value, data: &[u8]) -> Self::Value;
+
+    /// Turn the result of `update` into the checksum we send. The default
+    /// returns it unchanged.
+    fn finish(crc: Self::Value) -> Self::Value {
+        crc
     }
 
-    fn reader_read(&mut self) -> Result<u8, Error<TXO::Error, RXI::Error>> {
-        match self.reader.read() {
-            Ok(b) => Ok(b),
-            Err(nb::Error::WouldBlock) => Err(Error::TransportWouldBlock),
-            Err(nb::Error::Other(e)) => Err(Error::Reader(e)),
-        }
+    /// Checksum `data` in one go, with a seed of zero.
+    fn compute(data: &[u8]) -> Self::Value {
+        Self::finish(Self::update(value_of::<Self>(0), data))
     }
 
-    pub fn cobs_find_zero(&self, source: &[u8]) -> usize {
-        let mut num = source.len();
-        for (i, &b) in source.iter().enumerate() {
-            if b == 0 {
-                num = i;
-                break;
-            } else if i == 254 {
-                num = 254;
+    /// Write `value` into `dest`, which is `width()` bytes long. The default
+    /// puts the most significant byte first.
+    fn to_bytes(value: Self::Value, dest: &mut [u8]) {
+        let value: u32 = value.into();
+        for (i, b) in dest.iter_mut().rev().enumerate() {
+            *b = (value >> (8 * i)) as u8;
+        }
+    }
+}
+
+/// A `FrameChecksum`, with its types rubbed out so `ChecksumWidth` can hold
+/// it. Make one with `ChecksumWidth::custom`.
+#[derive(Debug, Copy, Clone)]
+pub struct ChecksumAlgorithm {
+    /// Which `FrameChecksum` this is. Comparing the function pointers
+    /// instead wouldn't be reliable.
+    id: core::any::TypeId,
+    width: usize,
+    update: fn(u32, &[u8]) -> u32,
+    finish: fn(u32) -> u32,
+    to_bytes: fn(u32, &mut [u8]),
+}
+
+impl PartialEq for ChecksumAlgorithm {
+    fn eq(&self, other: &ChecksumAlgorithm) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for ChecksumAlgorithm {}
+
+impl ChecksumAlgorithm {
+    fn of<C: FrameChecksum + 'static>() -> ChecksumAlgorithm {
+        assert!((1..=MAX_CHECKSUM_LEN).contains(&C::width()));
+        ChecksumAlgorithm {
+            id: core::any::TypeId::of::<C>(),
+            width: C::width(),
+            update: |crc, bytes| C::update(value_of::<C>(crc), bytes).into(),
+            finish: |crc| C::finish(value_of::<C>(crc)).into(),
+            to_bytes: |crc, dest| C::to_bytes(value_of::<C>(crc), dest),
+        }
+    }
+}
+
+/// Turn a `u32` back into a `FrameChecksum::Value`. We only ever hold values
+/// which came from one (or a seed), so after trimming to `width()` bytes this
+/// can only fail if `Value` is too small for the width.
+fn value_of<C: FrameChecksum + ?Sized>(crc: u32) -> C::Value {
+    let crc = match C::width() {
+        w if w < 4 => crc & ((1 << (8 * w)) - 1),
+        _ => crc,
+    };
+    match <C::Value as core::convert::TryFrom<u32>>::try_from(crc) {
+        Ok(value) => value,
+        Err(_) => panic!("FrameChecksum::Value is smaller than its width"),
+    }
+}
+
+/// Two bytes of CRC-16/X.25, for `ChecksumWidth::Crc16`.
+#[derive(Debug, Copy, Clone)]
+pub struct Crc16X25;
+
+impl FrameChecksum for Crc16X25 {
+    type Value = u16;
+
+    fn width() -> usize {
+        2
+    }
+
+    fn update(crc: u16, data: &[u8]) -> u16 {
+        crc::crc16::update(crc, &crc::crc16::X25_TABLE, data)
+    }
+}
+
+/// Two bytes of CRC-16/CCITT-FALSE, for `ChecksumWidth::Crc16CcittFalse`.
+#[derive(Debug, Copy, Clone)]
+pub struct Crc16CcittFalse;
+
+impl FrameChecksum for Crc16CcittFalse {
+    type Value = u16;
+
+    fn width() -> usize {
+        2
+    }
+
+    fn update(crc: u16, data: &[u8]) -> u16 {
+        crc16_ccitt_false(crc, data)
+    }
+
+    /// No final XOR, so undo the one `crc16_ccitt_false` did
+    fn finish(crc: u16) -> u16 {
+        !crc
+    }
+}
+
+/// Four bytes of CRC-32, for `ChecksumWidth::Crc32`.
+#[derive(Debug, Copy, Clone)]
+pub struct Crc32Ieee;
+
+impl FrameChecksum for Crc32Ieee {
+    type Value = u32;
+
+    fn width() -> usize {
+        4
+    }
+
+    fn update(crc: u32, data: &[u8]) -> u32 {
+        crc::crc32::update(crc, &crc::crc32::IEEE_TABLE, data)
+    }
+}
+
+/// The `crc` crate only does reflected CRC-16s, so we do this one by hand.
+/// Like `crc::crc16::update`, the value is inverted on the way in and out, so
+/// a `crc` of zero gives the standard initial value of `0xFFFF` and the
+/// result can be passed back in to checksum more bytes. CRC-16/CCITT-FALSE
+/// has no final XOR, so `Crc16CcittFalse::finish` inverts the result again.
+fn crc16_ccitt_false(crc: u16, bytes: &[u8]) -> u16 {
+    let mut crc = !crc;
+    for &b in bytes {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    !crc
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Checksum(u32);
+
+impl Checksum {
+    /// A `seed` of zero gives the standard CRC.
+    fn generate(data: &[u8], covers: ChecksumCovers, seed: u16, width: ChecksumWidth) -> Checksum {
+        match covers {
+            ChecksumCovers::Decoded => Checksum(width.finish(width.update(seed as u32, data))),
+            ChecksumCovers::Encoded => Self::generate_encoded(data, seed, width),
+        }
+    }
+
+    /// Checksum the COBS encoding of `data`, without actually needing
+    /// somewhere to put the encoded bytes.
+    fn generate_encoded(data: &[u8], seed: u16, width: ChecksumWidth) -> Checksum {
+        let mut crc = seed as u32;
+        let mut rest = data;
+        loop {
+            let run = rest
+                .iter()
+                .take(254)
+                .position(|&b| b == 0)
+                .unwrap_or_else(|| core::cmp::min(rest.len(), 254));
+            crc = width.update(crc, &[run as u8 + 1]);
+            crc = width.update(crc, &rest[..run]);
+            if run == rest.len() && run < 254 {
                 break;
+            } else if run == 254 {
+                // A full block - there's no zero to skip
+                rest = &rest[run..];
+            } else {
+                rest = &rest[run + 1..];
             }
         }
-        num
+        Checksum(width.finish(crc))
     }
 
-    /// Pumps the TX state machine. Returns `true` if it makes sense to call this function again right away.
-    /// Returns `false` if we're stuck waiting for an ack and you should wait a while before trying again.
-    pub fn run_tx(&mut self) -> Result<WaitingForAckNack, Error<TXO::Error, RXI::Error>> {
-        let mut result = WaitingForAckNack::No;
-        self.tx_state = match self.tx_state {
-            TxState::Idle => {
-                // Do nothing
-                if self.tx_buffer.len() != 0 {
-                    TxState::SendingDelimiterStart {
-                        payload: Payload::IFrame,
-                    }
-                } else if let Some(frame) = self.sframe_pending.take() {
-                    TxState::SendingDelimiterStart {
-                        payload: Payload::SFrame(frame),
-                    }
-                } else {
-                    TxState::Idle
+    fn validate(
+        self,
+        data: &[u8],
+        covers: ChecksumCovers,
+        seed: u16,
+        width: ChecksumWidth,
+    ) -> bool {
+        // What we received was read in most significant byte first, so
+        // compare it with what we would have sent, read in the same way
+        let mut expected = [0u8; MAX_CHECKSUM_LEN];
+        Self::generate(data, covers, seed, width).write_to(width, &mut expected);
+        let expected = expected[..width.len()]
+            .iter()
+            .fold(0u32, |acc, &b| (acc << 8) | b as u32);
+        expected == self.0
+    }
+
+    /// Write out the checksum, in the order its `FrameChecksum` wants.
+    fn write_to(self, width: ChecksumWidth, dest: &mut [u8]) {
+        (width.algorithm().to_bytes)(self.0, &mut dest[..width.len()])
+    }
+}
+
+const FRAME_TYPE_IDX: usize = 0;
+const PAYLOAD_LENGTH_IDX: usize = 1;
+const DATA_IDX: usize = 2;
+
+/// We checksum the payload length, plus 2 bytes (the frame type and the
+/// length byte)
+const CHECKSUM_OVERHEAD: usize = 2;
+
+/// Frame overhead comprises the checksum overhead, plus two bytes of
+/// checksum (when using the default `ChecksumWidth::Crc16`).
+const FRAME_OVERHEAD: usize = CHECKSUM_OVERHEAD + 2;
+
+/// The most checksum bytes any `ChecksumWidth` needs.
+const MAX_CHECKSUM_LEN: usize = 4;
+
+/// The frame-type (header) byte of every frame Illyria sends, for tools
+/// which pick apart captured traffic. `FrameType::from_u8` does the same job
+/// for code which uses the rest of the crate.
+///
+/// The byte is laid out as follows:
+///
+/// * Bits 0 to 3 say what kind of frame it is: 0x1 is an I-Frame, 0x2 an
+///   ACK, 0x3 a NACK, 0x4 a RESET, 0x5 a fragment (an I-Frame with bit 2
+///   set), 0x7 a NACK-unsupported, 0x9 a broadcast (an I-Frame with bit 3
+///   set), 0xA a PAUSE, 0xB a RESUME, 0xC a SYNC and 0xD a SYNC-ACK.
+/// * Bits 4 and 5 give the colour (see `COLOUR_MASK`): 0b00 is Purple, 0b01
+///   Blue and 0b10 Red. ACKs are only coloured with `set_coloured_acks`.
+///   0b11 marks the numbered frames used in windowed mode, which have no
+///   colour.
+/// * Bit 6 is set on an I-Frame which also ACKs the last frame the far end
+///   sent (see `set_piggyback_acks`).
+/// * Bit 7 is set on application-defined control frames, whose other bits
+///   are up to the application.
+pub mod wire {
+    pub use super::CONTROL_FRAME_FIRST;
+
+    /// The bits of the frame-type byte which give the colour.
+    pub const COLOUR_MASK: u8 = 0x30;
+    /// The colour bits of a Purple frame.
+    pub const COLOUR_PURPLE: u8 = 0x00;
+    /// The colour bits of a Blue frame.
+    pub const COLOUR_BLUE: u8 = 0x10;
+    /// The colour bits of a Red frame.
+    pub const COLOUR_RED: u8 = 0x20;
+    /// The bit which marks an I-Frame carrying a piggybacked ACK.
+    pub const PIGGYBACK_ACK: u8 = 0x40;
+
+    pub const HEADER_RED_IFRAME: u8 = 0x21;
+    pub const HEADER_BLUE_IFRAME: u8 = 0x11;
+    pub const HEADER_PURPLE_IFRAME: u8 = 0x01;
+    pub const HEADER_ACK: u8 = 0x02;
+    pub const HEADER_RED_ACK: u8 = 0x22;
+    pub const HEADER_BLUE_ACK: u8 = 0x12;
+    pub const HEADER_NACK: u8 = 0x03;
+    pub const HEADER_RESET: u8 = 0x04;
+    pub const HEADER_NACK_UNSUPPORTED: u8 = 0x07;
+    pub const HEADER_RED_FRAGMENT: u8 = 0x25;
+    pub const HEADER_BLUE_FRAGMENT: u8 = 0x15;
+    pub const HEADER_PURPLE_FRAGMENT: u8 = 0x05;
+    pub const HEADER_RED_BROADCAST: u8 = 0x29;
+    pub const HEADER_BLUE_BROADCAST: u8 = 0x19;
+    pub const HEADER_RED_IFRAME_ACK: u8 = 0x61;
+    pub const HEADER_BLUE_IFRAME_ACK: u8 = 0x51;
+    pub const HEADER_PURPLE_IFRAME_ACK: u8 = 0x41;
+    pub const HEADER_SEQ_IFRAME: u8 = 0x31;
+    pub const HEADER_SEQ_ACK: u8 = 0x32;
+    pub const HEADER_PAUSE: u8 = 0x0A;
+    pub const HEADER_RESUME: u8 = 0x0B;
+    pub const HEADER_SYNC: u8 = 0x0C;
+    pub const HEADER_SYNCACK: u8 = 0x0D;
+}
+
+use wire::*;
+
+/// Is this frame type an ACK (of any colour)?
+fn is_ack(frame_type: u8) -> bool {
+    matches!(FrameType::from_u8(frame_type), Some(FrameType::Ack(_)))
+}
+
+/// The largest payload the length byte can describe.
+const MAX_PAYLOAD: usize = 255;
+
+/// The part of an (un-COBS'd) frame which the checksum covers. This is the
+/// frame type, the length byte and `length` payload bytes - i.e. everything
+/// up to, but not including, the checksum itself. The length byte must
+/// already be filled in, and `frame` must be at least this long.
+///
+/// Both sending and receiving use this, so they can't disagree.
+fn checksummed_region(frame: &[u8]) -> &[u8] {
+    let payload_len = frame[PAYLOAD_LENGTH_IDX] as usize;
+    &frame[FRAME_TYPE_IDX..DATA_IDX + payload_len]
+}
+
+/// Fill in the frame type, length and checksum around a payload which has
+/// already been written into `buffer` at `DATA_IDX`. Returns the length of
+/// the complete (un-COBS'd) frame.
+fn build_frame(
+    buffer: &mut [u8],
+    frame_type: u8,
+    payload_len: usize,
+    covers: ChecksumCovers,
+    seed: u16,
+    width: ChecksumWidth,
+) -> usize {
+    buffer[FRAME_TYPE_IDX] = frame_type;
+    buffer[PAYLOAD_LENGTH_IDX] = payload_len as u8;
+    let region = checksummed_region(buffer);
+    let checksum_idx = region.len();
+    let checksum = Checksum::generate(region, covers, seed, width);
+    checksum.write_to(width, &mut buffer[checksum_idx..]);
+    CHECKSUM_OVERHEAD + payload_len + width.len()
+}
+
+/// The buffer a frame is built in before it is sent. It works like a
+/// `heapless::Vec`, but the bytes past the end of the frame stay put, so
+/// `open` can make the whole buffer available again without zeroing it.
+struct TxBuffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> TxBuffer<N> {
+    fn new() -> TxBuffer<N> {
+        TxBuffer {
+            bytes: [0; N],
+            len: 0,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Make the whole buffer available to build a new frame in. Whatever
+    /// the last frame left behind is still there, and the new frame is
+    /// written over it.
+    fn open(&mut self) {
+        self.len = N;
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.len = core::cmp::min(self.len, len);
+    }
+
+    fn extend_from_slice(&mut self, other: &[u8]) -> Result<(), ()> {
+        let new_len = self.len + other.len();
+        if new_len > N {
+            return Err(());
+        }
+        self.bytes[self.len..new_len].copy_from_slice(other);
+        self.len = new_len;
+        Ok(())
+    }
+}
+
+impl<const N: usize> core::ops::Deref for TxBuffer<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl<const N: usize> core::ops::DerefMut for TxBuffer<N> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes[..self.len]
+    }
+}
+
+/// The most bytes COBS encoding can add to `payload_len` bytes of data, not
+/// counting the `0x00` delimiters either side.
+///
+/// A frame carrying `n` bytes of payload is `n + 4` bytes long before
+/// encoding (see `max_payload`), so it takes up at most
+/// `n + 4 + cobs_max_overhead(n + 4) + 2` bytes on the wire.
+pub const fn cobs_max_overhead(payload_len: usize) -> usize {
+    payload_len.div_ceil(254) + 1
+}
+
+/// COBS encoding and decoding, on their own. Illyria uses COBS to frame
+/// everything it sends, but these are handy for anything else you want to
+/// send over a byte stream (e.g. a logging channel). No `0x00` delimiters are
+/// added or expected.
+pub mod cobs {
+    /// The errors `encode` and `decode` can report.
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub enum CobsError {
+        /// The output buffer is too small.
+        OutputTooSmall,
+        /// The input isn't valid COBS - it contains a zero, or it ends part
+        /// way through a block.
+        Invalid,
+    }
+
+    /// The most data bytes a COBS block can hold (with a code byte of 0xFF).
+    pub(crate) const MAX_RUN: usize = 254;
+
+    /// COBS encode `src` into `dst`, returning how many bytes were written.
+    /// `dst` is always big enough if it's `src.len() +
+    /// cobs_max_overhead(src.len())` bytes long.
+    pub fn encode(src: &[u8], dst: &mut [u8]) -> Result<usize, CobsError> {
+        let mut code_idx = 0;
+        let mut out_idx = 1;
+        let mut code = 1;
+        for &b in src {
+            if b == 0 {
+                put(dst, code_idx, code)?;
+                code_idx = out_idx;
+                out_idx += 1;
+                code = 1;
+            } else {
+                put(dst, out_idx, b)?;
+                out_idx += 1;
+                code += 1;
+                if code == 0xFF {
+                    put(dst, code_idx, code)?;
+                    code_idx = out_idx;
+                    out_idx += 1;
+                    code = 1;
                 }
             }
-            TxState::SendingDelimiterStart { payload } => {
-                self.writer_write(0x00)?;
-                TxState::SendingCobsHeader { payload }
-            }
-            TxState::SendingCobsHeader { payload } => {
-                // Count how many bytes up to the first zero byte.
-                // And send that number
-                let num = match payload {
-                    Payload::IFrame => self.cobs_find_zero(&self.tx_buffer),
-                    Payload::SFrame(frame) => self.cobs_find_zero(frame),
-                };
-                self.writer_write(num as u8 + 1)?;
-                TxState::SendingPayload { payload, sent: 0 }
+        }
+        put(dst, code_idx, code)?;
+        Ok(out_idx)
+    }
+
+    /// Decode the COBS encoded bytes in `src` into `dst`, returning how many
+    /// bytes were written. `dst` never needs to be longer than `src`.
+    pub fn decode(src: &[u8], dst: &mut [u8]) -> Result<usize, CobsError> {
+        let mut decoder = Decoder::new();
+        let mut out_idx = 0;
+        for (i, &b) in src.iter().enumerate() {
+            if b == 0 {
+                return Err(CobsError::Invalid);
             }
-            TxState::SendingPayload { payload, sent } => {
-                // Send the complete frame
-                let source = match payload {
-                    Payload::IFrame => &self.tx_buffer,
-                    Payload::SFrame(frame) => frame,
-                };
-                let len = source.len();
-                let mut b = source[sent];
-                if b == 0 {
-                    // Can't send zeros - send gap to next zero instead
-                    let num = self.cobs_find_zero(&source[sent + 1..]);
-                    b = num as u8 + 1;
-                }
-                self.writer_write(b)?;
-                let new_sent = sent + 1;
-                if new_sent == len {
-                    TxState::SendingDelimiterEnd { payload }
-                } else {
-                    TxState::SendingPayload {
-                        payload,
-                        sent: new_sent,
-                    }
-                }
+            let decoded = if i == 0 {
+                decoder.start(b);
+                None
+            } else {
+                decoder.feed(b)
+            };
+            if let Some(d) = decoded {
+                put(dst, out_idx, d)?;
+                out_idx += 1;
             }
-            TxState::SendingDelimiterEnd { payload } => {
-                self.writer_write(0x00)?;
-                match payload {
-                    Payload::IFrame => TxState::WaitingForAckNack { num_polls: 0 },
-                    Payload::SFrame { .. } => TxState::Idle,
-                }
+        }
+        if decoder.remaining != 0 {
+            return Err(CobsError::Invalid);
+        }
+        Ok(out_idx)
+    }
+
+    fn put(dst: &mut [u8], idx: usize, byte: u8) -> Result<(), CobsError> {
+        *dst.get_mut(idx).ok_or(CobsError::OutputTooSmall)? = byte;
+        Ok(())
+    }
+
+    /// Undoes the COBS encoding on received bytes, one byte at a time.
+    #[derive(Debug, Copy, Clone)]
+    pub(crate) struct Decoder {
+        /// How many more data bytes there are before the next code byte.
+        remaining: u8,
+        /// Was the current block a full one (code 0xFF)? If so, there's no zero
+        /// at the end of it.
+        full_block: bool,
+    }
+
+    impl Decoder {
+        pub(crate) fn new() -> Decoder {
+            Decoder {
+                remaining: 0,
+                full_block: false,
             }
-            TxState::WaitingForAckNack { num_polls } => {
-                if num_polls >= self.poll_limit {
-                    // Poll N times for ack/nack, else retry
-                    TxState::SendingDelimiterStart {
-                        payload: Payload::IFrame,
-                    }
+        }
+
+        /// Start decoding a frame, given the code byte which follows the
+        /// delimiter.
+        pub(crate) fn start(&mut self, code: u8) {
+            self.remaining = code - 1;
+            self.full_block = code == 0xFF;
+        }
+
+        /// Decode the next (non-zero) byte from the wire. Returns `None` if it
+        /// was a code byte which doesn't stand for a zero.
+        pub(crate) fn feed(&mut self, byte: u8) -> Option<u8> {
+            if self.remaining == 0 {
+                let zero = !self.full_block;
+                self.start(byte);
+                if zero {
+                    Some(0)
                 } else {
-                    result = WaitingForAckNack::Yes;
-                    TxState::WaitingForAckNack {
-                        num_polls: num_polls + 1,
-                    }
+                    None
                 }
+            } else {
+                self.remaining -= 1;
+                Some(byte)
             }
-        };
-        Ok(result)
+        }
     }
+}
 
-    fn check_cobs(cobs: u8, next_byte: u8) -> (u8, u8) {
-        if cobs == 1 {
-            (next_byte, 0)
-        } else {
-            (cobs - 1, next_byte)
+/// How many non-zero bytes start `source`, up to the most one COBS block
+/// can hold. The COBS code byte for the block is one more than this.
+///
+/// If there's no zero, we get `source.len()`, as the end of the frame
+/// counts as a zero. A result of `cobs::MAX_RUN` means a full block
+/// (code 0xFF), which has no zero after it, even if the next byte
+/// happens to be one.
+fn cobs_find_zero(source: &[u8]) -> usize {
+    let mut num = source.len();
+    for (i, &b) in source.iter().enumerate() {
+        if b == 0 {
+            num = i;
+            break;
+        } else if i == cobs::MAX_RUN {
+            num = cobs::MAX_RUN;
+            break;
         }
     }
+    num
+}
 
-    pub fn run_rx(&mut self) -> Result<Option<RXM>, Error<TXO::Error, RXI::Error>> {
-        let mut result = None;
-        let next_byte = self.reader_read()?;
-        if next_byte == 0 {
-            // Applies in any state
-            self.rx_state = RxState::WantCobsHeader;
-        } else {
-            self.rx_state = match self.rx_state {
-                RxState::WantFrameDelimiter => RxState::WantFrameDelimiter,
-                RxState::WantCobsHeader => RxState::WantFrameType { cobs: next_byte },
-                RxState::WantFrameType { cobs } => {
-                    let (cobs, next_byte) = Self::check_cobs(cobs, next_byte);
-                    self.rx_buffer.push(next_byte).unwrap();
-                    RxState::WantLength {
-                        cobs,
-                        frame: next_byte,
-                    }
-                }
-                RxState::WantLength { cobs, frame } => {
-                    let (cobs, next_byte) = Self::check_cobs(cobs, next_byte);
-                    self.rx_buffer.push(next_byte).unwrap();
-                    if next_byte == 0 {
-                        // Zero length - skip the payload
-                        RxState::WantChecksumFirst { cobs, frame }
-                    } else {
-                        // Collect a payload first
-                        RxState::WantPayload {
-                            cobs,
-                            frame,
-                            length: next_byte as usize,
-                        }
-                    }
-                }
-                RxState::WantPayload {
-                    cobs,
-                    frame,
-                    length,
-                } => {
-                    if self.rx_buffer.len() == self.rx_buffer.capacity() {
-                        // This packet is too long - drop it on the floor
-                        RxState::WantFrameDelimiter
-                    } else {
-                        let (cobs, next_byte) = Self::check_cobs(cobs, next_byte);
-                        self.rx_buffer.push(next_byte).unwrap();
-                        if self.rx_buffer.len() == length + Self::CHECKSUM_OVERHEAD {
-                            RxState::WantChecksumFirst { cobs, frame }
-                        } else {
-                            RxState::WantPayload {
-                                cobs,
-                                frame,
-                                length,
-                            }
-                        }
-                    }
-                }
-                RxState::WantChecksumFirst { cobs, frame } => {
-                    let (cobs, next_byte) = Self::check_cobs(cobs, next_byte);
-                    RxState::WantChecksumSecond {
-                        cobs,
-                        frame,
-                        csum_first: next_byte,
-                    }
-                }
-                RxState::WantChecksumSecond {
-                    cobs,
-                    frame,
-                    csum_first,
-                } => {
-                    // process packet here
-                    let (_cobs, next_byte) = Self::check_cobs(cobs, next_byte);
-                    let csum = Checksum(((csum_first as u16) << 8) | next_byte as u16);
-                    if csum.validate(&self.rx_buffer) {
-                        // Good packet
-                        match frame {
-                            Self::HEADER_RED_IFRAME => {
-                                // 1. Schedule an ACK (even for duplicates)
-                                self.sframe_pending = Some(&Self::SFRAME_ACK);
-                                // 2. Check if our Red IFRAME is what we expected
-                                if self.rx_colour.matches(Colour::Red) {
-                                    // A. Update our expectation.
-                                    self.rx_colour = Colour::next(Colour::Red);
-                                    // B. Tell the higher layer about it.
-                                    match postcard::from_bytes(&self.rx_buffer[2..]) {
-                                        Ok(m) => {
-                                            result = Some(m)
-                                        }
-                                        Err(e) => {
-                                            panic!("Failed to decode: {:?}", e);
-                                            // Failed to decode message. Drop
-                                            // it on the floor.
-                                        }
-                                    }
-                                }
-                            }
-                            Self::HEADER_BLUE_IFRAME => {
-                                // 1. Schedule an ACK (even for duplicates)
-                                self.sframe_pending = Some(&Self::SFRAME_ACK);
-                                // 2. Check if our Red IFRAME is what we expected
-                                if self.rx_colour.matches(Colour::Blue) {
-                                    // A. Update our expectation.
-                                    self.rx_colour = Colour::Blue.next();
-                                    // B. Tell the higher layer about it.
-                                    match postcard::from_bytes(&self.rx_buffer[2..]) {
-                                        Ok(m) => {
-                                            result = Some(m)
-                                        }
-                                        Err(e) => {
-                                            panic!("Failed to decode: {:?}", e);
-                                            // Failed to decode message. Drop
-                                            // it on the floor.
-                                        }
-                                    }
-                                }
-                            }
-                            Self::HEADER_PURPLE_IFRAME => {
-                                // 1. Schedule an ACK (even for duplicates)
-                                self.sframe_pending = Some(&Self::SFRAME_ACK);
-                                // 2. Check if our Red IFRAME is what we expected
-                                if self.rx_colour.matches(Colour::Purple) {
-                                    // A. Update our expectation.
-                                    self.rx_colour = Colour::Purple.next();
-                                    // B. Tell the higher layer about it.
-                                    match postcard::from_bytes(&self.rx_buffer[2..]) {
-                                        Ok(m) => {
-                                            result = Some(m)
-                                        }
-                                        Err(e) => {
-                                            panic!("Failed to decode: {:?}", e);
-                                            // Failed to decode message. Drop
-                                            // it on the floor.
-                                        }
-                                    }
-                                }
-                            }
-                            Self::HEADER_ACK => {
-                                if let TxState::WaitingForAckNack { .. } = self.tx_state {
-                                    self.next_tx_colour = self.next_tx_colour.next();
-                                    self.tx_state = TxState::Idle;
-                                    self.tx_buffer.truncate(0);
-                                }
-                            }
-                            Self::HEADER_NACK => {
-                                if let TxState::WaitingForAckNack { .. } = self.tx_state {
-                                    self.tx_state = TxState::Idle;
-                                    // leave contents in tx_buffer so we re-send
-                                }
-                            }
-                            _ => {
-                                // Valid, but not understood. This is a protocol error.
-                            }
-                        }
-                    } else {
-                        // Bad packet
-                        self.sframe_pending = Some(&Self::SFRAME_NACK);
-                    }
-                    // Empty the RX buffer
-                    self.rx_buffer.truncate(0);
-                    // Now start over
-                    RxState::WantFrameDelimiter
-                }
-            };
+/// How long an ACK is on the wire, including both delimiters.
+const ACK_WIRE_LEN: usize = FRAME_OVERHEAD + 1 + 2;
+
+/// Produce the on-the-wire bytes (including both COBS delimiters) of the ACK
+/// we would send for an I-Frame of the given colour, using the default
+/// checksum settings and coloured ACKs (see `set_coloured_acks`).
+///
+/// Without coloured ACKs, every ACK looks like the one for a Purple I-Frame.
+/// Use this rather than writing the bytes out by hand, so your tests and
+/// tools keep working if the format changes.
+pub fn expected_ack_frame(colour: Colour) -> [u8; ACK_WIRE_LEN] {
+    let mut frame = [0u8; FRAME_OVERHEAD];
+    build_frame(
+        &mut frame,
+        FrameType::Ack(colour).to_u8(),
+        0,
+        ChecksumCovers::Decoded,
+        0,
+        ChecksumWidth::Crc16,
+    );
+    let mut result = [0u8; ACK_WIRE_LEN];
+    // It fits, as the frame has no zeros apart from the length
+    cobs::encode(&frame, &mut result[1..ACK_WIRE_LEN - 1]).unwrap();
+    result
+}
+
+/// COBS encode `source`, appending the result to `dest`. No delimiters are
+/// added.
+#[cfg(feature = "alloc")]
+fn cobs_encode_to_vec(source: &[u8], dest: &mut alloc::vec::Vec<u8>) {
+    let start = dest.len();
+    dest.resize(start + source.len() + cobs_max_overhead(source.len()), 0);
+    // There's room for the worst case
+    let len = cobs::encode(source, &mut dest[start..]).unwrap();
+    dest.truncate(start + len);
+}
+
+/// Produce the complete on-the-wire bytes (including both COBS delimiters)
+/// for an I-Frame carrying `message`, with the given colour.
+///
+/// This doesn't need an Illyria object, so it's handy for building test
+/// data or replaying traffic to a device from a host.
+#[cfg(feature = "alloc")]
+pub fn encode_to_vec<M>(
+    message: &M,
+    colour: Colour,
+) -> Result<alloc::vec::Vec<u8>, Error<core::convert::Infallible, core::convert::Infallible>>
+where
+    M: serde::ser::Serialize,
+{
+    let mut frame = [0u8; MAX_PAYLOAD + FRAME_OVERHEAD];
+    let usable = frame.len() - 2;
+    let payload_len = postcard::to_slice(message, &mut frame[DATA_IDX..usable])
+        .map_err(|e| Error::Codec(CodecError::Postcard(e)))?
+        .len();
+    let frame_len = build_frame(
+        &mut frame,
+        FrameType::IFrame(colour).to_u8(),
+        payload_len,
+        ChecksumCovers::Decoded,
+        0,
+        ChecksumWidth::Crc16,
+    );
+    let mut result = alloc::vec::Vec::with_capacity(frame_len + cobs_max_overhead(frame_len) + 2);
+    result.push(0);
+    cobs_encode_to_vec(&frame[..frame_len], &mut result);
+    result.push(0);
+    Ok(result)
+}
+
+/// The checksum settings a link uses, for `decode_frame` and
+/// `encode_frame`. The default matches a new Illyria object; use
+/// `Illyria::frame_format` to get the settings of an existing one.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FrameFormat {
+    pub covers: ChecksumCovers,
+    pub seed: u16,
+    pub width: ChecksumWidth,
+}
+
+impl Default for FrameFormat {
+    fn default() -> FrameFormat {
+        FrameFormat {
+            covers: ChecksumCovers::Decoded,
+            seed: 0,
+            width: ChecksumWidth::Crc16,
+        }
+    }
+}
+
+/// A frame taken apart by `decode_frame`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Frame<'a> {
+    /// The frame type, which includes the colour for frames that have one.
+    pub kind: RxFrameKind,
+    pub payload: &'a [u8],
+    /// Whether the checksum matched. The other fields are filled in either
+    /// way.
+    pub checksum_ok: bool,
+}
+
+/// The errors `decode_frame` and `encode_frame` can report.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FrameError {
+    /// The frame's length byte doesn't agree with how many bytes we were
+    /// given.
+    BadLength,
+    /// The payload is too big for one frame.
+    PayloadTooLarge,
+    /// The output buffer is too small.
+    BufferFull,
+}
+
+/// Take apart a whole frame, which has already had its COBS encoding and
+/// delimiters removed. This doesn't need an Illyria object or a message
+/// type, so it suits bridges and test tools which only care about frames.
+pub fn decode_frame(raw: &[u8], format: FrameFormat) -> Result<Frame<'_>, FrameError> {
+    let overhead = CHECKSUM_OVERHEAD + format.width.len();
+    if raw.len() < overhead || raw[PAYLOAD_LENGTH_IDX] as usize != raw.len() - overhead {
+        return Err(FrameError::BadLength);
+    }
+    let region = checksummed_region(raw);
+    let mut csum = 0;
+    for &b in &raw[region.len()..] {
+        csum = (csum << 8) | b as u32;
+    }
+    Ok(Frame {
+        kind: RxFrameKind::from(raw[FRAME_TYPE_IDX]),
+        payload: &region[DATA_IDX..],
+        checksum_ok: Checksum(csum).validate(region, format.covers, format.seed, format.width),
+    })
+}
+
+/// Build a whole frame into `dst`, ready for COBS encoding. Returns how many
+/// bytes of `dst` were used. This is the reverse of `decode_frame`.
+pub fn encode_frame(
+    frame_type: FrameType,
+    payload: &[u8],
+    format: FrameFormat,
+    dst: &mut [u8],
+) -> Result<usize, FrameError> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(FrameError::PayloadTooLarge);
+    }
+    let frame_len = CHECKSUM_OVERHEAD + payload.len() + format.width.len();
+    if dst.len() < frame_len {
+        return Err(FrameError::BufferFull);
+    }
+    dst[DATA_IDX..DATA_IDX + payload.len()].copy_from_slice(payload);
+    Ok(build_frame(
+        dst,
+        frame_type.to_u8(),
+        payload.len(),
+        format.covers,
+        format.seed,
+        format.width,
+    ))
+}
+
+impl<
+        TXM,
+        RXM,
+        TXO,
+        RXI,
+        const TXLEN: usize,
+        const RXLEN: usize,
+        CODEC,
+        const RXQ: usize,
+        const LARGE: usize,
+        LOG,
+        const WINDOW: usize,
+        ACK,
+        RAW,
+    > Illyria<TXM, RXM, TXO, RXI, TXLEN, RXLEN, CODEC, RXQ, LARGE, LOG, WINDOW, ACK, RAW>
+where
+    CODEC: PayloadCodec<TXM, RXM>,
+    TXO: embedded_hal::serial::Write<u8>,
+    RXI: embedded_hal::serial::Read<u8>,
+    TXO::Error: core::fmt::Debug,
+    RXI::Error: core::fmt::Debug,
+    LOG: Logger,
+    ACK: FnMut(Colour),
+    RAW: FnMut(&[u8], u32),
+{
+    /// Create a new Illyria object which uses `Framing::LengthPrefixed`
+    /// rather than COBS. This is the same as calling `new` and then
+    /// `set_framing`.
+    pub fn new_length_prefixed(
+        writer: TXO,
+        reader: RXI,
+        poll_limit: u32,
+    ) -> Illyria<TXM, RXM, TXO, RXI, TXLEN, RXLEN, CODEC, RXQ, LARGE, LOG, WINDOW, ACK, RAW> {
+        let mut illyria = Self::new(writer, reader, poll_limit);
+        illyria.set_framing(Framing::LengthPrefixed);
+        illyria
+    }
+
+    /// Create a new Illyria object which re-sends a frame if it hasn't been
+    /// ACKed within `ack_timeout`, however often you call `run_tx`. This is
+    /// the same as calling `new`, `set_clock` and then `set_timeout_mode`.
+    pub fn new_with_clock(
+        writer: TXO,
+        reader: RXI,
+        clock: Clock,
+        ack_timeout: core::time::Duration,
+    ) -> Illyria<TXM, RXM, TXO, RXI, TXLEN, RXLEN, CODEC, RXQ, LARGE, LOG, WINDOW, ACK, RAW> {
+        let mut illyria = Self::new(writer, reader, 1);
+        illyria.set_clock(clock);
+        illyria.set_timeout_mode(TimeoutMode::Elapsed(ack_timeout));
+        illyria
+    }
+
+    /// Create a new Illyria object.
+    ///
+    /// `poll_limit` is the number of times `run_tx` will be called while
+    /// waiting for an ACK or NACK before the frame is re-sent. The minimum is
+    /// 1 - if you pass 0 it is treated as 1, otherwise we'd re-send before the
+    /// far end had any chance to reply.
+    ///
+    /// This is the same as `builder(writer, reader).poll_limit(poll_limit)`
+    /// followed by `build`.
+    pub fn new(
+        writer: TXO,
+        reader: RXI,
+        poll_limit: u32,
+    ) -> Illyria<TXM, RXM, TXO, RXI, TXLEN, RXLEN, CODEC, RXQ, LARGE, LOG, WINDOW, ACK, RAW> {
+        Self::builder(writer, reader).poll_limit(poll_limit).build()
+    }
+
+    /// Start building a new Illyria object. You must give the `Builder` a
+    /// poll limit (see `new`) before you can `build` it. Anything else you
+    /// don't set has the same default as with `new`.
+    pub fn builder(writer: TXO, reader: RXI) -> Builder<Self, false> {
+        Builder {
+            illyria: Self::with_defaults(writer, reader),
+        }
+    }
+
+    /// An Illyria object with every setting at its default.
+    fn with_defaults(
+        writer: TXO,
+        reader: RXI,
+    ) -> Illyria<TXM, RXM, TXO, RXI, TXLEN, RXLEN, CODEC, RXQ, LARGE, LOG, WINDOW, ACK, RAW> {
+        Illyria {
+            poll_limit: 1,
+            timeout_mode: TimeoutMode::Polls,
+            backoff: BackoffPolicy::Constant,
+            tx_sent_at: 0,
+            rx_slice_budget: None,
+            writer,
+            reader,
+            tx_buffer: TxBuffer::new(),
+            window: [(); WINDOW].map(|_| heapless::Vec::new()),
+            window_head: 0,
+            window_len: 0,
+            tx_seq: 0,
+            window_next: 0,
+            window_polls: 0,
+            rx_seq: 0,
+            sframe_pending: None,
+            ack_checksum: 0,
+            sframe: [0; CHECKSUM_OVERHEAD + 2 * MAX_CHECKSUM_LEN],
+            checksum_covers: ChecksumCovers::Decoded,
+            crc_seed: 0,
+            checksum_width: ChecksumWidth::Crc16,
+            double_delimiters: false,
+            framing: Framing::Cobs,
+            tx_sync_sent: 0,
+            nack_unknown_frames: false,
+            unsupported_frame: 0,
+            nack_truncated_frames: false,
+            coloured_acks: false,
+            piggyback_acks: false,
+            ack_checksums: false,
+            glitch_limit: None,
+            glitch_gap_ms: 0,
+            crc_failures: 0,
+            rx_resyncing: false,
+            tx_zero_sent: false,
+            flush_on_send: true,
+            flush_at_frame_end: false,
+            tx_flush_pending: false,
+            max_flush_failures: None,
+            flush_failures: 0,
+            tx_verifying: false,
+            tx_forwarding: false,
+            rx_zero_pending: false,
+            rx_buffer: heapless::Vec::new(),
+            rx_queue: heapless::spsc::Queue::new(),
+            large_tx: heapless::Vec::new(),
+            large_tx_sent: 0,
+            large_rx: heapless::Vec::new(),
+            large_rx_discard: false,
+            rx_payload_large: false,
+            tx_state: TxState::Idle,
+            next_tx_colour: Colour::Purple,
+            next_broadcast_colour: Colour::Red,
+            broadcast_repeats: 0,
+            rx_state: RxState::WantFrameDelimiter,
+            cobs_decoder: cobs::Decoder::new(),
+            rx_colour: Colour::Purple,
+            rx_last_colour: None,
+            last_broadcast: None,
+            rx_payload_ready: false,
+            tx_paused: false,
+            peer_paused: false,
+            paused_at: 0,
+            held_polls: 0,
+            rx_paused: false,
+            rx_recovered: false,
+            flow_pending: None,
+            frames_received: 0,
+            last_rx_frame: None,
+            stats: Stats::default(),
+            bytes_transmitted: 0,
+            bytes_received: 0,
+            clock: None,
+            max_in_flight_ms: None,
+            max_nacks: None,
+            max_retries: None,
+            tx_result: None,
+            retries: 0,
+            link_dead: false,
+            connected: false,
+            nacks: 0,
+            max_duplicates: None,
+            duplicates: 0,
+            duplicate_policy: DuplicatePolicy::Drop,
+            tx_queued_at: 0,
+            ack_delay_ms: None,
+            keepalive_ms: None,
+            tx_last_byte_at: 0,
+            ack_scheduled_at: 0,
+            last_rx_byte_at: None,
+            on_ack: None,
+            on_raw_frame: None,
+            logger: None,
+            tx_wrote_byte: false,
+            #[cfg(feature = "testing")]
+            tx_capture: alloc::vec::Vec::new(),
+            _phantom: core::marker::PhantomData,
+        }
+    }
+
+    /// The largest payload which fits in the TX buffer. This is zero if the
+    /// TX buffer is too small to hold even an empty frame.
+    ///
+    /// The TX buffer holds the frame before COBS encoding - the COBS bytes
+    /// and delimiters are worked out as each byte is sent, so they don't
+    /// need any room. A payload of this size always goes out, whatever is
+    /// in it, and fits in the far end's RX buffer if that's the same size
+    /// as our TX buffer. See `cobs_max_overhead` for how long it is on the
+    /// wire.
+    pub fn space(&self) -> usize {
+        self.tx_buffer
+            .capacity()
+            .saturating_sub(self.frame_overhead())
+    }
+
+    /// Can the TX buffer hold a frame at all?
+    fn tx_buffer_usable(&self) -> bool {
+        self.tx_buffer.capacity() >= self.frame_overhead()
+    }
+
+    /// How many bytes each frame has on top of its payload.
+    fn frame_overhead(&self) -> usize {
+        CHECKSUM_OVERHEAD + self.checksum_width.len()
+    }
+
+    /// The ACK or NACK we're sending.
+    fn sframe_bytes(&self) -> &[u8] {
+        let payload_len = self.sframe[PAYLOAD_LENGTH_IDX] as usize;
+        &self.sframe[..self.frame_overhead() + payload_len]
+    }
+
+    /// The largest payload we can send in one frame. This is limited by both
+    /// the size of the TX buffer and by the length byte in the frame header.
+    ///
+    /// Use `cobs_max_overhead` to work out how much space a frame of this
+    /// size takes up on the wire.
+    pub fn max_payload(&self) -> usize {
+        core::cmp::min(self.space(), MAX_PAYLOAD)
+    }
+
+    /// Queue a message for sending. Only one message can be in flight at a
+    /// time, so this returns `Error::PacketInFlight` until the last one has
+    /// been ACKed.
+    ///
+    /// To be precise, `PacketInFlight` means the TX buffer holds a frame
+    /// (a message, a fragment, a broadcast, a control frame, a RESET or a
+    /// forwarded frame) which is waiting to be sent, being sent, waiting for
+    /// its ACK or waiting to be re-sent. An ACK or NACK of our own never
+    /// gets in the way - they don't use the TX buffer, so the message is
+    /// queued and goes out as soon as the ACK or NACK has finished. So if
+    /// you get `PacketInFlight`, it's worth waiting for the ACK (see
+    /// `take_tx_result`) rather than retrying straight away.
+    ///
+    /// A NACKed frame stays in the TX buffer until it has been re-sent and
+    /// ACKed, so `send` is rejected until then too. While it waits to go out
+    /// again, `link_state` returns `LinkState::AwaitingResend`.
+    ///
+    /// If TX is paused (see `pause_tx`), the message is still queued, and it
+    /// will be sent as soon as TX is resumed.
+    ///
+    /// In windowed mode (a `WINDOW` above 1), you only get `PacketInFlight`
+    /// once `WINDOW` messages are waiting to be ACKed. Each message takes one
+    /// more byte (its number), so it must be one byte smaller than
+    /// `max_payload`.
+    pub fn send(&mut self, message: &TXM) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        if WINDOW > 1 {
+            return self.queue_windowed(message);
+        }
+        self.queue_message(message, FrameType::IFrame(self.next_tx_colour))
+    }
+
+    /// Encode a message into the next free slot in the window, as a
+    /// numbered I-Frame.
+    fn queue_windowed(&mut self, message: &TXM) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        if !self.tx_buffer_usable() || self.max_payload() == 0 {
+            return Err(Error::MessageTooLarge);
+        }
+        if self.window_len == WINDOW {
+            return Err(Error::PacketInFlight);
+        }
+        self.flush_before_send()?;
+        let usable = DATA_IDX + self.max_payload();
+        let (covers, seed, width) = (self.checksum_covers, self.crc_seed, self.checksum_width);
+        let frame = &mut self.window[(self.window_head + self.window_len) % WINDOW];
+        frame.resize_default(frame.capacity()).unwrap();
+        frame[DATA_IDX] = self.tx_seq;
+        match CODEC::encode(message, &mut frame[DATA_IDX + 1..usable]) {
+            Ok(len) => {
+                let frame_len = build_frame(frame, HEADER_SEQ_IFRAME, len + 1, covers, seed, width);
+                frame.truncate(frame_len);
+                self.tx_seq = self.tx_seq.wrapping_add(1);
+                self.window_len += 1;
+                self.link_dead = false;
+                Ok(())
+            }
+            Err(e) => {
+                frame.truncate(0);
+                Err(Error::Codec(e))
+            }
+        }
+    }
+
+    /// The number of the oldest frame in the window.
+    fn window_base(&self) -> u8 {
+        self.tx_seq.wrapping_sub(self.window_len as u8)
+    }
+
+    /// How many messages `send` has queued in windowed mode which haven't
+    /// been ACKed yet. This is always zero in stop-and-wait mode.
+    pub fn window_in_flight(&self) -> usize {
+        self.window_len
+    }
+
+    /// The far end wants frame `next` next, so has everything before it.
+    fn window_acked(&mut self, next: u8) {
+        let base = self.window_base();
+        let acked = next.wrapping_sub(base) as usize;
+        if acked == 0 {
+            // Nothing new - the far end is still waiting for `base`
+            return;
+        } else if acked > self.window_len {
+            trace!(self, "RX unexpected numbered ACK {}", next);
+            self.tx_result = Some(TxResult::Ignored);
+            return;
+        }
+        for _ in 0..acked {
+            self.window[self.window_head].truncate(0);
+            self.window_head = (self.window_head + 1) % WINDOW;
+        }
+        self.window_len -= acked;
+        if (self.window_next.wrapping_sub(base) as usize) < acked {
+            self.window_next = next;
+        }
+        self.retries = 0;
+        self.window_polls = 0;
+        self.tx_sent_at = self.now();
+        self.tx_result = Some(TxResult::Acked);
+        self.stats.frames_acked = self.stats.frames_acked.wrapping_add(acked as u32);
+    }
+
+    /// Go back to the oldest frame in the window, and send it all again.
+    fn window_go_back(&mut self) {
+        self.window_next = self.window_base();
+        self.window_polls = 0;
+        self.stats.retransmits = self.stats.retransmits.wrapping_add(1);
+    }
+
+    /// Queue a message to be sent `repeats` times back-to-back (but at least
+    /// once), without waiting for an ACK. This is for broadcasting on a
+    /// shared medium where nobody can ACK, and sending several copies
+    /// improves the odds of each receiver getting one.
+    ///
+    /// Receivers don't ACK broadcasts, and only hand back the first copy of
+    /// each. Broadcasts alternate between Red and Blue, so a receiver which
+    /// misses every copy of one broadcast will also drop the next one.
+    pub fn send_broadcast(
+        &mut self,
+        message: &TXM,
+        repeats: u8,
+    ) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        self.queue_message(message, FrameType::Broadcast(self.next_broadcast_colour))?;
+        self.broadcast_repeats = repeats.saturating_sub(1);
+        self.next_broadcast_colour = self.next_broadcast_colour.next();
+        Ok(())
+    }
+
+    /// Encode a message into the tx_buffer, as a frame of the given type.
+    fn queue_message(
+        &mut self,
+        message: &TXM,
+        frame_type: FrameType,
+    ) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        if !self.tx_buffer_usable() {
+            return Err(Error::MessageTooLarge);
+        }
+        if !self.tx_ready() {
+            return Err(Error::PacketInFlight);
+        }
+        self.flush_before_send()?;
+        self.tx_buffer.open();
+        let usable = DATA_IDX + self.max_payload();
+        match CODEC::encode(message, &mut self.tx_buffer[DATA_IDX..usable]) {
+            Ok(payload_len) => {
+                self.finish_frame(frame_type.to_u8(), payload_len);
+                Ok(())
+            }
+            Err(e) => {
+                self.tx_buffer.truncate(0);
+                Err(Error::Codec(e))
+            }
+        }
+    }
+
+    /// Queue a message which might be too big to fit in one frame. It is
+    /// encoded into a scratch buffer (the `LARGE` type parameter sets the
+    /// size), and then sent as a series of fragments, each of which is ACKed
+    /// before the next is sent. The far end puts the message back together
+    /// (in a scratch buffer of its own) and `run_rx` returns it as usual.
+    /// A message which fits in one frame is sent just like `send` would.
+    ///
+    /// As with `send`, you can't queue anything else until the whole message
+    /// has been ACKed. The on-ACK callback is only called for the last
+    /// fragment.
+    pub fn send_large(&mut self, message: &TXM) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        if !self.tx_buffer_usable() || self.max_payload() == 0 {
+            return Err(Error::MessageTooLarge);
+        }
+        if !self.tx_ready() {
+            return Err(Error::PacketInFlight);
+        }
+        self.flush_before_send()?;
+        self.large_tx
+            .resize_default(self.large_tx.capacity())
+            .unwrap();
+        match CODEC::encode(message, &mut self.large_tx) {
+            Ok(len) => {
+                self.large_tx.truncate(len);
+                self.large_tx_sent = 0;
+                self.next_fragment();
+                Ok(())
+            }
+            Err(e) => {
+                self.large_tx.truncate(0);
+                Err(Error::Codec(e))
+            }
+        }
+    }
+
+    /// Move the next piece of the large message into the tx_buffer.
+    fn next_fragment(&mut self) {
+        let remaining = self.large_tx.len() - self.large_tx_sent;
+        let len = core::cmp::min(remaining, self.max_payload());
+        let last = len == remaining;
+        self.tx_buffer.open();
+        self.tx_buffer[DATA_IDX..DATA_IDX + len]
+            .copy_from_slice(&self.large_tx[self.large_tx_sent..self.large_tx_sent + len]);
+        let frame_type = if last {
+            FrameType::IFrame(self.next_tx_colour)
+        } else {
+            FrameType::Fragment(self.next_tx_colour)
+        };
+        self.finish_frame(frame_type.to_u8(), len);
+        if last {
+            self.large_tx.truncate(0);
+            self.large_tx_sent = 0;
+        } else {
+            self.large_tx_sent += len;
+        }
+    }
+
+    /// Queue an application-defined control frame. The `type_byte` must be
+    /// `CONTROL_FRAME_FIRST` or higher. Control frames are checksummed like
+    /// any other frame, but they are not acknowledged by the far end and they
+    /// are never retransmitted. They use the same buffer as `send`, so you
+    /// can't queue one while a message is in flight.
+    pub fn send_control(
+        &mut self,
+        type_byte: u8,
+        payload: &[u8],
+    ) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        if type_byte < CONTROL_FRAME_FIRST {
+            return Err(Error::InvalidFrameType);
+        }
+        self.queue_bytes(type_byte, payload)
+    }
+
+    /// Queue some bytes you've already encoded (a firmware chunk, say) to be
+    /// sent as a message, exactly as `send` would, but without going through
+    /// the codec. The far end's codec still has to make sense of them,
+    /// unless it uses `received_payload`.
+    ///
+    /// `data` can be empty, which gives an I-Frame with a length of zero.
+    pub fn send_bytes(&mut self, data: &[u8]) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        self.queue_bytes(FrameType::IFrame(self.next_tx_colour).to_u8(), data)
+    }
+
+    /// Copy a payload into the tx_buffer, as a frame of the given type.
+    fn queue_bytes(
+        &mut self,
+        frame_type: u8,
+        payload: &[u8],
+    ) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        if !self.tx_buffer_usable() || payload.len() > self.max_payload() {
+            return Err(Error::MessageTooLarge);
+        }
+        if !self.tx_ready() {
+            return Err(Error::PacketInFlight);
+        }
+        self.flush_before_send()?;
+        self.tx_buffer.open();
+        self.tx_buffer[DATA_IDX..DATA_IDX + payload.len()].copy_from_slice(payload);
+        self.finish_frame(frame_type, payload.len());
+        Ok(())
+    }
+
+    /// Queue a frame which is already COBS encoded - for example, one
+    /// received on another link - to be sent exactly as it is. This is for
+    /// relays and repeaters passing frames through, and nothing else.
+    ///
+    /// We don't re-encode the frame or check its checksum, and it bypasses
+    /// the ARQ entirely: our colour doesn't change and we don't wait for an
+    /// ACK (that's between the two ends of the relayed link). Any zero
+    /// delimiters at either end of `cobs_encoded` are ignored, and we send
+    /// our own. A zero anywhere else gives `Error::InvalidFrameType`, as
+    /// does trying to forward a frame with `Framing::LengthPrefixed`.
+    pub fn forward_frame(
+        &mut self,
+        cobs_encoded: &[u8],
+    ) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        let start = cobs_encoded.iter().take_while(|&&b| b == 0).count();
+        let trailing = cobs_encoded[start..]
+            .iter()
+            .rev()
+            .take_while(|&&b| b == 0)
+            .count();
+        let frame = &cobs_encoded[start..cobs_encoded.len() - trailing];
+        if frame.is_empty() || frame.contains(&0) || self.framing != Framing::Cobs {
+            return Err(Error::InvalidFrameType);
+        }
+        if frame.len() > self.tx_buffer.capacity() {
+            return Err(Error::MessageTooLarge);
+        }
+        if !self.tx_ready() {
+            return Err(Error::PacketInFlight);
+        }
+        self.flush_before_send()?;
+        // It fits - we checked above
+        self.tx_buffer.extend_from_slice(frame).unwrap();
+        self.tx_forwarding = true;
+        self.tx_queued_at = self.now();
+        self.nacks = 0;
+        self.retries = 0;
+        self.link_dead = false;
+        Ok(())
+    }
+
+    /// Can we accept a new frame into the tx_buffer? We can if it's empty and
+    /// we're either idle, or only sending an SFRAME.
+    fn tx_ready(&self) -> bool {
+        // The buffer is only ever emptied on the way to `Idle`, or while an
+        // S-Frame is being sent, so the state check is belt-and-braces.
+        self.tx_buffer.is_empty()
+            && matches!(
+                self.tx_state,
+                TxState::Idle
+                    | TxState::SendingDelimiterStart {
+                        payload: Payload::SFrame { .. },
+                    }
+                    | TxState::SendingCobsHeader {
+                        payload: Payload::SFrame { .. },
+                    }
+                    | TxState::SendingPayload {
+                        payload: Payload::SFrame { .. },
+                        ..
+                    }
+                    | TxState::SendingDelimiterEnd {
+                        payload: Payload::SFrame { .. },
+                    }
+            )
+    }
+
+    /// Build a complete frame around a payload which has already been written
+    /// into the tx_buffer at `DATA_IDX`. The tx_buffer must be at full length
+    /// when this is called (it definitely fits).
+    fn finish_frame(&mut self, frame_type: u8, payload_len: usize) {
+        let frame_len = build_frame(
+            &mut self.tx_buffer,
+            frame_type,
+            payload_len,
+            self.checksum_covers,
+            self.crc_seed,
+            self.checksum_width,
+        );
+        self.tx_buffer.truncate(frame_len);
+        self.tx_queued_at = self.now();
+        self.nacks = 0;
+        self.retries = 0;
+        self.link_dead = false;
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.map(|f| f()).unwrap_or(0)
+    }
+
+    /// Remember when we last received a byte, if we have a clock. If we're
+    /// recovering from a glitch and the line has been quiet for long enough,
+    /// we start listening again.
+    fn note_rx_byte(&mut self) {
+        if self.clock.is_some() {
+            let now = self.now();
+            if self.rx_resyncing {
+                let quiet = match self.last_rx_byte_at {
+                    Some(t) => now.wrapping_sub(t) >= self.glitch_gap_ms,
+                    None => true,
+                };
+                if quiet {
+                    self.rx_resyncing = false;
+                }
+            }
+            self.last_rx_byte_at = Some(now);
+        }
+    }
+
+    /// If `after_failures` frames in a row fail their checksum, assume the
+    /// line glitched and we can no longer trust where we think frames start.
+    /// `run_rx` returns `RxEvent::GlitchRecovery`, and everything received
+    /// is ignored until the line has been quiet for `idle_gap`. The next
+    /// frame delimiter after that is trusted as the start of a frame.
+    ///
+    /// Pass `None` to disable this (the default). It has no effect until you
+    /// call `set_clock`.
+    pub fn set_glitch_recovery(
+        &mut self,
+        after_failures: Option<u32>,
+        idle_gap: core::time::Duration,
+    ) {
+        self.glitch_limit = after_failures;
+        self.glitch_gap_ms = idle_gap.as_millis() as u64;
+    }
+
+    /// How long it's been since we last received a byte (of any kind). This
+    /// tells you whether the far end has gone quiet, or is still talking but
+    /// its frames aren't getting through.
+    ///
+    /// Returns `None` if we haven't received anything since `set_clock` was
+    /// called.
+    pub fn time_since_last_rx_byte(&self) -> Option<core::time::Duration> {
+        self.last_rx_byte_at
+            .map(|t| core::time::Duration::from_millis(self.now().wrapping_sub(t)))
+    }
+
+    /// Give Illyria a clock, so it can tell how much time has passed.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = Some(clock);
+    }
+
+    /// Set (or clear) a function to be called from `run_rx` whenever the
+    /// message in flight is ACKed. This lets you free anything you were
+    /// holding on to in case the message had to be sent again. It is not
+    /// called when a RESET is ACKed.
+    pub fn set_on_ack(&mut self, on_ack: Option<ACK>) {
+        self.on_ack = on_ack;
+    }
+
+    /// Find out what has happened to the frame in flight since you last
+    /// asked - whether it's been ACKed, NACKed or timed out, or if we
+    /// ignored an ACK or NACK. Only the latest result is kept. Fragments of
+    /// a large message count as frames of their own.
+    pub fn take_tx_result(&mut self) -> Option<TxResult> {
+        self.tx_result.take()
+    }
+
+    /// Set (or clear) the logger which diagnostic messages are sent to.
+    pub fn set_logger(&mut self, logger: Option<LOG>) {
+        self.logger = logger;
+    }
+
+    /// Set (or clear) a function to be called from `run_rx` with every frame
+    /// that arrives complete, whether or not its checksum is valid. This is
+    /// for logging what's on the wire, e.g. to analyse line errors offline.
+    pub fn set_on_raw_frame(&mut self, on_raw_frame: Option<RAW>) {
+        self.on_raw_frame = on_raw_frame;
+    }
+
+    /// Choose which bytes the checksum covers. The default is
+    /// `ChecksumCovers::Decoded`. Both ends of the link must use the same
+    /// setting.
+    pub fn set_checksum_covers(&mut self, covers: ChecksumCovers) {
+        self.checksum_covers = covers;
+    }
+
+    /// Set the seed for the checksum. The default of zero gives a standard
+    /// CRC-16/X.25. If several independent links share one medium, giving
+    /// each link its own seed means frames from one link fail the checksum
+    /// on all the others. Both ends of the link must use the same seed.
+    pub fn set_crc_seed(&mut self, seed: u16) {
+        self.crc_seed = seed;
+    }
+
+    /// Choose which checksum each frame carries. The default is
+    /// `ChecksumWidth::Crc16`, and `ChecksumWidth::custom` takes a
+    /// `FrameChecksum` of your own. Both ends of the link must use the same
+    /// setting. If a frame arrives with a checksum of the other width,
+    /// `run_rx` returns `RxEvent::ChecksumWidthMismatch` rather than NACKing
+    /// it.
+    ///
+    /// A wider checksum leaves less room for payload in the TX buffer. Don't
+    /// change this while a frame is being sent.
+    pub fn set_checksum_width(&mut self, width: ChecksumWidth) {
+        self.checksum_width = width;
+    }
+
+    /// The checksum settings we're using, for `decode_frame` and
+    /// `encode_frame`.
+    pub fn frame_format(&self) -> FrameFormat {
+        FrameFormat {
+            covers: self.checksum_covers,
+            seed: self.crc_seed,
+            width: self.checksum_width,
+        }
+    }
+
+    /// How many bytes of checksum each frame carries.
+    pub fn checksum_width(&self) -> usize {
+        self.checksum_width.len()
+    }
+
+    /// Choose whether ACKs carry the colour of the I-Frame they acknowledge.
+    /// The default is `false`.
+    ///
+    /// Without colours, a duplicated ACK can be mistaken for the ACK of the
+    /// next frame. This happens if we re-send a frame because its ACK was
+    /// slow: the far end ACKs both copies, the first ACK clears the frame,
+    /// and the second arrives while our next frame is in flight. If that
+    /// next frame was lost, we'd never know. With coloured ACKs, an ACK is
+    /// only accepted if its colour matches the frame in flight, so the stale
+    /// one is ignored.
+    ///
+    /// Both ends of the link must use the same setting. A Purple ACK looks
+    /// the same either way.
+    pub fn set_coloured_acks(&mut self, coloured: bool) {
+        self.coloured_acks = coloured;
+    }
+
+    /// Choose whether an ACK we owe the far end can ride along with an
+    /// I-Frame we're about to send, rather than going out on its own. The
+    /// default is `false`.
+    ///
+    /// On a link where each message gets a reply, this saves sending an ACK
+    /// frame each way. The far end must understand the combined I-Frame+ACK
+    /// frames, so don't turn this on if it's running an older version of
+    /// this crate. Piggybacked ACKs carry no colour, so they aren't used
+    /// with coloured ACKs (see `set_coloured_acks`).
+    pub fn set_piggyback_acks(&mut self, piggyback: bool) {
+        self.piggyback_acks = piggyback;
+    }
+
+    /// Choose whether each ACK we send carries the checksum of the frame it
+    /// ACKs, as its payload. The default is `false`, where ACKs are empty.
+    ///
+    /// When an ACK with a checksum arrives, we only accept it if the
+    /// checksum matches the frame we have in flight, so an ACK for some
+    /// other frame (e.g. an earlier copy of a re-sent one) can't be
+    /// mistaken for it. Empty ACKs are accepted as before, so only the end
+    /// doing the ACKing needs to turn this on. NACKs stay empty, as the
+    /// checksum of a corrupted frame can't be trusted. Piggybacked ACKs
+    /// (see `set_piggyback_acks`) don't carry a checksum either.
+    pub fn set_ack_checksums(&mut self, ack_checksums: bool) {
+        self.ack_checksums = ack_checksums;
+    }
+
+    /// Choose whether to NACK a frame which passes its checksum but has a
+    /// frame type we don't understand. We send a NACK-unsupported, carrying
+    /// the frame type, which tells the far end to stop sending it. The
+    /// default is `false`, where such frames are ignored. Either way,
+    /// `run_rx` returns `RxEvent::UnknownFrame`.
+    pub fn set_nack_unknown_frames(&mut self, nack: bool) {
+        self.nack_unknown_frames = nack;
+    }
+
+    /// Choose whether to NACK every frame which is cut short by a frame
+    /// delimiter. On a noisy link, this gets the far end to re-send it
+    /// without waiting for its ACK timeout. The default is `false`, where
+    /// only frames we can tell were I-Frames are NACKed, and the rest are
+    /// only counted (see `Stats::framing_errors`).
+    pub fn set_nack_truncated_frames(&mut self, nack: bool) {
+        self.nack_truncated_frames = nack;
+    }
+
+    /// Mark the start and end of each frame with two zero bytes instead of
+    /// one, and only treat two zeros in a row as a frame boundary. A single
+    /// zero on its own (e.g. from line noise) is then ignored, rather than
+    /// throwing away the frame we're receiving. Both ends of the link must
+    /// use the same setting.
+    pub fn set_double_delimiters(&mut self, double: bool) {
+        self.double_delimiters = double;
+    }
+
+    /// Choose how frames are marked out in the byte stream. The default is
+    /// `Framing::Cobs`. Both ends of the link must use the same setting, and
+    /// you shouldn't change it while a frame is being sent or received.
+    /// Double delimiters (see `set_double_delimiters`) only apply to COBS
+    /// framing.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Choose whether to flush the writer when a frame is queued to send.
+    /// The default is `true`.
+    pub fn set_flush_on_send(&mut self, flush: bool) {
+        self.flush_on_send = flush;
+    }
+
+    /// Check that the writer really is sending what we give it. With a limit
+    /// set, the writer is flushed at the end of every frame, and the frame
+    /// only counts as sent once a flush succeeds. Until then, each `run_tx`
+    /// tries to flush again, and a failed flush gives `Error::Writer`. If the
+    /// flush fails more than `max` times in a row, the frame is dropped and
+    /// you get `Error::WriterFault`. A failed flush in `send` (see
+    /// `set_flush_on_send`) also gives `Error::Writer`, and counts towards
+    /// the limit.
+    ///
+    /// Pass `None` to not check (the default). Flush errors are then
+    /// ignored, and we can go on re-sending into a broken transmitter
+    /// without noticing.
+    pub fn set_max_flush_failures(&mut self, max: Option<u32>) {
+        self.max_flush_failures = max;
+        self.flush_failures = 0;
+    }
+
+    /// Choose whether to flush the writer once the last byte of each frame
+    /// has been written to it. The default is `false`.
+    ///
+    /// With a buffered writer, this makes sure a whole frame goes out on the
+    /// wire even if `run_tx` then isn't called for a while. If the writer
+    /// isn't ready to flush, the next `run_tx` tries again before doing
+    /// anything else.
+    pub fn set_flush_at_frame_end(&mut self, flush: bool) {
+        self.flush_at_frame_end = flush;
+    }
+
+    /// Hold back each ACK for up to `delay`, to give the application a
+    /// chance to queue a response first. In a request/response protocol this
+    /// lets the far end see the ACK immediately followed by the response. As
+    /// soon as a frame is queued, the ACK goes out (ahead of it). If nothing
+    /// is queued within the delay, the ACK is sent on its own.
+    ///
+    /// Keep the delay well under the far end's re-send timeout, or it will
+    /// re-send its messages unnecessarily. Pass `None` to send ACKs straight
+    /// away (the default). The delay has no effect until you call
+    /// `set_clock`.
+    pub fn set_ack_delay(&mut self, delay: Option<core::time::Duration>) {
+        self.ack_delay_ms = delay.map(|d| d.as_millis() as u64);
+    }
+
+    /// While we have nothing to send, send a single zero byte every
+    /// `interval`, to stop the far end's UART losing sync during long quiet
+    /// periods. A stray zero is just a frame delimiter, so the far end
+    /// ignores it. Nothing is sent while TX is paused.
+    ///
+    /// Pass `None` to stay quiet (the default). This has no effect until you
+    /// call `set_clock`.
+    pub fn set_idle_keepalive(&mut self, interval: Option<core::time::Duration>) {
+        self.keepalive_ms = interval.map(|d| d.as_millis() as u64);
+    }
+
+    /// Is it time to send a keepalive byte? Only called when we're idle.
+    fn keepalive_due(&self) -> bool {
+        match (self.clock, self.keepalive_ms) {
+            (Some(_), Some(interval)) => self.now().wrapping_sub(self.tx_last_byte_at) >= interval,
+            _ => false,
+        }
+    }
+
+    /// Set the longest time a frame may sit in the TX buffer, waiting to be
+    /// acknowledged, before it is dropped. This applies however many re-sends
+    /// the poll limit would otherwise allow. When a frame is dropped, `run_tx`
+    /// returns `Error::FrameExpired` and the next frame is sent Purple, as we
+    /// don't know whether the far end got the dropped one.
+    ///
+    /// Pass `None` to disable the limit (the default). The limit has no
+    /// effect until you call `set_clock`.
+    pub fn set_max_in_flight_time(&mut self, max: Option<core::time::Duration>) {
+        self.max_in_flight_ms = max.map(|d| d.as_millis() as u64);
+    }
+
+    /// Set how many times in a row the far end may NACK the frame in the TX
+    /// buffer. Each NACK makes us re-send the frame, so if the NACKs keep
+    /// coming the line (or the far end) is probably corrupting everything.
+    /// When the limit is exceeded, the frame is dropped and `run_tx` returns
+    /// `Error::TooManyNacks`, rather than the `Error::FrameExpired` you get
+    /// when nothing comes back at all. As with an expired frame, the next
+    /// frame is sent Purple.
+    ///
+    /// A NACKed frame is re-sent straight away, and that re-send counts
+    /// towards this limit rather than the one from `set_max_retries`.
+    ///
+    /// Pass `None` to disable the limit (the default).
+    pub fn set_max_nacks(&mut self, max: Option<u32>) {
+        self.max_nacks = max;
+    }
+
+    /// Set how many times in a row we may re-send a frame because no ACK or
+    /// NACK came back in time. When the limit is reached, we stop re-sending,
+    /// drop the frame, and `run_tx` returns `Error::LinkDead`. After that
+    /// `link_state` says `Dead` until you queue another frame (or call
+    /// `send_reset` or `resync`), so you have time to react - switch
+    /// antennas, reset the radio, or whatever. As with an expired frame, the
+    /// next frame is sent Purple.
+    ///
+    /// Pass `None` to re-send forever (the default).
+    pub fn set_max_retries(&mut self, max: Option<u32>) {
+        self.max_retries = max;
+    }
+
+    /// How many more times we'll re-send the frame in the TX buffer if no
+    /// ACK comes back, or `None` if there's no limit (see
+    /// `set_max_retries`).
+    pub fn retries_remaining(&self) -> Option<u32> {
+        self.max_retries.map(|max| max.saturating_sub(self.retries))
+    }
+
+    /// How is the link doing, from the TX side?
+    pub fn link_state(&self) -> LinkState {
+        if self.link_dead {
+            LinkState::Dead
+        } else if self.tx_buffer.is_empty() && self.window_len == 0 {
+            LinkState::Idle
+        } else if self.nacks > 0 && !self.sending_tx_buffer() && !self.awaiting_ack() {
+            LinkState::AwaitingResend
+        } else if self.retries > 0 {
+            LinkState::Retrying(self.retries)
+        } else {
+            LinkState::InFlight
+        }
+    }
+
+    /// Set how many duplicate I-Frames we may receive in a row before
+    /// `run_rx` returns `RxEvent::LinkStalled`.
+    ///
+    /// A few duplicates just mean some of our ACKs were lost. But if our RX
+    /// colour and the far end's TX colour ever got stuck out of step (which
+    /// the colour scheme should prevent, but bugs happen), every frame would
+    /// be ACKed and then dropped as a duplicate, and the far end would never
+    /// know. If you see `LinkStalled`, calling `send_reset` will put both
+    /// ends back to Purple. The count starts again after each event.
+    ///
+    /// Pass `None` to disable the check (the default).
+    pub fn set_max_duplicates(&mut self, max: Option<u32>) {
+        self.max_duplicates = max;
+        self.duplicates = 0;
+    }
+
+    /// Choose whether `run_rx` tells you about duplicate I-Frames (see
+    /// `DuplicatePolicy`). The default is `DuplicatePolicy::Drop`.
+    pub fn set_duplicate_policy(&mut self, policy: DuplicatePolicy) {
+        self.duplicate_policy = policy;
+    }
+
+    /// How many times in a row has the far end NACKed the frame in the TX
+    /// buffer?
+    pub fn consecutive_nacks(&self) -> u32 {
+        self.nacks
+    }
+
+    /// Is there a frame in the TX buffer which we're not part-way through
+    /// sending? Only these frames can be dropped.
+    fn tx_droppable(&self) -> bool {
+        !self.tx_buffer.is_empty()
+            && matches!(
+                self.tx_state,
+                TxState::Idle | TxState::WaitingForAckNack { .. }
+            )
+    }
+
+    /// Has the frame in the TX buffer been there too long?
+    fn tx_expired(&self) -> bool {
+        match (self.clock, self.max_in_flight_ms) {
+            (Some(_), Some(max)) => {
+                self.tx_droppable() && self.now().wrapping_sub(self.tx_queued_at) > max
+            }
+            _ => false,
+        }
+    }
+
+    /// Has the frame in the TX buffer been NACKed too many times?
+    fn tx_nacked_out(&self) -> bool {
+        match self.max_nacks {
+            Some(max) => self.tx_droppable() && self.nacks > max,
+            None => false,
+        }
+    }
+
+    /// Throw away the frame in the TX buffer, which may or may not have got
+    /// through.
+    fn drop_tx_frame(&mut self) {
+        self.tx_buffer.truncate(0);
+        self.tx_forwarding = false;
+        self.large_tx.truncate(0);
+        self.tx_state = TxState::Idle;
+        self.tx_verifying = false;
+        self.next_tx_colour = Colour::Purple;
+        // Nothing is in flight now, so no ACK or timeout would lift a pause
+        // whose RESUME got lost
+        self.peer_paused = false;
+    }
+
+    /// Put the TX state machine back to `Idle`. This does not throw away the
+    /// frame in the TX buffer - it will be sent again from the start. Use
+    /// `abort_pending` if you want rid of it.
+    pub fn reset(&mut self) {
+        self.tx_state = TxState::Idle;
+        self.tx_verifying = false;
+    }
+
+    /// Throw away the frame in the TX buffer, whether it's waiting to be
+    /// sent, part-way through being sent or waiting for an ACK, so you can
+    /// send something more important instead. Any ACK or NACK we're sending
+    /// still goes out.
+    ///
+    /// As the frame may or may not have got through, the colour isn't
+    /// advanced - the next frame is sent Purple, as with an expired frame.
+    pub fn abort_pending(&mut self) {
+        self.stop_awaiting_ack();
+        let tx_state = self.tx_state;
+        let sending_sframe = match tx_state {
+            TxState::SendingDelimiterStart { payload }
+            | TxState::SendingCobsHeader { payload }
+            | TxState::SendingPayload { payload, .. }
+            | TxState::SendingDelimiterEnd { payload } => {
+                matches!(payload, Payload::SFrame { .. })
+            }
+            TxState::Idle | TxState::WaitingForAckNack { .. } => false,
+        };
+        self.drop_tx_frame();
+        if sending_sframe {
+            self.tx_state = tx_state;
+        }
+        self.nacks = 0;
+        self.retries = 0;
+    }
+
+    /// Throw away all our ARQ state. Anything queued to send (or half-way
+    /// through being received) is dropped, and both our TX and RX colours go
+    /// back to Purple so we'll accept whatever the far end sends next.
+    pub fn resync(&mut self) {
+        self.tx_buffer.truncate(0);
+        self.clear_window();
+        self.rx_seq = 0;
+        self.nacks = 0;
+        self.retries = 0;
+        self.link_dead = false;
+        self.connected = false;
+        self.tx_result = None;
+        self.large_tx.truncate(0);
+        self.large_rx.truncate(0);
+        self.large_rx_discard = false;
+        self.rx_payload_large = false;
+        self.sframe_pending = None;
+        self.rx_buffer.truncate(0);
+        self.tx_state = TxState::Idle;
+        self.next_tx_colour = Colour::Purple;
+        self.tx_zero_sent = false;
+        self.tx_verifying = false;
+        self.tx_sync_sent = 0;
+        self.tx_forwarding = false;
+        self.rx_state = RxState::WantFrameDelimiter;
+        self.rx_zero_pending = false;
+        self.rx_colour = Colour::Purple;
+        self.rx_last_colour = None;
+        self.last_broadcast = None;
+        self.last_rx_frame = None;
+        self.rx_payload_ready = false;
+        self.crc_failures = 0;
+        self.rx_resyncing = false;
+        self.duplicates = 0;
+        self.peer_paused = false;
+        self.rx_paused = false;
+        self.rx_recovered = false;
+        self.flow_pending = None;
+    }
+
+    /// Throw away every frame in the window, and start numbering from zero.
+    fn clear_window(&mut self) {
+        for frame in self.window.iter_mut() {
+            frame.truncate(0);
+        }
+        self.window_head = 0;
+        self.window_len = 0;
+        self.tx_seq = 0;
+        self.window_next = 0;
+        self.window_polls = 0;
+    }
+
+    /// Throw away any partially received frame. We then ignore everything
+    /// up to the next frame delimiter. Unlike `resync`, this leaves the rest
+    /// of our state (including which colour we expect next) alone.
+    pub fn abort_rx_frame(&mut self) {
+        self.rx_buffer.truncate(0);
+        self.rx_state = RxState::WantFrameDelimiter;
+        self.rx_payload_ready = false;
+    }
+
+    /// `resync` ourselves, and then queue a RESET frame which tells the far
+    /// end to `resync` too. The RESET is re-sent until the far end ACKs it,
+    /// just like a message, and `send` will return `PacketInFlight` until
+    /// then.
+    ///
+    /// We never send a RESET in response to anything, so two ends can't get
+    /// stuck resetting each other. If both ends send a RESET at the same
+    /// time, each one's RESET is dropped by the other end's `resync` and both
+    /// end up in sync anyway.
+    ///
+    /// If the TX buffer is too small to hold a frame, we only `resync`
+    /// ourselves.
+    pub fn send_reset(&mut self) {
+        self.resync_and_send(FrameType::Reset);
+    }
+
+    /// Start (or restart) the link, after either end has rebooted. We
+    /// `resync`, and queue a SYNC, which tells the far end to `resync` too
+    /// and then answer with a SYNC-ACK. The SYNC is re-sent until the
+    /// SYNC-ACK arrives, just like a message, and until then `send` returns
+    /// `PacketInFlight` and `is_connected` returns false. Once it has
+    /// arrived, both ends are back to Purple (and numbering from zero).
+    ///
+    /// A SYNC is only ever answered by a SYNC-ACK, never an ACK, so a stale
+    /// ACK from before the far end rebooted can't complete the handshake.
+    pub fn connect(&mut self) {
+        self.resync_and_send(FrameType::Sync);
+    }
+
+    /// `resync`, and then queue a RESET or SYNC. If the TX buffer is too
+    /// small to hold a frame, we only `resync`.
+    fn resync_and_send(&mut self, frame_type: FrameType) {
+        self.resync();
+        if !self.tx_buffer_usable() {
+            return;
+        }
+        // Any writer fault shows up when the frame is sent
+        let _ = self.flush_before_send();
+        self.tx_buffer.open();
+        self.finish_frame(frame_type.to_u8(), 0);
+    }
+
+    /// Is the frame in the tx_buffer a SYNC, which only a SYNC-ACK answers?
+    fn sync_in_flight(&self) -> bool {
+        !self.tx_forwarding
+            && self.tx_buffer.first().and_then(|&b| FrameType::from_u8(b)) == Some(FrameType::Sync)
+    }
+
+    /// Do both ends agree on where they are? This is true once the far end
+    /// has answered our SYNC (see `connect`) or ACKed our RESET, or we've
+    /// received a SYNC or RESET from the far end. It goes false again if we
+    /// `resync`, or the link dies (see `set_max_retries`).
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Stop `run_tx` from starting any new frames (including re-sends, ACKs
+    /// and NACKs). A frame which is part-way through being sent will be
+    /// finished. Anything queued stays queued until `resume_tx` is called,
+    /// and `send` will still queue a message if there's room for it.
+    ///
+    /// This is useful if you need to share a half-duplex bus.
+    pub fn pause_tx(&mut self) {
+        self.tx_paused = true;
+    }
+
+    /// Allow `run_tx` to start sending frames again, after `pause_tx`.
+    pub fn resume_tx(&mut self) {
+        self.tx_paused = false;
+    }
+
+    /// Has the far end asked us to pause? It does that when one of our
+    /// frames doesn't fit in its receive buffer, or when it has queued as
+    /// many messages as it can hold. Until it sends RESUME (or ACKs
+    /// something), `run_tx` won't start any new I-Frames, although a frame
+    /// already in flight can still be re-sent. If a new I-Frame has been
+    /// held for as long as we'd wait for an ACK, we assume the RESUME was
+    /// lost and send it anyway.
+    pub fn is_paused(&self) -> bool {
+        self.peer_paused
+    }
+
+    /// Is the frame in the tx_buffer a new I-Frame, which we mustn't start
+    /// because the far end has paused us? A forwarded frame never is - it's
+    /// already COBS encoded, so its first byte isn't a frame type.
+    fn held_by_peer(&self) -> bool {
+        self.peer_paused
+            && !self.tx_forwarding
+            && self.nacks == 0
+            && matches!(
+                self.tx_buffer.first().and_then(|&b| FrameType::from_u8(b)),
+                Some(FrameType::IFrame(_))
+                    | Some(FrameType::IFrameAck(_))
+                    | Some(FrameType::Fragment(_))
+            )
+    }
+
+    /// Has the far end held back new I-Frames for as long as it would wait
+    /// for an ACK? If so, our RESUME may have been lost.
+    fn pause_timed_out(&self) -> bool {
+        match (self.timeout_mode, self.clock) {
+            (TimeoutMode::Elapsed(timeout), Some(_)) => {
+                self.now().wrapping_sub(self.paused_at) >= timeout.as_millis() as u64
+            }
+            _ => self.held_polls >= self.poll_limit,
+        }
+    }
+
+    /// We can't take any more for now - a frame didn't fit in the
+    /// rx_buffer, or the rx_queue is full - so ask the far end to stop sending new
+    /// I-Frames. Any ACK or NACK we owe still goes first.
+    fn pause_rx(&mut self) {
+        if !self.rx_paused {
+            trace!(self, "RX out of room, sending PAUSE");
+            self.flow_pending = Some(HEADER_PAUSE);
+        }
+        self.rx_paused = true;
+        self.rx_recovered = false;
+    }
+
+    /// Do we owe the far end a RESUME? Once our PAUSE has gone, we send one
+    /// when we're between frames, the rx_queue has room, and either the
+    /// application has taken a message from the queue or a frame has since
+    /// arrived which fitted.
+    fn resume_due(&self) -> bool {
+        let between_frames = match self.framing {
+            Framing::Cobs => matches!(self.rx_state, RxState::WantCobsHeader),
+            Framing::LengthPrefixed => matches!(self.rx_state, RxState::WantFrameDelimiter),
+        };
+        self.rx_paused
+            && self.rx_recovered
+            && self.flow_pending.is_none()
+            && self.rx_queue.len() < self.rx_queue.capacity()
+            && between_frames
+    }
+
+    /// Take the oldest message `run_rx_slice` (or `run_to_completion`)
+    /// queued, if there is one. That makes room for more, which may let us
+    /// send a RESUME.
+    fn dequeue_rx(&mut self) -> Option<(RXM, Colour)> {
+        let queued = self.rx_queue.dequeue();
+        if queued.is_some() {
+            self.rx_made_room();
+        }
+        queued
+    }
+
+    /// We've just put a message on the rx_queue. If that filled it, we
+    /// can't take the next one until the application takes one off.
+    fn rx_queued(&mut self) {
+        if self.rx_queue.len() == self.rx_queue.capacity() {
+            self.pause_rx();
+        }
+    }
+
+    /// We've taken a message off the rx_queue, or received a frame which
+    /// fitted, so we can ask for more. If our PAUSE hasn't gone yet, it
+    /// doesn't need to.
+    fn rx_made_room(&mut self) {
+        self.rx_recovered = true;
+        if self.flow_pending == Some(HEADER_PAUSE) {
+            self.rx_paused = false;
+            self.flow_pending = None;
+        }
+    }
+
+    /// Choose whether we wait for an ACK by counting calls to `run_tx` (the
+    /// default), or by the clock. If your main loop runs at a varying rate,
+    /// counting polls is a poor guide to how long the far end has had to
+    /// reply.
+    pub fn set_timeout_mode(&mut self, mode: TimeoutMode) {
+        self.timeout_mode = mode;
+    }
+
+    /// Choose whether to wait longer for an ACK each time a frame is
+    /// re-sent, so we don't hammer a congested link. The default is
+    /// `BackoffPolicy::Constant`. Each new frame (and each NACK, which tells
+    /// us the far end is alive) starts again from the first wait.
+    pub fn set_backoff(&mut self, backoff: BackoffPolicy) {
+        self.backoff = backoff;
+    }
+
+    /// How many times the normal timeout to wait, given how many times
+    /// we've re-sent this frame.
+    fn backoff_multiplier(&self) -> u32 {
+        match self.backoff {
+            BackoffPolicy::Constant => 1,
+            BackoffPolicy::Exponential { base, max } => {
+                let mut multiplier: u32 = 1;
+                for _ in 0..self.retries {
+                    if multiplier >= max {
+                        break;
+                    }
+                    multiplier = multiplier.saturating_mul(base);
+                }
+                core::cmp::max(core::cmp::min(multiplier, max), 1)
+            }
+        }
+    }
+
+    /// Have we waited long enough for an ACK or NACK, having been polled
+    /// `num_polls` times?
+    fn ack_timed_out(&self, num_polls: u32) -> bool {
+        match (self.timeout_mode, self.clock) {
+            (TimeoutMode::Elapsed(timeout), Some(_)) => {
+                let timeout_ms = timeout.as_millis() as u64 * self.backoff_multiplier() as u64;
+                self.now().wrapping_sub(self.tx_sent_at) >= timeout_ms
+            }
+            _ => num_polls >= self.poll_limit.saturating_mul(self.backoff_multiplier()),
+        }
+    }
+
+    /// Change the number of polls we wait for an ACK or NACK before re-sending
+    /// a frame. As with `new`, the minimum is 1.
+    pub fn set_poll_limit(&mut self, poll_limit: u32) {
+        self.poll_limit = core::cmp::max(poll_limit, 1);
+    }
+
+    /// Limit how many bytes each call to `run_rx_slice` will process, so a
+    /// big slice can't blow the time budget of a real-time loop. Whatever
+    /// isn't used is left for you to pass in again next time - the return
+    /// value tells you how much that is. (`run_rx` and `run_tx` only ever
+    /// handle one byte per call.)
+    ///
+    /// Pass `None` to process as much as possible (the default).
+    pub fn set_rx_slice_budget(&mut self, max_bytes: Option<usize>) {
+        self.rx_slice_budget = max_bytes;
+    }
+
+    /// If we're waiting for an ACK or NACK, returns how many times `run_tx`
+    /// has been polled so far. When this reaches the poll limit, the frame is
+    /// re-sent.
+    pub fn current_poll_count(&self) -> Option<u32> {
+        match self.tx_state {
+            TxState::WaitingForAckNack { num_polls } => Some(num_polls),
+            _ => None,
+        }
+    }
+
+    /// If we're waiting for an ACK or NACK, change how many times `run_tx`
+    /// is considered to have been polled so far. Setting this to the poll
+    /// limit makes the next `run_tx` re-send the frame. Does nothing if we're
+    /// not waiting.
+    pub fn set_poll_count(&mut self, count: u32) {
+        if let TxState::WaitingForAckNack { ref mut num_polls } = self.tx_state {
+            *num_polls = count;
+        }
+    }
+
+    /// Flush the writer before queueing a frame, unless we've been told not
+    /// to (see `set_flush_on_send`). A failed flush is only an error if
+    /// we're checking flushes (see `set_max_flush_failures`).
+    fn flush_before_send(&mut self) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        if !self.flush_on_send {
+            return Ok(());
+        }
+        match self.writer.flush() {
+            Err(nb::Error::Other(e)) if self.max_flush_failures.is_some() => {
+                self.flush_failures = self.flush_failures.saturating_add(1);
+                Err(Error::Writer(e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Flush the writer at the end of a frame, and check that it worked (see
+    /// `set_max_flush_failures`). If the writer has failed too many times in
+    /// a row, the frame is dropped and we give up on it.
+    fn verify_flush(&mut self) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        match self.writer.flush() {
+            Ok(()) => {
+                self.flush_failures = 0;
+                Ok(())
+            }
+            Err(nb::Error::WouldBlock) => Err(Error::TransportWouldBlock),
+            Err(nb::Error::Other(e)) => {
+                self.flush_failures = self.flush_failures.saturating_add(1);
+                if matches!(self.max_flush_failures, Some(max) if self.flush_failures > max) {
+                    trace!(
+                        self,
+                        "TX writer failed {} flushes, giving up",
+                        self.flush_failures
+                    );
+                    self.flush_failures = 0;
+                    self.tx_verifying = false;
+                    self.drop_tx_frame();
+                    Err(Error::WriterFault)
+                } else {
+                    Err(Error::Writer(e))
+                }
+            }
+        }
+    }
+
+    /// Flush the writer at the end of a frame. If it isn't ready, we'll try
+    /// again on the next `run_tx`.
+    fn flush_frame(&mut self) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        match self.writer.flush() {
+            Ok(()) => {
+                self.tx_flush_pending = false;
+                Ok(())
+            }
+            Err(nb::Error::WouldBlock) => {
+                self.tx_flush_pending = true;
+                Err(Error::TransportWouldBlock)
+            }
+            Err(nb::Error::Other(e)) => {
+                self.tx_flush_pending = false;
+                Err(Error::Writer(e))
+            }
+        }
+    }
+
+    fn writer_write(&mut self, byte: u8) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        match self.writer.write(byte) {
+            Ok(()) => {
+                self.tx_wrote_byte = true;
+                #[cfg(feature = "testing")]
+                self.tx_capture.push(byte);
+                self.bytes_transmitted = self.bytes_transmitted.wrapping_add(1);
+                if self.keepalive_ms.is_some() {
+                    self.tx_last_byte_at = self.now();
+                }
+                Ok(())
+            }
+            Err(nb::Error::WouldBlock) => Err(Error::TransportWouldBlock),
+            Err(nb::Error::Other(e)) => Err(Error::Writer(e)),
+        }
+    }
+
+    fn reader_read(&mut self) -> Result<u8, Error<TXO::Error, RXI::Error>> {
+        match self.reader.read() {
+            Ok(b) => {
+                self.note_rx_byte();
+                self.bytes_received = self.bytes_received.wrapping_add(1);
+                Ok(b)
+            }
+            Err(nb::Error::WouldBlock) => Err(Error::TransportWouldBlock),
+            Err(nb::Error::Other(e)) => Err(Error::Reader(e)),
+        }
+    }
+
+    /// Having just sent a delimiter, do we need to send another one? Keeps
+    /// track of how many we've sent if double delimiters are on.
+    fn another_delimiter(&mut self) -> bool {
+        if self.double_delimiters && !self.tx_zero_sent {
+            self.tx_zero_sent = true;
+            true
+        } else {
+            self.tx_zero_sent = false;
+            false
+        }
+    }
+
+    /// Render the pending ACK or NACK, and start sending it. It's rendered
+    /// separately so we can send it while a frame is waiting in the
+    /// tx_buffer.
+    fn start_sframe(&mut self, resume_polls: Option<u32>) -> TxState {
+        if self.resume_due() {
+            trace!(self, "RX has room again, sending RESUME");
+            self.rx_paused = false;
+            self.flow_pending = Some(HEADER_RESUME);
+        }
+        // An ACK goes before a PAUSE, as the far end carries on when it
+        // gets an ACK
+        let next = self
+            .sframe_pending
+            .take()
+            .or_else(|| self.flow_pending.take());
+        if let Some(frame_type) = next {
+            if frame_type == HEADER_NACK || frame_type == HEADER_NACK_UNSUPPORTED {
+                self.stats.nacks_sent = self.stats.nacks_sent.wrapping_add(1);
+            }
+            let payload_len = if self.ack_checksums && is_ack(frame_type) {
+                let width = self.checksum_width;
+                Checksum(self.ack_checksum).write_to(width, &mut self.sframe[DATA_IDX..]);
+                width.len()
+            } else if frame_type == HEADER_SEQ_ACK {
+                self.sframe[DATA_IDX] = self.rx_seq;
+                1
+            } else if frame_type == HEADER_NACK_UNSUPPORTED {
+                self.sframe[DATA_IDX] = self.unsupported_frame;
+                1
+            } else {
+                0
+            };
+            build_frame(
+                &mut self.sframe,
+                frame_type,
+                payload_len,
+                self.checksum_covers,
+                self.crc_seed,
+                self.checksum_width,
+            );
+        }
+        TxState::SendingDelimiterStart {
+            payload: Payload::SFrame { resume_polls },
+        }
+    }
+
+    /// Are we waiting for the far end to ACK the frame in the tx_buffer? We
+    /// might be sending an ACK or NACK of our own in the meantime.
+    fn awaiting_ack(&self) -> bool {
+        match self.tx_state {
+            TxState::WaitingForAckNack { .. } => true,
+            TxState::SendingDelimiterStart { payload }
+            | TxState::SendingCobsHeader { payload }
+            | TxState::SendingPayload { payload, .. }
+            | TxState::SendingDelimiterEnd { payload } => matches!(
+                payload,
+                Payload::SFrame {
+                    resume_polls: Some(_)
+                }
+            ),
+            TxState::Idle => false,
+        }
+    }
+
+    /// Are we part-way through sending the frame in the tx_buffer (rather
+    /// than an ACK or NACK of our own)?
+    fn sending_tx_buffer(&self) -> bool {
+        match self.tx_state {
+            TxState::SendingDelimiterStart { payload }
+            | TxState::SendingCobsHeader { payload }
+            | TxState::SendingPayload { payload, .. }
+            | TxState::SendingDelimiterEnd { payload } => {
+                !matches!(payload, Payload::SFrame { .. })
+            }
+            TxState::Idle | TxState::WaitingForAckNack { .. } => false,
+        }
+    }
+
+    /// Stop waiting for an ACK, without interrupting any ACK or NACK we're
+    /// sending.
+    fn stop_awaiting_ack(&mut self) {
+        match self.tx_state {
+            TxState::WaitingForAckNack { .. } => self.tx_state = TxState::Idle,
+            TxState::SendingDelimiterStart { ref mut payload }
+            | TxState::SendingCobsHeader { ref mut payload }
+            | TxState::SendingPayload {
+                ref mut payload, ..
+            }
+            | TxState::SendingDelimiterEnd { ref mut payload } => {
+                if let Payload::SFrame { resume_polls } = payload {
+                    *resume_polls = None;
+                }
+            }
+            TxState::Idle => {}
+        }
+    }
+
+    /// Start re-sending the frame in the tx_buffer straight away, rather
+    /// than waiting for the next `run_tx` to notice it from `Idle`. We
+    /// don't if there's something which `Idle` would do first, or if the
+    /// frame is about to be dropped.
+    fn resend_now(&mut self) {
+        if matches!(self.tx_state, TxState::Idle)
+            && !self.tx_paused
+            && !self.tx_buffer.is_empty()
+            && !self.sframe_due()
+            && !self.tx_expired()
+            && !self.tx_nacked_out()
+        {
+            trace!(self, "TX re-sending after NACK");
+            self.stats.retransmits = self.stats.retransmits.wrapping_add(1);
+            self.set_iframe_ack(false);
+            self.tx_state = TxState::SendingDelimiterStart {
+                payload: Payload::IFrame,
+            };
+        }
+    }
+
+    /// If we owe the far end an ACK, and have an I-Frame ready to go, send
+    /// the ACK as part of the I-Frame (see `set_piggyback_acks`). Returns
+    /// true if we did.
+    fn piggyback_ack(&mut self) -> bool {
+        let iframe = !self.tx_forwarding
+            && matches!(
+                self.tx_buffer.first().and_then(|&b| FrameType::from_u8(b)),
+                Some(FrameType::IFrame(_)) | Some(FrameType::IFrameAck(_))
+            );
+        if self.piggyback_acks
+            && !self.coloured_acks
+            && iframe
+            && self.sframe_pending == Some(HEADER_ACK)
+        {
+            trace!(self, "TX piggybacking ACK");
+            self.sframe_pending = None;
+            self.set_iframe_ack(true);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Make the I-Frame in the tx_buffer carry an ACK, or not. A re-sent
+    /// frame mustn't carry the ACK from last time, as that ACK could be
+    /// taken for the ACK of a later frame.
+    fn set_iframe_ack(&mut self, ack: bool) {
+        if self.tx_forwarding {
+            return;
+        }
+        let frame_type = match FrameType::from_u8(self.tx_buffer[FRAME_TYPE_IDX]) {
+            Some(FrameType::IFrame(colour)) | Some(FrameType::IFrameAck(colour)) if ack => {
+                FrameType::IFrameAck(colour)
+            }
+            Some(FrameType::IFrame(colour)) | Some(FrameType::IFrameAck(colour)) => {
+                FrameType::IFrame(colour)
+            }
+            _ => return,
+        };
+        if frame_type.to_u8() != self.tx_buffer[FRAME_TYPE_IDX] {
+            let payload_len = self.tx_buffer[PAYLOAD_LENGTH_IDX] as usize;
+            build_frame(
+                &mut self.tx_buffer,
+                frame_type.to_u8(),
+                payload_len,
+                self.checksum_covers,
+                self.crc_seed,
+                self.checksum_width,
+            );
+        }
+    }
+
+    /// Should we send the pending ACK or NACK now? An ACK can be held back
+    /// (see `set_ack_delay`), but not once we have something else to send.
+    fn sframe_due(&self) -> bool {
+        if self.flow_pending.is_some() || self.resume_due() {
+            // A PAUSE or RESUME doesn't wait, and nor does an ACK before it
+            return true;
+        }
+        match (self.sframe_pending, self.ack_delay_ms) {
+            (None, _) => false,
+            (Some(header), Some(delay)) if is_ack(header) && self.clock.is_some() => {
+                !self.tx_buffer.is_empty()
+                    || self.now().wrapping_sub(self.ack_scheduled_at) >= delay
+            }
+            (Some(_), _) => true,
+        }
+    }
+
+    /// Arrange for an ACK to be sent, for a frame of the given colour and
+    /// checksum.
+    fn schedule_ack(&mut self, colour: Colour, checksum: u32) {
+        if !matches!(self.sframe_pending, Some(header) if is_ack(header)) {
+            self.ack_scheduled_at = self.now();
+        }
+        self.ack_checksum = checksum;
+        let colour = if self.coloured_acks {
+            colour
+        } else {
+            Colour::Purple
+        };
+        self.sframe_pending = Some(FrameType::Ack(colour).to_u8());
+    }
+
+    /// Is an ACK of this colour for the frame we have in flight? Without
+    /// coloured ACKs, any (uncoloured) ACK is.
+    fn ack_matches(&self, colour: Colour) -> bool {
+        if self.sync_in_flight() {
+            false
+        } else if self.coloured_acks {
+            // A RESET counts as Purple
+            match FrameType::from_u8(self.tx_buffer[FRAME_TYPE_IDX]) {
+                Some(FrameType::IFrame(c))
+                | Some(FrameType::IFrameAck(c))
+                | Some(FrameType::Fragment(c)) => colour == c,
+                _ => colour == Colour::Purple,
+            }
+        } else {
+            colour == Colour::Purple
+        }
+    }
+
+    /// Did the last call to `run_tx` write a byte to the transport? If it
+    /// didn't, calling it again straight away may well not help (although
+    /// moving out of the idle state doesn't write anything, so one more call
+    /// is worth it after a `send`).
+    pub fn tx_wrote_byte(&self) -> bool {
+        self.tx_wrote_byte
+    }
+
+    /// Pumps the TX state machine. Returns `true` if it makes sense to call this function again right away.
+    /// Returns `false` if we're stuck waiting for an ack and you should wait a while before trying again.
+    pub fn run_tx(&mut self) -> Result<WaitingForAckNack, Error<TXO::Error, RXI::Error>> {
+        self.tx_wrote_byte = false;
+        if self.tx_flush_pending {
+            self.flush_frame()?;
+        }
+        if self.tx_expired() {
+            trace!(self, "TX frame expired");
+            self.drop_tx_frame();
+            return Err(Error::FrameExpired);
+        }
+        if self.tx_nacked_out() {
+            trace!(self, "TX frame NACKed too many times");
+            self.drop_tx_frame();
+            return Err(Error::TooManyNacks);
+        }
+        let mut result = WaitingForAckNack::No;
+        let old_state = core::mem::discriminant(&self.tx_state);
+        // Each arm writes at most one byte, before it changes anything. If
+        // the writer blocks, the `?` returns with our state untouched, and
+        // the next call works out the same byte again.
+        self.tx_state = match self.tx_state {
+            TxState::Idle => {
+                if self.peer_paused && !self.tx_paused {
+                    if self.pause_timed_out() {
+                        trace!(self, "TX paused for too long, carrying on");
+                        self.peer_paused = false;
+                    }
+                    self.held_polls = self.held_polls.saturating_add(1);
+                }
+                // Do nothing
+                if self.tx_paused {
+                    TxState::Idle
+                } else if !self.held_by_peer() && self.piggyback_ack() {
+                    TxState::SendingDelimiterStart {
+                        payload: Payload::IFrame,
+                    }
+                } else if self.sframe_due() {
+                    // ACKs and NACKs go first, so a response never holds up
+                    // the ACK for the message it's responding to.
+                    self.start_sframe(None)
+                } else if self.tx_forwarding {
+                    TxState::SendingDelimiterStart {
+                        payload: Payload::Forward,
+                    }
+                } else if !self.tx_buffer.is_empty() && !self.held_by_peer() {
+                    if self.nacks > 0 {
+                        // A NACKed frame we couldn't re-send straight away
+                        self.stats.retransmits = self.stats.retransmits.wrapping_add(1);
+                    }
+                    let payload = match FrameType::from_u8(self.tx_buffer[FRAME_TYPE_IDX]) {
+                        Some(FrameType::Control(_)) => Payload::Control,
+                        Some(FrameType::Broadcast(_)) => Payload::Broadcast {
+                            repeats: self.broadcast_repeats,
+                        },
+                        _ => {
+                            self.set_iframe_ack(false);
+                            Payload::IFrame
+                        }
+                    };
+                    TxState::SendingDelimiterStart { payload }
+                } else if self.window_next != self.tx_seq && !self.peer_paused {
+                    // The next numbered frame in the window
+                    let offset = self.window_next.wrapping_sub(self.window_base()) as usize;
+                    let frame = &self.window[(self.window_head + offset) % WINDOW];
+                    // The tx_buffer is empty, and just as big
+                    self.tx_buffer.extend_from_slice(frame).unwrap();
+                    TxState::SendingDelimiterStart {
+                        payload: Payload::Windowed,
+                    }
+                } else if self.window_len > 0 {
+                    // The whole window has been sent
+                    if !self.ack_timed_out(self.window_polls) {
+                        result = WaitingForAckNack::Yes;
+                        self.window_polls = self.window_polls.saturating_add(1);
+                    } else if matches!(self.max_retries, Some(max) if self.retries >= max) {
+                        trace!(self, "TX no ACK after {} re-sends, giving up", self.retries);
+                        self.clear_window();
+                        self.link_dead = true;
+                        self.connected = false;
+                        return Err(Error::LinkDead);
+                    } else {
+                        trace!(
+                            self,
+                            "TX no ACK after {} polls, going back",
+                            self.window_polls
+                        );
+                        // If we were paused, the RESUME may have been lost
+                        self.peer_paused = false;
+                        self.retries = self.retries.saturating_add(1);
+                        self.tx_result = Some(TxResult::TimedOut);
+                        self.window_go_back();
+                    }
+                    TxState::Idle
+                } else if self.keepalive_due() {
+                    self.writer_write(0x00)?;
+                    TxState::Idle
+                } else {
+                    TxState::Idle
+                }
+            }
+            TxState::SendingDelimiterStart { payload } if self.framing != Framing::Cobs => {
+                self.writer_write(SYNC_WORD[self.tx_sync_sent])?;
+                self.tx_sync_sent += 1;
+                if self.tx_sync_sent < SYNC_WORD.len() {
+                    TxState::SendingDelimiterStart { payload }
+                } else {
+                    // The frame goes out as it is
+                    self.tx_sync_sent = 0;
+                    TxState::SendingPayload {
+                        payload,
+                        sent: 0,
+                        left: 0,
+                        full: false,
+                    }
+                }
+            }
+            TxState::SendingDelimiterStart { payload } => {
+                self.writer_write(0x00)?;
+                if self.another_delimiter() {
+                    TxState::SendingDelimiterStart { payload }
+                } else if let Payload::Forward = payload {
+                    // Already has its COBS header
+                    TxState::SendingPayload {
+                        payload,
+                        sent: 0,
+                        left: 0,
+                        full: false,
+                    }
+                } else {
+                    TxState::SendingCobsHeader { payload }
+                }
+            }
+            TxState::SendingCobsHeader { payload } => {
+                // Count how many bytes up to the first zero byte.
+                // And send that number
+                let num = match payload {
+                    Payload::IFrame
+                    | Payload::Control
+                    | Payload::Broadcast { .. }
+                    | Payload::Forward
+                    | Payload::Windowed => cobs_find_zero(&self.tx_buffer),
+                    Payload::SFrame { .. } => cobs_find_zero(self.sframe_bytes()),
+                };
+                self.writer_write(num as u8 + 1)?;
+                TxState::SendingPayload {
+                    payload,
+                    sent: 0,
+                    left: num as u8,
+                    full: num == cobs::MAX_RUN,
+                }
+            }
+            TxState::SendingPayload {
+                payload,
+                sent,
+                left,
+                full,
+            } => {
+                // Send the complete frame
+                let source = match payload {
+                    Payload::IFrame
+                    | Payload::Control
+                    | Payload::Broadcast { .. }
+                    | Payload::Forward
+                    | Payload::Windowed => &self.tx_buffer,
+                    Payload::SFrame { .. } => self.sframe_bytes(),
+                };
+                let len = source.len();
+                let raw = matches!(payload, Payload::Forward) || self.framing != Framing::Cobs;
+                let (b, new_sent, left, full) = if raw {
+                    // Already encoded, or not COBS encoded at all
+                    (source[sent], sent + 1, 0, false)
+                } else if left == 0 && full {
+                    // A full block doesn't end in a zero, so start a new
+                    // block without using up a byte
+                    let num = cobs_find_zero(&source[sent..]);
+                    (num as u8 + 1, sent, num as u8, num == cobs::MAX_RUN)
+                } else if source[sent] == 0 {
+                    // Can't send zeros - send gap to next zero instead
+                    let num = cobs_find_zero(&source[sent + 1..]);
+                    (num as u8 + 1, sent + 1, num as u8, num == cobs::MAX_RUN)
+                } else {
+                    (source[sent], sent + 1, left - 1, full)
+                };
+                self.writer_write(b)?;
+                if new_sent == len {
+                    TxState::SendingDelimiterEnd { payload }
+                } else {
+                    TxState::SendingPayload {
+                        payload,
+                        sent: new_sent,
+                        left,
+                        full,
+                    }
+                }
+            }
+            TxState::SendingDelimiterEnd { payload } => {
+                if self.framing == Framing::Cobs && !self.tx_verifying {
+                    self.writer_write(0x00)?;
+                    if self.another_delimiter() {
+                        return Ok(result);
+                    }
+                }
+                if self.max_flush_failures.is_some() {
+                    // The frame only counts as sent once the writer says it
+                    // has gone. Until then, we just keep trying to flush.
+                    self.tx_verifying = true;
+                    self.verify_flush()?;
+                    self.tx_verifying = false;
+                } else if self.flush_at_frame_end {
+                    // The frame is done either way. If the writer isn't
+                    // ready, we try again on the next call.
+                    let _ = self.flush_frame();
+                }
+                match payload {
+                    Payload::IFrame => {
+                        self.stats.frames_sent = self.stats.frames_sent.wrapping_add(1);
+                        self.tx_sent_at = self.now();
+                        TxState::WaitingForAckNack { num_polls: 0 }
+                    }
+                    Payload::Windowed => {
+                        let seq = self.tx_buffer[DATA_IDX];
+                        if seq == self.window_base() {
+                            // The ACK timeout runs from the oldest frame
+                            self.tx_sent_at = self.now();
+                            self.window_polls = 0;
+                        }
+                        if seq == self.window_next {
+                            self.window_next = seq.wrapping_add(1);
+                        }
+                        self.stats.frames_sent = self.stats.frames_sent.wrapping_add(1);
+                        self.tx_buffer.truncate(0);
+                        TxState::Idle
+                    }
+                    Payload::Control | Payload::Broadcast { repeats: 0 } | Payload::Forward => {
+                        // Control frames are fire-and-forget
+                        self.tx_buffer.truncate(0);
+                        self.tx_forwarding = false;
+                        TxState::Idle
+                    }
+                    Payload::Broadcast { repeats } => TxState::SendingDelimiterStart {
+                        payload: Payload::Broadcast {
+                            repeats: repeats - 1,
+                        },
+                    },
+                    Payload::SFrame {
+                        resume_polls: Some(num_polls),
+                    } => TxState::WaitingForAckNack { num_polls },
+                    Payload::SFrame { resume_polls: None } => TxState::Idle,
+                }
+            }
+            TxState::WaitingForAckNack { num_polls } => {
+                if !self.tx_paused && self.sframe_due() {
+                    // The far end may be waiting for us too
+                    self.start_sframe(Some(num_polls))
+                } else if self.ack_timed_out(num_polls) && self.tx_paused {
+                    // Retry as soon as we're resumed
+                    result = WaitingForAckNack::Yes;
+                    TxState::WaitingForAckNack { num_polls }
+                } else if self.ack_timed_out(num_polls) {
+                    // Poll N times for ack/nack, else retry
+                    if matches!(self.max_retries, Some(max) if self.retries >= max) {
+                        trace!(self, "TX no ACK after {} re-sends, giving up", self.retries);
+                        self.drop_tx_frame();
+                        self.link_dead = true;
+                        self.connected = false;
+                        return Err(Error::LinkDead);
+                    }
+                    self.retries = self.retries.saturating_add(1);
+                    self.stats.retransmits = self.stats.retransmits.wrapping_add(1);
+                    self.tx_result = Some(TxResult::TimedOut);
+                    trace!(self, "TX no ACK after {} polls, re-sending", num_polls);
+                    // If we were paused, the RESUME may have been lost
+                    self.peer_paused = false;
+                    self.set_iframe_ack(false);
+                    TxState::SendingDelimiterStart {
+                        payload: Payload::IFrame,
+                    }
+                } else {
+                    result = WaitingForAckNack::Yes;
+                    TxState::WaitingForAckNack {
+                        num_polls: num_polls + 1,
+                    }
+                }
+            }
+        };
+        if core::mem::discriminant(&self.tx_state) != old_state {
+            trace!(self, "TX {:?}", self.tx_state);
+        }
+        Ok(result)
+    }
+
+    /// Like `run_tx`, but keeps going until the writer blocks, the frame
+    /// we're sending is finished, or we've written `max_bytes`. Returns how
+    /// many bytes were written. This suits writers which can take a burst of
+    /// bytes at once (e.g. into a DMA buffer).
+    ///
+    /// While we're waiting for an ACK, each call only counts as one poll
+    /// (see `new`). If the writer blocks after some bytes have been
+    /// written, you get the count rather than `Error::TransportWouldBlock`.
+    pub fn run_tx_burst(
+        &mut self,
+        max_bytes: usize,
+    ) -> Result<(usize, WaitingForAckNack), Error<TXO::Error, RXI::Error>> {
+        let mut written = 0;
+        let mut result = WaitingForAckNack::No;
+        while written < max_bytes {
+            let ending = matches!(self.tx_state, TxState::SendingDelimiterEnd { .. });
+            result = match self.run_tx() {
+                Ok(result) => result,
+                Err(Error::TransportWouldBlock) if written > 0 => break,
+                Err(e) => return Err(e),
+            };
+            if self.tx_wrote_byte {
+                written += 1;
+            }
+            let finished = ending && !matches!(self.tx_state, TxState::SendingDelimiterEnd { .. });
+            let idle = matches!(
+                self.tx_state,
+                TxState::Idle | TxState::WaitingForAckNack { .. }
+            );
+            if finished || idle {
+                break;
+            }
+        }
+        Ok((written, result))
+    }
+
+    /// Keep running the TX state machine until the frame we're sending has
+    /// been written out in full, and then until the writer has flushed it.
+    /// Whenever the writer would block, we just try again. This is handy
+    /// when a frame must be on the wire before you (say) change radio mode.
+    ///
+    /// Unlike `send_blocking`, this doesn't wait for an ACK. If we're
+    /// `Idle`, there's no frame to finish and we return straight away - a
+    /// message you've just queued with `send` isn't started until the next
+    /// `run_tx`.
+    pub fn flush_tx(&mut self) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        if matches!(self.tx_state, TxState::Idle) {
+            return Ok(());
+        }
+        while !matches!(self.tx_state, TxState::WaitingForAckNack { .. }) {
+            let ending = matches!(self.tx_state, TxState::SendingDelimiterEnd { .. });
+            match self.run_tx() {
+                Ok(_) | Err(Error::TransportWouldBlock) => {}
+                Err(e) => return Err(e),
+            }
+            if ending && !matches!(self.tx_state, TxState::SendingDelimiterEnd { .. }) {
+                break;
+            }
+        }
+        loop {
+            match self.flush_frame() {
+                Ok(()) => return Ok(()),
+                Err(Error::TransportWouldBlock) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Pumps the RX state machine. Returns an `RxEvent` if a complete frame
+    /// has arrived which the application needs to know about.
+    ///
+    /// If `run_rx_slice` has queued any messages, they are returned (oldest
+    /// first) before any more bytes are read.
+    ///
+    /// A message which passes its checksum but fails to decode gives
+    /// `Error::Codec`, and is NACKed so the far end re-sends it.
+    pub fn run_rx(&mut self) -> RxResult<'_, RXM, TXO, RXI> {
+        // Any payload we handed out last time is no longer available
+        self.rx_payload_ready = false;
+        if let Some((m, _)) = self.dequeue_rx() {
+            return Ok(Some(RxEvent::Message(m)));
+        }
+        let next_byte = self.reader_read()?;
+        let received = self.process_rx_byte(next_byte);
+        let message = match received {
+            Received::Message => Some(self.decode_received().map_err(Error::Codec)?),
+            _ => None,
+        };
+        Ok(self.rx_event(received, message))
+    }
+
+    /// Like `run_rx`, but reads up to `max_bytes` bytes in one go. We stop
+    /// early when a frame is completed which gives an `RxEvent`, or when the
+    /// reader blocks. This bounds how long each call takes, without needing
+    /// a call for every byte.
+    ///
+    /// Messages queued by `run_rx_slice` are returned first, as with
+    /// `run_rx`.
+    pub fn run_rx_n(
+        &mut self,
+        max_bytes: usize,
+    ) -> Result<RxProgress<'_, RXM>, Error<TXO::Error, RXI::Error>> {
+        self.rx_payload_ready = false;
+        if let Some((m, _)) = self.dequeue_rx() {
+            return Ok(RxProgress::FrameReady(RxEvent::Message(m)));
+        }
+        let mut received = Received::Nothing;
+        let mut consumed = 0;
+        while consumed < max_bytes {
+            let next_byte = match self.reader_read() {
+                Ok(b) => b,
+                Err(Error::TransportWouldBlock) => return Ok(RxProgress::Blocked),
+                Err(e) => return Err(e),
+            };
+            consumed += 1;
+            received = self.process_rx_byte(next_byte);
+            let quiet = match received {
+                Received::Nothing => true,
+                Received::Duplicate => self.duplicate_policy == DuplicatePolicy::Drop,
+                _ => false,
+            };
+            if !quiet {
+                break;
+            }
+        }
+        let message = match received {
+            Received::Message => Some(self.decode_received().map_err(Error::Codec)?),
+            _ => None,
+        };
+        Ok(match self.rx_event(received, message) {
+            Some(event) => RxProgress::FrameReady(event),
+            None => RxProgress::BytesConsumed(consumed),
+        })
+    }
+
+    /// Like `run_rx`, but a new message is decoded into `message` instead of
+    /// being returned. `RxEvent::Message(())` tells you that it has been.
+    ///
+    /// This lets the codec re-use whatever storage `message` already has
+    /// (e.g. a `heapless::Vec`), rather than building a fresh message for
+    /// every frame - see `PayloadCodec::decode_into`. Messages queued by
+    /// `run_rx_slice` have already been decoded, so they are just moved into
+    /// `message`.
+    pub fn run_rx_into(&mut self, message: &mut RXM) -> RxResult<'_, (), TXO, RXI> {
+        self.rx_payload_ready = false;
+        if let Some((m, _)) = self.dequeue_rx() {
+            *message = m;
+            return Ok(Some(RxEvent::Message(())));
+        }
+        let next_byte = self.reader_read()?;
+        let received = self.process_rx_byte(next_byte);
+        let decoded = match received {
+            Received::Message => {
+                self.decode_received_into(message).map_err(Error::Codec)?;
+                Some(())
+            }
+            _ => None,
+        };
+        Ok(self.rx_event(received, decoded))
+    }
+
+    /// Like `run_rx`, but only for messages, and with some metadata about
+    /// each one. Duplicates (which `run_rx` drops) are returned too, marked
+    /// as such. Other events are dropped - use `run_rx` if you need them.
+    ///
+    /// A message which passes its checksum but fails to decode gives
+    /// `Error::Codec`. We NACK it rather than ACK it, so the far end can
+    /// re-send it.
+    pub fn poll(&mut self) -> PollResult<RXM, TXO, RXI> {
+        self.rx_payload_ready = false;
+        if let Some((message, colour)) = self.dequeue_rx() {
+            return Ok(Some(ReceivedMessage {
+                message,
+                colour,
+                duplicate: false,
+            }));
+        }
+        let next_byte = self.reader_read()?;
+        let received = match self.process_rx_byte(next_byte) {
+            Received::Message => {
+                let message = self.decode_received().map_err(Error::Codec)?;
+                self.rx_payload_ready = true;
+                Some((message, false))
+            }
+            Received::Duplicate => CODEC::decode(self.new_payload())
+                .ok()
+                .map(|message| (message, true)),
+            _ => None,
+        };
+        Ok(received.map(|(message, duplicate)| ReceivedMessage {
+            message,
+            colour: self.rx_frame_colour(),
+            duplicate,
+        }))
+    }
+
+    /// Pumps the RX state machine, and when a new (i.e. not duplicate)
+    /// message arrives, copies its payload into `dst` without decoding it.
+    /// Returns the length of the payload, or `None` if nothing has been
+    /// completed yet. As with `run_rx`, we process one byte per call.
+    ///
+    /// If the payload doesn't fit in `dst`, you get `Error::BufferTooSmall`,
+    /// and we NACK the message rather than ACK it, so the far end sends it
+    /// again.
+    ///
+    /// Messages queued by `run_rx_slice` have already been decoded, so we
+    /// can't give you their payload. While there are any, you get
+    /// `Error::MessagesQueued` and nothing is read - take them with `run_rx`
+    /// or `poll` first.
+    pub fn poll_into(
+        &mut self,
+        dst: &mut [u8],
+    ) -> Result<Option<usize>, Error<TXO::Error, RXI::Error>> {
+        if !self.rx_queue.is_empty() {
+            return Err(Error::MessagesQueued);
+        }
+        self.rx_payload_ready = false;
+        let next_byte = self.reader_read()?;
+        if let Received::Message = self.process_rx_byte(next_byte) {
+            let payload = self.new_payload();
+            let len = payload.len();
+            if len > dst.len() {
+                self.reject_received();
+                return Err(Error::BufferTooSmall);
+            }
+            dst[..len].copy_from_slice(payload);
+            Ok(Some(len))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Turn what the RX state machine gave us into an event for the
+    /// application. `message` is the decoded message, if there was one.
+    fn rx_event<M>(&mut self, received: Received, message: Option<M>) -> Option<RxEvent<'_, M>> {
+        match received {
+            Received::Nothing => None,
+            Received::Duplicate => match self.duplicate_policy {
+                DuplicatePolicy::Drop => None,
+                DuplicatePolicy::Report => Some(RxEvent::Duplicate {
+                    colour: self.rx_frame_colour(),
+                }),
+            },
+            Received::Message => {
+                let m = message?;
+                self.rx_payload_ready = true;
+                Some(RxEvent::Message(m))
+            }
+            Received::Control(type_byte) => Some(RxEvent::Control {
+                type_byte,
+                payload: &self.rx_buffer[DATA_IDX..],
+            }),
+            Received::Unknown(frame) => Some(RxEvent::UnknownFrame(frame)),
+            Received::Truncated => Some(RxEvent::TruncatedFrame),
+            Received::Corrupt => Some(RxEvent::CorruptFrame),
+            Received::Reset => Some(RxEvent::Reset),
+            Received::GlitchRecovery => Some(RxEvent::GlitchRecovery),
+            Received::ChecksumWidthMismatch => Some(RxEvent::ChecksumWidthMismatch),
+            Received::Stalled => Some(RxEvent::LinkStalled),
+        }
+    }
+
+    /// Serve one request: pump the RX state machine until a message
+    /// arrives, pass it to `handler`, and queue whatever it returns to be
+    /// sent back. Returns `Ok(true)` if we responded, or `Ok(false)` if the
+    /// reader ran out of bytes before a whole request arrived.
+    ///
+    /// The ACK for the request is sent just ahead of the response. If you
+    /// also call `set_ack_delay`, the far end sees the two back-to-back.
+    ///
+    /// We can't queue a response while one of our own messages is in flight,
+    /// so this returns `Error::PacketInFlight` without reading anything if
+    /// that's the case. Events other than messages are dropped.
+    pub fn respond(
+        &mut self,
+        handler: impl FnOnce(RXM) -> TXM,
+    ) -> Result<bool, Error<TXO::Error, RXI::Error>> {
+        if !self.tx_ready() {
+            return Err(Error::PacketInFlight);
+        }
+        loop {
+            match self.run_rx() {
+                Ok(Some(RxEvent::Message(request))) => {
+                    let response = handler(request);
+                    self.send(&response)?;
+                    return Ok(true);
+                }
+                Ok(_) => {}
+                Err(Error::TransportWouldBlock) => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Build a frame of the given type around `payload`, and feed it through
+    /// the RX state machine as if it had just arrived (see `run_rx_slice`).
+    /// The frame uses our own framing and checksum settings, so it's always
+    /// valid. This saves a test working out every byte by hand.
+    ///
+    /// Gives `FrameError::BufferFull` if the RX queue filled up before the
+    /// whole frame was used.
+    #[cfg(feature = "testing")]
+    pub fn feed_rx_frame(
+        &mut self,
+        frame_type: FrameType,
+        payload: &[u8],
+    ) -> Result<(), FrameError> {
+        let mut frame = [0u8; MAX_PAYLOAD + CHECKSUM_OVERHEAD + MAX_CHECKSUM_LEN];
+        let frame_len = encode_frame(frame_type, payload, self.frame_format(), &mut frame)?;
+        let mut wire = alloc::vec::Vec::new();
+        if self.framing == Framing::Cobs {
+            wire.push(0);
+            cobs_encode_to_vec(&frame[..frame_len], &mut wire);
+        } else {
+            wire.extend_from_slice(&SYNC_WORD);
+            wire.extend_from_slice(&frame[..frame_len]);
+        }
+        if self.run_rx_slice(&wire) != wire.len() {
+            return Err(FrameError::BufferFull);
+        }
+        if self.framing == Framing::Cobs {
+            // The frame is complete without its closing delimiter, so it
+            // doesn't matter if the queue is now too full to take it - the
+            // next frame's opening delimiter does the same job.
+            self.run_rx_slice(&[0]);
+        }
+        Ok(())
+    }
+
+    /// Call `run_tx` until there's nothing more to send for now (we're idle,
+    /// or waiting for an ACK) or the writer blocks, and return every byte
+    /// written since the last call. The bytes still go to the writer as
+    /// usual.
+    #[cfg(feature = "testing")]
+    pub fn drain_tx(&mut self) -> alloc::vec::Vec<u8> {
+        loop {
+            let was_resting = !self.tx_in_progress();
+            if self.run_tx().is_err() {
+                break;
+            }
+            let waiting = matches!(self.tx_state, TxState::WaitingForAckNack { .. });
+            if waiting || (was_resting && !self.tx_in_progress()) {
+                break;
+            }
+        }
+        core::mem::take(&mut self.tx_capture)
+    }
+
+    /// Pumps the RX state machine with a whole slice of received bytes,
+    /// rather than reading them from the reader one at a time. This is
+    /// useful if your bytes arrive in bursts (e.g. from a DMA buffer).
+    ///
+    /// Any messages received are queued, and `run_rx` will hand them back
+    /// one at a time. If the queue fills up, we stop early. Returns how many
+    /// bytes were used - pass the rest in again once you've called `run_rx`
+    /// to empty the queue.
+    ///
+    /// We also stop early once we've used up the budget set with
+    /// `set_rx_slice_budget`.
+    ///
+    /// Other events (such as control frames and truncated frames) can't be
+    /// queued, so they are dropped, as are messages which fail to decode.
+    /// Use `run_rx` if you need to see those.
+    pub fn run_rx_slice(&mut self, bytes: &[u8]) -> usize {
+        let bytes = match self.rx_slice_budget {
+            Some(max) if max < bytes.len() => &bytes[..max],
+            _ => bytes,
+        };
+        if !bytes.is_empty() {
+            self.note_rx_byte();
+        }
+        for (used, &b) in bytes.iter().enumerate() {
+            if self.rx_queue.len() == self.rx_queue.capacity() {
+                return used;
+            }
+            self.bytes_received = self.bytes_received.wrapping_add(1);
+            if let Received::Message = self.process_rx_byte(b) {
+                if let Ok(m) = self.decode_received() {
+                    // We checked above that there's room
+                    let _ = self.rx_queue.enqueue((m, self.rx_frame_colour()));
+                    self.rx_queued();
+                }
+            }
+        }
+        bytes.len()
+    }
+
+    /// Process received bytes until the reader has no more, and return how
+    /// many complete frames (of any type, with a valid checksum) were in
+    /// them. This saves calling `run_rx` in a loop when a burst of frames
+    /// has arrived.
+    ///
+    /// As with `run_rx_slice`, messages are queued for `run_rx` to hand
+    /// back, and we stop early if the queue fills up. Other events are
+    /// dropped.
+    pub fn run_rx_until_block(&mut self) -> Result<usize, Error<TXO::Error, RXI::Error>> {
+        let before = self.frames_received;
+        self.read_into_queue()?;
+        Ok(self.frames_received.wrapping_sub(before) as usize)
+    }
+
+    /// Runs both state machines until the link is quiet: every received byte
+    /// has been read, any ACK or NACK has been sent, and nothing is in
+    /// flight. This is handy for tests and host-side tools which want to
+    /// know everything has settled before they stop.
+    ///
+    /// This blocks until the far end ACKs whatever we're sending, so set a
+    /// maximum in-flight time if it might never answer. While TX is paused,
+    /// nothing left to send is waited for.
+    ///
+    /// As with `run_rx_slice`, messages are queued for `run_rx` to hand
+    /// back, and we stop early if the queue fills up. Other events are
+    /// dropped.
+    #[cfg(feature = "std")]
+    pub fn run_to_completion(&mut self) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        loop {
+            if !self.read_into_queue()? {
+                return Ok(());
+            }
+            let quiet = matches!(self.tx_state, TxState::Idle)
+                && (self.tx_paused
+                    || self.held_by_peer()
+                    || (self.tx_buffer.is_empty()
+                        && self.window_len == 0
+                        && self.sframe_pending.is_none()
+                        && self.flow_pending.is_none()
+                        && !self.resume_due()));
+            if quiet {
+                return Ok(());
+            }
+            match self.run_tx() {
+                Ok(_) | Err(Error::TransportWouldBlock) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Queue a message, and keep running both state machines until the far
+    /// end ACKs it. This is handy on a host, where there's nothing else to
+    /// do while you wait.
+    ///
+    /// We give up after `max_cycles` goes round, and return
+    /// `Error::TransportWouldBlock` - the message is still in flight, so you
+    /// can carry on calling `run_tx` and `run_rx` if you like. If the frame
+    /// is dropped (see `set_max_retries`, `set_max_nacks` and
+    /// `set_max_in_flight_time`), or the transport fails, you get that error
+    /// instead.
+    ///
+    /// As with `run_rx_slice`, any messages which arrive in the meantime are
+    /// queued for `run_rx` to hand back. If the queue fills up, we stop
+    /// reading, so we won't see the ACK.
+    pub fn send_blocking(
+        &mut self,
+        message: &TXM,
+        max_cycles: usize,
+    ) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        self.send(message)?;
+        for _ in 0..max_cycles {
+            self.read_into_queue()?;
+            if self.tx_buffer.is_empty() && self.window_len == 0 {
+                return Ok(());
+            }
+            match self.run_tx() {
+                Ok(_) | Err(Error::TransportWouldBlock) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(Error::TransportWouldBlock)
+    }
+
+    /// Send a message and wait until the far end has ACKed it. This is
+    /// `send_blocking` for async code - whenever the transport would block,
+    /// we yield to the executor and try again when next polled. If a message
+    /// is already in flight, we wait for that to finish first.
+    ///
+    /// **This busy-polls.** We wake ourselves up straight away every time we
+    /// yield, so while we wait the task never sleeps and the CPU runs flat
+    /// out. Use `send_frame_with` to wait on a timer or an interrupt instead.
+    ///
+    /// As with `send_blocking`, any messages which arrive in the meantime
+    /// are queued for `recv_frame` (or `run_rx`) to hand back. If the queue
+    /// fills up, we stop reading, so we won't see the ACK.
+    #[cfg(feature = "async")]
+    pub async fn send_frame(&mut self, message: &TXM) -> Result<(), Error<TXO::Error, RXI::Error>> {
+        self.send_frame_with(message, || YieldNow(false)).await
+    }
+
+    /// Like `send_frame`, but whenever the transport would block we await
+    /// the future which `wait` returns, rather than busy-polling. A future
+    /// which completes on a timer tick, or when the UART has something for
+    /// us, lets the task sleep in between.
+    #[cfg(feature = "async")]
+    pub async fn send_frame_with<W, F>(
+        &mut self,
+        message: &TXM,
+        mut wait: W,
+    ) -> Result<(), Error<TXO::Error, RXI::Error>>
+    where
+        W: FnMut() -> F,
+        F: core::future::Future<Output = ()>,
+    {
+        loop {
+            match self.send(message) {
+                Ok(()) => break,
+                Err(Error::PacketInFlight) => {}
+                Err(e) => return Err(e),
+            }
+            self.pump_async(&mut wait).await?;
+        }
+        while !self.tx_buffer.is_empty() || self.window_len != 0 {
+            self.pump_async(&mut wait).await?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the next message from the far end. Our own frames (and the
+    /// ACK for each message we receive) keep going out while we wait. Events
+    /// other than messages are dropped.
+    ///
+    /// **This busy-polls**, just like `send_frame`. Use `recv_frame_with` to
+    /// wait on a timer or an interrupt instead.
+    #[cfg(feature = "async")]
+    pub async fn recv_frame(&mut self) -> Result<RXM, Error<TXO::Error, RXI::Error>> {
+        self.recv_frame_with(|| YieldNow(false)).await
+    }
+
+    /// Like `recv_frame`, but whenever the transport would block we await
+    /// the future which `wait` returns, rather than busy-polling.
+    #[cfg(feature = "async")]
+    pub async fn recv_frame_with<W, F>(
+        &mut self,
+        mut wait: W,
+    ) -> Result<RXM, Error<TXO::Error, RXI::Error>>
+    where
+        W: FnMut() -> F,
+        F: core::future::Future<Output = ()>,
+    {
+        loop {
+            match self.run_tx() {
+                Ok(_) | Err(Error::TransportWouldBlock) => {}
+                Err(e) => return Err(e),
+            }
+            match self.run_rx() {
+                Ok(Some(RxEvent::Message(m))) => return Ok(m),
+                Ok(_) => {}
+                Err(Error::TransportWouldBlock) => wait().await,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Read whatever has arrived, send what we can, and then wait.
+    #[cfg(feature = "async")]
+    async fn pump_async<W, F>(&mut self, wait: &mut W) -> Result<(), Error<TXO::Error, RXI::Error>>
+    where
+        W: FnMut() -> F,
+        F: core::future::Future<Output = ()>,
+    {
+        self.read_into_queue()?;
+        match self.run_tx() {
+            Ok(_) | Err(Error::TransportWouldBlock) => {}
+            Err(e) => return Err(e),
+        }
+        wait().await;
+        Ok(())
+    }
+
+    /// Process bytes from the reader until it has no more, queueing any
+    /// messages for `run_rx`. Returns false if we stopped early because the
+    /// queue is full.
+    fn read_into_queue(&mut self) -> Result<bool, Error<TXO::Error, RXI::Error>> {
+        loop {
+            if self.rx_queue.len() == self.rx_queue.capacity() {
+                return Ok(false);
+            }
+            match self.reader_read() {
+                Ok(b) => {
+                    if let Received::Message = self.process_rx_byte(b) {
+                        let m = self.decode_received().map_err(Error::Codec)?;
+                        // We checked above that there's room
+                        let _ = self.rx_queue.enqueue((m, self.rx_frame_colour()));
+                        self.rx_queued();
+                    }
+                }
+                Err(Error::TransportWouldBlock) => return Ok(true),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Handle the payload of a new I-Frame or fragment. Returns true once we
+    /// have all of the message, ready to decode.
+    fn receive_iframe(&mut self, colour: Colour, last: bool) -> bool {
+        if colour == Colour::Purple {
+            // The far end has started over
+            self.large_rx_discard = false;
+        }
+        if self.rx_payload_large || colour == Colour::Purple {
+            // Done with the last large message, or the far end has started
+            // over.
+            self.large_rx.truncate(0);
+            self.rx_payload_large = false;
+        }
+        if last && self.large_rx.is_empty() && !self.large_rx_discard {
+            // The whole message is in one frame
+            true
+        } else {
+            if self.large_rx_discard
+                || self
+                    .large_rx
+                    .extend_from_slice(&self.rx_buffer[DATA_IDX..])
+                    .is_err()
+            {
+                // Too big - drop every fragment of this message
+                self.large_rx.truncate(0);
+                self.large_rx_discard = !last;
+                return false;
+            }
+            self.rx_payload_large = last;
+            last
+        }
+    }
+
+    /// The payload of the message we have just received.
+    fn new_payload(&self) -> &[u8] {
+        if self.rx_payload_large {
+            &self.large_rx
+        } else if self.rx_buffer[FRAME_TYPE_IDX] == HEADER_SEQ_IFRAME {
+            // Skip the sequence number
+            &self.rx_buffer[DATA_IDX + 1..]
+        } else {
+            &self.rx_buffer[DATA_IDX..]
+        }
+    }
+
+    /// Decode the message we have just received. If it won't decode, see
+    /// `reject_received`.
+    fn decode_received(&mut self) -> Result<RXM, CodecError> {
+        let result = CODEC::decode(self.new_payload());
+        if result.is_err() {
+            self.reject_received();
+        }
+        result
+    }
+
+    /// Decode the message we have just received into `message`.
+    fn decode_received_into(&mut self, message: &mut RXM) -> Result<(), CodecError> {
+        let result = CODEC::decode_into(self.new_payload(), message);
+        if result.is_err() {
+            self.reject_received();
+        }
+        result
+    }
+
+    /// The message we have just received passed its checksum but we can't
+    /// use it (it wouldn't decode, or there's nowhere to put it). If it
+    /// came in a single I-Frame, we NACK it instead of ACKing it and go back
+    /// to expecting its colour, so a re-send isn't mistaken for a duplicate.
+    /// Broadcasts and large messages are just dropped.
+    fn reject_received(&mut self) {
+        trace!(self, "RX message rejected");
+        let frame = self.rx_buffer[FRAME_TYPE_IDX];
+        match FrameType::from_u8(frame) {
+            Some(FrameType::IFrame(colour)) | Some(FrameType::IFrameAck(colour))
+                if !self.rx_payload_large =>
+            {
+                self.rx_colour = colour;
+                self.rx_last_colour = None;
+                self.sframe_pending = Some(FrameType::Nack.to_u8());
+            }
+            Some(FrameType::SeqIFrame) => {
+                self.rx_seq = self.rx_buffer[DATA_IDX];
+                self.sframe_pending = Some(FrameType::Nack.to_u8());
+            }
+            _ => {}
+        }
+    }
+
+    /// The colour of the I-Frame, fragment or broadcast in the rx_buffer.
+    fn rx_frame_colour(&self) -> Colour {
+        match FrameType::from_u8(self.rx_buffer[FRAME_TYPE_IDX]) {
+            Some(FrameType::IFrame(colour))
+            | Some(FrameType::IFrameAck(colour))
+            | Some(FrameType::Fragment(colour))
+            | Some(FrameType::Broadcast(colour)) => colour,
+            _ => Colour::Purple,
+        }
+    }
+
+    /// Feed one received byte through the RX state machine.
+    fn process_rx_byte(&mut self, next_byte: u8) -> Received {
+        let old_state = core::mem::discriminant(&self.rx_state);
+        let received = self.handle_rx_byte(next_byte);
+        if self.logger.is_some() {
+            if core::mem::discriminant(&self.rx_state) != old_state {
+                trace!(self, "RX {:?}", self.rx_state);
+            }
+            match received {
+                Received::Nothing => {}
+                Received::Message => trace!(self, "RX message"),
+                Received::Duplicate => {}
+                Received::Control(type_byte) => trace!(self, "RX control frame {:#04x}", type_byte),
+                Received::Unknown(_) => {}
+                Received::Truncated => trace!(self, "RX truncated frame"),
+                Received::Corrupt => {}
+                Received::Reset => trace!(self, "RX RESET or SYNC"),
+                Received::GlitchRecovery => trace!(self, "RX glitch recovery"),
+                Received::ChecksumWidthMismatch => trace!(self, "RX checksum width mismatch"),
+                Received::Stalled => trace!(self, "RX link stalled"),
+            }
+        }
+        received
+    }
+
+    fn handle_rx_byte(&mut self, next_byte: u8) -> Received {
+        let mut message = false;
+        let mut control = None;
+        let mut unknown = None;
+        let mut reset = false;
+        let mut glitch = false;
+        let mut stalled = false;
+        let mut mismatch = false;
+        let mut corrupt = false;
+        let mut duplicate = false;
+        if self.rx_resyncing {
+            // Waiting for the line to go quiet
+            return Received::Nothing;
+        }
+        if self.framing != Framing::Cobs {
+            if self.find_sync(next_byte) {
+                return Received::Nothing;
+            }
+        } else if next_byte == 0 && self.double_delimiters && !self.rx_zero_pending {
+            // Might be noise. Wait and see if another zero follows.
+            self.rx_zero_pending = true;
+            return Received::Nothing;
+        }
+        // If we were waiting for a second zero and didn't get one, the
+        // first one was noise and we just ignore it.
+        self.rx_zero_pending = false;
+        if next_byte == 0 && self.framing == Framing::Cobs {
+            // Applies in any state. We hang on to the last frame until we
+            // see the start of the next one, as control frame payloads are
+            // handed out by reference.
+            let outcome = match self.rx_state {
+                RxState::WantFrameType
+                | RxState::WantLength { .. }
+                | RxState::WantPayload { .. } => self.frame_truncated(),
+                RxState::WantChecksum { csum, received, .. } => {
+                    self.checksum_ended_early(csum, received)
+                }
+                _ => Received::Nothing,
+            };
+            self.rx_buffer.truncate(0);
+            self.rx_state = RxState::WantCobsHeader;
+            if !matches!(outcome, Received::Nothing) {
+                return outcome;
+            }
+        } else {
+            let in_frame = self.framing == Framing::Cobs
+                && !matches!(
+                    self.rx_state,
+                    RxState::WantFrameDelimiter | RxState::WantCobsHeader
+                );
+            let next_byte = if in_frame {
+                match self.cobs_decoder.feed(next_byte) {
+                    Some(b) => b,
+                    // Just a COBS code byte - nothing to process
+                    None => return Received::Nothing,
+                }
+            } else {
+                next_byte
+            };
+            self.rx_state = match self.rx_state {
+                // Only used for length-prefixed framing, which
+                // `find_sync` has already dealt with
+                RxState::WantSync => RxState::WantSync,
+                RxState::WantFrameDelimiter => RxState::WantFrameDelimiter,
+                RxState::WantCobsHeader => {
+                    self.cobs_decoder.start(next_byte);
+                    RxState::WantFrameType
+                }
+                RxState::WantFrameType => {
+                    if self.rx_buffer.push(next_byte).is_err() {
+                        // No room for even the header - drop it on the floor
+                        self.pause_rx();
+                        RxState::WantFrameDelimiter
+                    } else {
+                        RxState::WantLength { frame: next_byte }
+                    }
+                }
+                RxState::WantLength { frame } => {
+                    if self.rx_buffer.push(next_byte).is_err() {
+                        // No room for the length - drop it on the floor
+                        self.pause_rx();
+                        RxState::WantFrameDelimiter
+                    } else if next_byte == 0 {
+                        // Zero length - skip the payload
+                        RxState::WantChecksum {
+                            frame,
+                            csum: 0,
+                            received: 0,
+                        }
+                    } else {
+                        // Collect a payload first
+                        RxState::WantPayload {
+                            frame,
+                            length: next_byte as usize,
+                        }
+                    }
+                }
+                RxState::WantPayload { frame, length } => {
+                    if self.rx_buffer.push(next_byte).is_err() {
+                        // This packet is too long - drop it on the floor,
+                        // and ask the far end to hold off for now
+                        self.pause_rx();
+                        RxState::WantFrameDelimiter
+                    } else if self.rx_buffer.len() == length + CHECKSUM_OVERHEAD {
+                        RxState::WantChecksum {
+                            frame,
+                            csum: 0,
+                            received: 0,
+                        }
+                    } else {
+                        RxState::WantPayload { frame, length }
+                    }
+                }
+                RxState::WantChecksum {
+                    frame,
+                    csum,
+                    received,
+                } => {
+                    let csum = (csum << 8) | next_byte as u32;
+                    let received = received + 1;
+                    let width = self.checksum_width;
+                    if received == width.len() {
+                        if let Some(on_raw_frame) = &mut self.on_raw_frame {
+                            on_raw_frame(checksummed_region(&self.rx_buffer), csum);
+                        }
+                    }
+                    if received < width.len() {
+                        RxState::WantChecksum {
+                            frame,
+                            csum,
+                            received,
+                        }
+                    } else if received == width.len() && self.checksum_matches(csum, width) {
+                        // Good packet
+                        self.frames_received = self.frames_received.wrapping_add(1);
+                        self.rx_made_room();
+                        self.crc_failures = 0;
+                        let stats = &mut self.stats;
+                        match FrameType::from_u8(frame) {
+                            Some(FrameType::Ack(_))
+                            | Some(FrameType::IFrameAck(_))
+                            | Some(FrameType::SeqAck) => {
+                                stats.acks_received = stats.acks_received.wrapping_add(1)
+                            }
+                            Some(FrameType::Nack) | Some(FrameType::NackUnsupported) => {
+                                stats.nacks_received = stats.nacks_received.wrapping_add(1)
+                            }
+                            _ => {}
+                        }
+                        match FrameType::from_u8(frame) {
+                            Some(FrameType::IFrame(colour))
+                            | Some(FrameType::IFrameAck(colour))
+                            | Some(FrameType::Fragment(colour)) => {
+                                // 0. Handle a piggybacked ACK first, so we
+                                // can respond to the I-Frame straight away
+                                if frame == FrameType::IFrameAck(colour).to_u8() {
+                                    if self.awaiting_ack() && self.ack_matches(Colour::Purple) {
+                                        self.ack_received();
+                                    } else {
+                                        trace!(self, "RX unexpected piggybacked ACK");
+                                        self.tx_result = Some(TxResult::Ignored);
+                                    }
+                                }
+                                // 1. Schedule an ACK (even for duplicates)
+                                self.schedule_ack(colour, csum);
+                                // 2. Check if this IFRAME is what we expected
+                                if self.rx_colour.matches(colour) {
+                                    // A. Update our expectation.
+                                    self.rx_colour = colour.next();
+                                    self.rx_last_colour = Some(colour);
+                                    self.duplicates = 0;
+                                    // B. Tell the higher layer about it.
+                                    let last = frame != FrameType::Fragment(colour).to_u8();
+                                    message = self.receive_iframe(colour, last);
+                                } else {
+                                    trace!(self, "RX duplicate {:?} I-Frame", colour);
+                                    self.stats.duplicates_dropped =
+                                        self.stats.duplicates_dropped.wrapping_add(1);
+                                    if self.rx_last_colour == Some(colour) {
+                                        // Our ACK for it must have been lost
+                                        self.stats.colour_mismatches =
+                                            self.stats.colour_mismatches.wrapping_add(1);
+                                    }
+                                    stalled = self.duplicate_received();
+                                    duplicate = frame != FrameType::Fragment(colour).to_u8();
+                                }
+                            }
+                            Some(FrameType::Broadcast(colour)) => {
+                                // Not ACKed, and only the first copy counts
+                                if self.last_broadcast != Some(colour) {
+                                    self.last_broadcast = Some(colour);
+                                    if self.rx_payload_large {
+                                        self.large_rx.truncate(0);
+                                        self.rx_payload_large = false;
+                                    }
+                                    message = true;
+                                }
+                            }
+                            Some(FrameType::SeqIFrame) => {
+                                // Only the frame we want next is kept - the
+                                // far end goes back and re-sends the rest.
+                                match self.rx_buffer.get(DATA_IDX) {
+                                    Some(&seq) if seq == self.rx_seq => {
+                                        self.rx_seq = seq.wrapping_add(1);
+                                        if self.rx_payload_large {
+                                            self.large_rx.truncate(0);
+                                            self.rx_payload_large = false;
+                                        }
+                                        message = true;
+                                    }
+                                    Some(&seq) => {
+                                        trace!(self, "RX numbered I-Frame {} out of order", seq);
+                                        if (self.rx_seq.wrapping_sub(seq) as i8) > 0 {
+                                            // One we've already had
+                                            self.stats.duplicates_dropped =
+                                                self.stats.duplicates_dropped.wrapping_add(1);
+                                        }
+                                    }
+                                    None => {}
+                                }
+                                self.sframe_pending = Some(FrameType::SeqAck.to_u8());
+                            }
+                            Some(FrameType::SeqAck) => {
+                                if let Some(&next) = self.rx_buffer.get(DATA_IDX) {
+                                    self.window_acked(next);
+                                }
+                                self.peer_paused = false;
+                            }
+                            Some(FrameType::Pause) => {
+                                trace!(self, "RX PAUSE");
+                                self.peer_paused = true;
+                                self.paused_at = self.now();
+                                self.held_polls = 0;
+                            }
+                            Some(FrameType::Resume) => {
+                                trace!(self, "RX RESUME");
+                                self.peer_paused = false;
+                            }
+                            Some(FrameType::Ack(colour))
+                                if self.awaiting_ack()
+                                    && self.ack_matches(colour)
+                                    && self.ack_checksum_matches() =>
+                            {
+                                self.ack_received();
+                            }
+                            Some(FrameType::Nack) if self.awaiting_ack() => {
+                                // The far end is alive, at least
+                                self.retries = 0;
+                                self.nacks = self.nacks.saturating_add(1);
+                                self.stop_awaiting_ack();
+                                self.tx_result = Some(TxResult::Nacked);
+                                // leave contents in tx_buffer so we re-send
+                                self.resend_now();
+                            }
+                            Some(FrameType::Nack) if self.window_next != self.window_base() => {
+                                // Something in the window was lost
+                                self.tx_result = Some(TxResult::Nacked);
+                                self.window_go_back();
+                            }
+                            Some(FrameType::NackUnsupported)
+                                if self.awaiting_ack()
+                                    && self.rx_buffer.get(DATA_IDX)
+                                        == self.tx_buffer.get(FRAME_TYPE_IDX) =>
+                            {
+                                trace!(self, "RX far end can't handle our frame, dropping it");
+                                self.abort_pending();
+                                self.tx_result = Some(TxResult::Unsupported);
+                            }
+                            Some(FrameType::SyncAck)
+                                if self.awaiting_ack() && self.sync_in_flight() =>
+                            {
+                                trace!(self, "RX SYNC-ACK");
+                                self.ack_received();
+                            }
+                            Some(FrameType::Ack(_))
+                            | Some(FrameType::Nack)
+                            | Some(FrameType::NackUnsupported)
+                            | Some(FrameType::SyncAck) => {
+                                // We weren't expecting one (or it's a stale
+                                // ACK for an earlier frame). Ignore it.
+                                trace!(self, "RX unexpected {:?}", FrameType::from_u8(frame));
+                                self.tx_result = Some(TxResult::Ignored);
+                            }
+                            Some(FrameType::Reset) => {
+                                self.resync();
+                                self.connected = true;
+                                // ACK it (even if it's a duplicate) so the
+                                // far end stops re-sending it.
+                                self.schedule_ack(Colour::Purple, csum);
+                                reset = true;
+                            }
+                            Some(FrameType::Sync) => {
+                                self.resync();
+                                self.connected = true;
+                                // Answer it (even if it's a duplicate) so the
+                                // far end stops re-sending it.
+                                self.sframe_pending = Some(HEADER_SYNCACK);
+                                reset = true;
+                            }
+                            Some(FrameType::Control(type_byte)) => {
+                                // Application control frame. These are not
+                                // acknowledged.
+                                control = Some(type_byte);
+                            }
+                            None => {
+                                // Valid, but not understood. This is a protocol error.
+                                trace!(self, "RX did not understand frame type {:#04x}", frame);
+                                if self.nack_unknown_frames {
+                                    self.unsupported_frame = frame;
+                                    self.sframe_pending = Some(HEADER_NACK_UNSUPPORTED);
+                                }
+                                unknown = Some(frame);
+                            }
+                        }
+                        // Set after the frame is handled, as a RESET
+                        // clears it.
+                        self.last_rx_frame = Some(frame);
+                        // Now start over
+                        RxState::WantFrameDelimiter
+                    } else if received < MAX_CHECKSUM_LEN && self.framing == Framing::Cobs {
+                        // Bad, unless the far end uses a wider checksum.
+                        // We'll find out when the frame ends.
+                        RxState::WantChecksum {
+                            frame,
+                            csum,
+                            received,
+                        }
+                    } else {
+                        if self.checksum_matches(csum, ChecksumWidth::Crc32) {
+                            // We must be using CRC-16
+                            mismatch = true;
+                        } else {
+                            glitch = self.checksum_failed();
+                            corrupt = !glitch;
+                        }
+                        RxState::WantFrameDelimiter
+                    }
+                }
+            };
+        }
+        if reset {
+            Received::Reset
+        } else if glitch {
+            Received::GlitchRecovery
+        } else if mismatch {
+            Received::ChecksumWidthMismatch
+        } else if corrupt {
+            Received::Corrupt
+        } else if stalled {
+            Received::Stalled
+        } else if let Some(type_byte) = control {
+            Received::Control(type_byte)
+        } else if let Some(frame) = unknown {
+            Received::Unknown(frame)
+        } else if message {
+            Received::Message
+        } else if duplicate {
+            Received::Duplicate
+        } else {
+            Received::Nothing
+        }
+    }
+
+    /// Does the ACK in the rx_buffer carry the checksum of the frame in the
+    /// tx_buffer? An ACK without one always does (see `set_ack_checksums`).
+    fn ack_checksum_matches(&self) -> bool {
+        let echoed = &self.rx_buffer[DATA_IDX..];
+        let frame_len = self.tx_buffer.len();
+        echoed.is_empty()
+            || (frame_len >= echoed.len() && echoed == &self.tx_buffer[frame_len - echoed.len()..])
+    }
+
+    /// The far end has ACKed the frame in the tx_buffer.
+    fn ack_received(&mut self) {
+        // A RESET or SYNC doesn't use up a colour - the far end is Purple
+        // now, and so are we.
+        let in_flight = FrameType::from_u8(self.tx_buffer[FRAME_TYPE_IDX]);
+        if !matches!(in_flight, Some(FrameType::Reset) | Some(FrameType::Sync)) {
+            if let (Some(on_ack), true) = (&mut self.on_ack, self.large_tx.is_empty()) {
+                on_ack(self.next_tx_colour);
+            }
+            self.next_tx_colour = self.next_tx_colour.next();
+        } else {
+            self.connected = true;
+        }
+        // The far end coped with that one, so it's ready for more
+        self.peer_paused = false;
+        self.stop_awaiting_ack();
+        self.tx_buffer.truncate(0);
+        self.tx_result = Some(TxResult::Acked);
+        self.stats.frames_acked = self.stats.frames_acked.wrapping_add(1);
+        if !self.large_tx.is_empty() {
+            self.next_fragment();
+        }
+    }
+
+    /// We dropped a duplicate I-Frame. Returns true if that's too many in a
+    /// row.
+    fn duplicate_received(&mut self) -> bool {
+        self.duplicates = self.duplicates.saturating_add(1);
+        match self.max_duplicates {
+            Some(max) if self.duplicates > max => {
+                self.duplicates = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Does `csum` match the frame in the rx_buffer?
+    fn checksum_matches(&self, csum: u32, width: ChecksumWidth) -> bool {
+        let region = checksummed_region(&self.rx_buffer);
+        Checksum(csum).validate(region, self.checksum_covers, self.crc_seed, width)
+    }
+
+    /// The frame in the rx_buffer failed its checksum. NACK it, and see if
+    /// it's time to recover from a glitch. Returns true if it is.
+    fn checksum_failed(&mut self) -> bool {
+        trace!(self, "RX bad checksum");
+        self.stats.crc_errors = self.stats.crc_errors.wrapping_add(1);
+        self.sframe_pending = Some(FrameType::Nack.to_u8());
+        self.crc_failures = self.crc_failures.saturating_add(1);
+        if let (Some(limit), Some(_)) = (self.glitch_limit, self.clock) {
+            if self.crc_failures >= limit {
+                self.crc_failures = 0;
+                self.rx_resyncing = true;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// With length-prefixed framing, look for the sync word which starts
+    /// each frame. Returns true if `byte` was part of the search.
+    fn find_sync(&mut self, byte: u8) -> bool {
+        self.rx_state = match self.rx_state {
+            RxState::WantFrameDelimiter | RxState::WantCobsHeader if byte == SYNC_WORD[0] => {
+                RxState::WantSync
+            }
+            RxState::WantFrameDelimiter | RxState::WantCobsHeader => RxState::WantFrameDelimiter,
+            RxState::WantSync if byte == SYNC_WORD[1] => {
+                // As with a COBS delimiter, we hang on to the last frame
+                // until the next one starts
+                self.rx_buffer.truncate(0);
+                RxState::WantFrameType
+            }
+            RxState::WantSync if byte == SYNC_WORD[0] => RxState::WantSync,
+            RxState::WantSync => RxState::WantFrameDelimiter,
+            _ => return false,
+        };
+        true
+    }
+
+    /// A frame ended after `received` bytes of checksum. We might have been
+    /// waiting for more, in case the far end uses a wider checksum than us.
+    fn checksum_ended_early(&mut self, csum: u32, received: usize) -> Received {
+        let width = self.checksum_width;
+        if received == width.len() {
+            // We already know it didn't match
+            if self.checksum_failed() {
+                Received::GlitchRecovery
+            } else {
+                Received::Corrupt
+            }
+        } else if width == ChecksumWidth::Crc32
+            && received == ChecksumWidth::Crc16.len()
+            && self.checksum_matches(csum, ChecksumWidth::Crc16)
+        {
+            Received::ChecksumWidthMismatch
+        } else {
+            self.frame_truncated()
+        }
+    }
+
+    /// A frame delimiter arrived part way through a frame. Count it, and
+    /// NACK it if it was an I-Frame (or if we've been asked to NACK every
+    /// truncated frame), so the far end re-sends it straight away.
+    fn frame_truncated(&mut self) -> Received {
+        self.stats.framing_errors = self.stats.framing_errors.wrapping_add(1);
+        let iframe = matches!(
+            self.rx_buffer.first().and_then(|&b| FrameType::from_u8(b)),
+            Some(FrameType::IFrame(_))
+                | Some(FrameType::IFrameAck(_))
+                | Some(FrameType::Fragment(_))
+                | Some(FrameType::SeqIFrame)
+        );
+        if iframe || self.nack_truncated_frames {
+            self.sframe_pending = Some(FrameType::Nack.to_u8());
+        }
+        Received::Truncated
+    }
+
+    /// What is the TX state machine doing? Handy for working out where a
+    /// link has got stuck.
+    pub fn tx_state_debug(&self) -> TxStateInfo {
+        match self.tx_state {
+            TxState::Idle => TxStateInfo::Idle,
+            TxState::SendingDelimiterStart { payload } => TxStateInfo::SendingDelimiterStart {
+                frame: payload.into(),
+            },
+            TxState::SendingCobsHeader { payload } => TxStateInfo::SendingCobsHeader {
+                frame: payload.into(),
+            },
+            TxState::SendingPayload { payload, sent, .. } => TxStateInfo::SendingPayload {
+                frame: payload.into(),
+                sent,
+            },
+            TxState::SendingDelimiterEnd { payload } => TxStateInfo::SendingDelimiterEnd {
+                frame: payload.into(),
+            },
+            TxState::WaitingForAckNack { num_polls } => {
+                TxStateInfo::WaitingForAckNack { num_polls }
+            }
+        }
+    }
+
+    /// Are we part way through receiving a frame? If so, it's not a good
+    /// time to stop the reader (e.g. to go into a low power mode).
+    pub fn rx_in_progress(&self) -> bool {
+        !matches!(
+            self.rx_state,
+            RxState::WantFrameDelimiter | RxState::WantCobsHeader
+        )
+    }
+
+    /// Are we part way through sending a frame? If so, it's not a good time
+    /// to stop the writer. Waiting for an ACK doesn't count.
+    pub fn tx_in_progress(&self) -> bool {
+        !matches!(
+            self.tx_state,
+            TxState::Idle | TxState::WaitingForAckNack { .. }
+        )
+    }
+
+    /// What is the RX state machine doing? Handy for working out where a
+    /// link has got stuck.
+    pub fn rx_state_debug(&self) -> RxStateInfo {
+        match self.rx_state {
+            RxState::WantFrameDelimiter => RxStateInfo::WantFrameDelimiter,
+            RxState::WantCobsHeader => RxStateInfo::WantCobsHeader,
+            RxState::WantSync => RxStateInfo::WantSync,
+            RxState::WantFrameType => RxStateInfo::WantFrameType,
+            RxState::WantLength { frame } => RxStateInfo::WantLength { frame_type: frame },
+            RxState::WantPayload { frame, length } => RxStateInfo::WantPayload {
+                frame_type: frame,
+                length,
+                received: self.rx_buffer.len(),
+            },
+            RxState::WantChecksum {
+                frame, received, ..
+            } => RxStateInfo::WantChecksum {
+                frame_type: frame,
+                received,
+            },
+        }
+    }
+
+    /// The number of frames (of any type) we have received with a valid
+    /// checksum. This wraps around at `u32::MAX`.
+    pub fn frames_received(&self) -> u32 {
+        self.frames_received
+    }
+
+    /// The type of the frame we most recently received with a valid
+    /// checksum, whatever it was, or `None` if there hasn't been one (since
+    /// `resync`).
+    pub fn last_rx_frame(&self) -> Option<RxFrameKind> {
+        self.last_rx_frame.map(RxFrameKind::from)
+    }
+
+    /// What's happened on the link since we started (or since you last
+    /// called `reset_stats`).
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Set all the counters in `stats` back to zero, along with
+    /// `bytes_transmitted` and `bytes_received`.
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+        self.bytes_transmitted = 0;
+        self.bytes_received = 0;
+    }
+
+    /// How many bytes we've given the writer, including delimiters and
+    /// COBS overhead. Together with the time, this tells you how much of
+    /// the link we're using.
+    pub fn bytes_transmitted(&self) -> u64 {
+        self.bytes_transmitted
+    }
+
+    /// How many bytes we've taken from the reader (or been given by
+    /// `run_rx_slice`), whether or not they made up good frames.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Get the raw payload of the message most recently returned by
+    /// `run_rx`.
+    ///
+    /// This lets you deserialise types which borrow from the payload (e.g.
+    /// with `postcard::from_bytes`) without copying them. The slice points
+    /// into Illyria's receive buffer, so the borrow checker won't let you
+    /// call `run_rx` (or anything else which takes `&mut self`) while you
+    /// hold on to it. Once you do call `run_rx` again, the payload is gone
+    /// and this function returns `None` until the next message arrives.
+    ///
+    /// Returns `None` if the last call to `run_rx` did not return a message.
+    /// Duplicate messages, which `run_rx` drops, are never returned here.
+    pub fn received_payload(&self) -> Option<&[u8]> {
+        if self.rx_payload_ready {
+            Some(self.new_payload())
+        } else {
+            None
+        }
+    }
+
+    pub fn access_writer(&mut self) -> &mut TXO {
+        &mut self.writer
+    }
+
+    pub fn access_reader(&mut self) -> &mut RXI {
+        &mut self.reader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nb;
+    use serde::{Serialize, Deserialize};
+    use std::collections::VecDeque;
+
+    #[derive(Debug)]
+    struct TestWriter {
+        out_tx_buffer: Vec<u8>,
+    }
+
+    #[derive(Debug)]
+    struct TestReader {
+        source: VecDeque<u8>,
+    }
+
+    impl TestWriter {
+        fn new() -> TestWriter {
+            TestWriter {
+                out_tx_buffer: Vec::new(),
+            }
+        }
+
+        fn check(&self, expected: &[u8]) {
+            assert_eq!(self.out_tx_buffer, expected);
+        }
+    }
+
+    impl TestReader {
+        fn new() -> TestReader {
+            TestReader {
+                source: VecDeque::new(),
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    enum Message {
+        A,
+        B(u32),
+        C(bool),
+        D([u32; 16]),
+        E([u32; 15]),
+    }
+
+    impl embedded_hal::serial::Write<u8> for TestWriter {
+        type Error = ();
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+            self.out_tx_buffer.push(byte);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Self::Error> {
+            self.out_tx_buffer = Vec::new();
+            Ok(())
+        }
+    }
+
+    impl embedded_hal::serial::Read<u8> for TestReader {
+        type Error = ();
+
+        fn read(&mut self) -> nb::Result<u8, Self::Error> {
+            match self.source.pop_front() {
+                Some(b) => {
+                    Ok(b)
+                }
+                None => {
+                    Err(nb::Error::WouldBlock)
+                }
+            }
+        }
+    }
+
+    type MyIllyria = Illyria<Message, Message, TestWriter, TestReader, 66, 66>;
+
+    /// Pump one byte through each of the TX and RX state machines. Returns
+    /// the message received, if there was one.
+    fn step<
+        TXM,
+        RXM,
+        TXO,
+        const TXLEN: usize,
+        const RXLEN: usize,
+        CODEC,
+        const RXQ: usize,
+        const LARGE: usize,
+        LOG,
+        const WINDOW: usize,
+        ACK,
+        RAW,
+    >(
+        illyria: &mut Illyria<
+            TXM,
+            RXM,
+            TXO,
+            TestReader,
+            TXLEN,
+            RXLEN,
+            CODEC,
+            RXQ,
+            LARGE,
+            LOG,
+            WINDOW,
+            ACK,
+            RAW,
+        >,
+    ) -> Option<RXM>
+    where
+        RXM: core::fmt::Debug,
+        CODEC: PayloadCodec<TXM, RXM>,
+        TXO: embedded_hal::serial::Write<u8>,
+        TXO::Error: core::fmt::Debug,
+        LOG: Logger,
+        ACK: FnMut(Colour),
+        RAW: FnMut(&[u8], u32),
+    {
+        illyria.run_tx().unwrap();
+        match illyria.run_rx() {
+            Ok(None) => None,
+            Ok(Some(RxEvent::Message(m))) => Some(m),
+            // Already NACKed, and counted in the stats
+            Ok(Some(RxEvent::CorruptFrame)) => None,
+            Ok(Some(e)) => {
+                panic!("Got unexpected event {:?}", e);
+            }
+            Err(Error::TransportWouldBlock) => None,
+            Err(e) => {
+                panic!("Got error {:?}", e);
+            }
+        }
+    }
+
+    /// Call `step` until there's nothing left to send or receive, or until
+    /// we've called it `max` times. Returns the messages received.
+    fn run_until_idle<
+        TXM,
+        RXM,
+        TXO,
+        const TXLEN: usize,
+        const RXLEN: usize,
+        CODEC,
+        const RXQ: usize,
+        const LARGE: usize,
+        LOG,
+        const WINDOW: usize,
+        ACK,
+        RAW,
+    >(
+        illyria: &mut Illyria<
+            TXM,
+            RXM,
+            TXO,
+            TestReader,
+            TXLEN,
+            RXLEN,
+            CODEC,
+            RXQ,
+            LARGE,
+            LOG,
+            WINDOW,
+            ACK,
+            RAW,
+        >,
+        max: usize,
+    ) -> Vec<RXM>
+    where
+        RXM: core::fmt::Debug,
+        CODEC: PayloadCodec<TXM, RXM>,
+        TXO: embedded_hal::serial::Write<u8>,
+        TXO::Error: core::fmt::Debug,
+        LOG: Logger,
+        ACK: FnMut(Colour),
+        RAW: FnMut(&[u8], u32),
+    {
+        let mut messages = Vec::new();
+        for _ in 0..max {
+            messages.extend(step(illyria));
+            let idle = matches!(illyria.tx_state, TxState::Idle)
+                && illyria.tx_buffer.is_empty()
+                && illyria.sframe_pending.is_none()
+                && illyria.flow_pending.is_none()
+                && !illyria.resume_due()
+                && illyria.reader.source.is_empty();
+            if idle {
+                break;
+            }
+        }
+        messages
+    }
+
+    /// Call `run_tx` `polls` times, for tests which count polls.
+    fn poll_tx<
+        TXM,
+        RXM,
+        TXO,
+        RXI,
+        const TXLEN: usize,
+        const RXLEN: usize,
+        CODEC,
+        const RXQ: usize,
+        const LARGE: usize,
+        LOG,
+        const WINDOW: usize,
+        ACK,
+        RAW,
+    >(
+        illyria: &mut Illyria<
+            TXM,
+            RXM,
+            TXO,
+            RXI,
+            TXLEN,
+            RXLEN,
+            CODEC,
+            RXQ,
+            LARGE,
+            LOG,
+            WINDOW,
+            ACK,
+            RAW,
+        >,
+        polls: usize,
+    ) where
+        CODEC: PayloadCodec<TXM, RXM>,
+        TXO: embedded_hal::serial::Write<u8>,
+        RXI: embedded_hal::serial::Read<u8>,
+        TXO::Error: core::fmt::Debug,
+        RXI::Error: core::fmt::Debug,
+        LOG: Logger,
+        ACK: FnMut(Colour),
+        RAW: FnMut(&[u8], u32),
+    {
+        for _ in 0..polls {
+            illyria.run_tx().unwrap();
+        }
+    }
+
+    /// Call `run_tx` until the frame it starts on (or is part way through)
+    /// has been sent, and we're waiting for an ACK or idle again. If there's
+    /// nothing to send, this is one poll.
+    fn send_frame_out<
+        TXM,
+        RXM,
+        TXO,
+        RXI,
+        const TXLEN: usize,
+        const RXLEN: usize,
+        CODEC,
+        const RXQ: usize,
+        const LARGE: usize,
+        LOG,
+        const WINDOW: usize,
+        ACK,
+        RAW,
+    >(
+        illyria: &mut Illyria<
+            TXM,
+            RXM,
+            TXO,
+            RXI,
+            TXLEN,
+            RXLEN,
+            CODEC,
+            RXQ,
+            LARGE,
+            LOG,
+            WINDOW,
+            ACK,
+            RAW,
+        >,
+    ) where
+        CODEC: PayloadCodec<TXM, RXM>,
+        TXO: embedded_hal::serial::Write<u8>,
+        RXI: embedded_hal::serial::Read<u8>,
+        TXO::Error: core::fmt::Debug,
+        RXI::Error: core::fmt::Debug,
+        LOG: Logger,
+        ACK: FnMut(Colour),
+        RAW: FnMut(&[u8], u32),
+    {
+        illyria.run_tx().unwrap();
+        while illyria.tx_in_progress() {
+            illyria.run_tx().unwrap();
+        }
+    }
+
+    /// A new Illyria object, with nothing to read and nothing written yet.
+    fn new_illyria(poll_limit: u32) -> MyIllyria {
+        MyIllyria::new(TestWriter::new(), TestReader::new(), poll_limit)
+    }
+
+    /// Give `wire` to the reader, and call `run_rx` until it has all been
+    /// read. Returns the events `run_rx` handed back, as text.
+    fn collect_events<
+        TXM,
+        RXM,
+        TXO,
+        const TXLEN: usize,
+        const RXLEN: usize,
+        CODEC,
+        const RXQ: usize,
+        const LARGE: usize,
+        LOG,
+        const WINDOW: usize,
+        ACK,
+        RAW,
+    >(
+        illyria: &mut Illyria<
+            TXM,
+            RXM,
+            TXO,
+            TestReader,
+            TXLEN,
+            RXLEN,
+            CODEC,
+            RXQ,
+            LARGE,
+            LOG,
+            WINDOW,
+            ACK,
+            RAW,
+        >,
+        wire: &[u8],
+    ) -> Vec<String>
+    where
+        RXM: core::fmt::Debug,
+        CODEC: PayloadCodec<TXM, RXM>,
+        TXO: embedded_hal::serial::Write<u8>,
+        TXO::Error: core::fmt::Debug,
+        LOG: Logger,
+        ACK: FnMut(Colour),
+        RAW: FnMut(&[u8], u32),
+    {
+        illyria.access_reader().source.extend(wire);
+        let mut events = Vec::new();
+        while !illyria.access_reader().source.is_empty() {
+            match illyria.run_rx() {
+                Ok(Some(e)) => events.push(format!("{:?}", e)),
+                Ok(None) => {}
+                Err(e) => panic!("Got error {:?}", e),
+            }
+        }
+        events
+    }
+
+    /// Give `wire` to the reader, and call `run_rx` until it has all been
+    /// read, ignoring whatever `run_rx` hands back.
+    fn deliver<
+        TXM,
+        RXM,
+        TXO,
+        const TXLEN: usize,
+        const RXLEN: usize,
+        CODEC,
+        const RXQ: usize,
+        const LARGE: usize,
+        LOG,
+        const WINDOW: usize,
+        ACK,
+        RAW,
+    >(
+        illyria: &mut Illyria<
+            TXM,
+            RXM,
+            TXO,
+            TestReader,
+            TXLEN,
+            RXLEN,
+            CODEC,
+            RXQ,
+            LARGE,
+            LOG,
+            WINDOW,
+            ACK,
+            RAW,
+        >,
+        wire: &[u8],
+    ) where
+        CODEC: PayloadCodec<TXM, RXM>,
+        TXO: embedded_hal::serial::Write<u8>,
+        TXO::Error: core::fmt::Debug,
+        LOG: Logger,
+        ACK: FnMut(Colour),
+        RAW: FnMut(&[u8], u32),
+    {
+        illyria.access_reader().source.extend(wire);
+        while !illyria.access_reader().source.is_empty() {
+            let _ = illyria.run_rx();
+        }
+    }
+
+    thread_local! {
+        static TEST_TIME: core::cell::Cell<u64> = const { core::cell::Cell::new(0) };
+    }
+
+    /// A `Clock` for tests, which only moves when `set_test_time` is called.
+    /// Each test runs on its own thread, so each has its own time.
+    fn test_clock() -> u64 {
+        TEST_TIME.with(|t| t.get())
+    }
+
+    /// Set the time `test_clock` returns, in milliseconds.
+    fn set_test_time(ms: u64) {
+        TEST_TIME.with(|t| t.set(ms));
+    }
+
+    #[test]
+    fn timeout_message() {
+        let mut illyria = new_illyria(10);
+
+        illyria.send(&Message::A).unwrap();
+        poll_tx(&mut illyria, 17);
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            3,    // Gap to next zero
+            1,    // Frame type
+            1,    // Length
+            3,    // Payload 0
+            0x85, // Checksum 0
+            0xC8, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+        illyria.access_writer().out_tx_buffer.truncate(0);
+        // This should cause a retry
+        poll_tx(&mut illyria, 11);
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            3,    // Gap to next zero
+            1,    // Frame type
+            1,    // Length
+            3,    // Payload 0
+            0x85, // Checksum 0
+            0xC8, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+    }
+
+    #[test]
+    fn rx_message() {
+        let mut illyria = new_illyria(10);
+
+        illyria.access_reader().source.push_back(0); // COBS delimiter
+        illyria.access_reader().source.push_back(3); // Gap to next zero
+        illyria.access_reader().source.push_back(1); // Frame type
+        illyria.access_reader().source.push_back(1); // Length
+        illyria.access_reader().source.push_back(3); // Payload 0
+        illyria.access_reader().source.push_back(0x85); // Checksum 0
+        illyria.access_reader().source.push_back(0xC8); // Checksum 1
+        illyria.access_reader().source.push_back(0); // COBS delimiter
+
+        let messages = run_until_idle(&mut illyria, 20);
+        assert_eq!(messages, vec![Message::A]);
+
+        // Should have sent an ACK
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            2,    // Gap to next zero
+            2,    // Frame type
+            3,    // Length
+            0x3C, // Checksum 0
+            0xF7, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+    }
+
+    #[test]
+    fn rx_red_message_decoded() {
+        let mut illyria = new_illyria(10);
+
+        illyria.access_reader().source.push_back(0); // COBS delimiter
+        illyria.access_reader().source.push_back(10); // Gap to next zero
+        illyria.access_reader().source.push_back(HEADER_RED_IFRAME); // Frame type
+        illyria.access_reader().source.push_back(5); // Length
+        illyria.access_reader().source.push_back(1); // Payload 0 - Message type B
+        illyria.access_reader().source.push_back(9); // Payload 1
+        illyria.access_reader().source.push_back(8); // Payload 2
+        illyria.access_reader().source.push_back(7); // Payload 3
+        illyria.access_reader().source.push_back(6); // Payload 4
+        illyria.access_reader().source.push_back(0x78); // Checksum 0
+        illyria.access_reader().source.push_back(0x7A); // Checksum 1
+        illyria.access_reader().source.push_back(0); // COBS delimiter
+
+        let messages = run_until_idle(&mut illyria, 20);
+        assert_eq!(messages, vec![Message::B(0x06070809)]);
+
+        // Should have sent an ACK
+        illyria.access_writer().check(&[0, 2, 2, 3, 0x3C, 0xF7, 0]);
+    }
+
+    #[test]
+    fn rx_message2() {
+        let mut illyria = new_illyria(10);
+
+        illyria.access_reader().source.push_back(0); // COBS delimiter
+        illyria.access_reader().source.push_back(5); // Gap to next zero
+        illyria.access_reader().source.push_back(1); // Frame type
+        illyria.access_reader().source.push_back(5); // Length
+        illyria.access_reader().source.push_back(1); // Payload 0 - Message type B
+        illyria.access_reader().source.push_back(5); // Payload 1
+        illyria.access_reader().source.push_back(1); // Payload 2
+        illyria.access_reader().source.push_back(1); // Payload 3
+        illyria.access_reader().source.push_back(3); // Payload 4
+        illyria.access_reader().source.push_back(0x62); // Checksum 0
+        illyria.access_reader().source.push_back(0x31); // Checksum 1
+        illyria.access_reader().source.push_back(0); // COBS delimiter
+
+        let messages = run_until_idle(&mut illyria, 20);
+        assert_eq!(messages, vec![Message::B(5)]);
+
+        // Should have sent an ACK
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            2,    // Gap to next zero
+            2,    // Frame type
+            3,    // Length
+            0x3C, // Checksum 0
+            0xF7, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+    }
+
+    #[test]
+    fn rx_bad_message() {
+        let mut illyria = new_illyria(10);
+
+        illyria.access_reader().source.push_back(0); // COBS delimiter
+        illyria.access_reader().source.push_back(3); // Gap to next zero
+        illyria
+            .access_reader()
+            .source
+            .push_back(HEADER_PURPLE_IFRAME); // Frame type
+        illyria.access_reader().source.push_back(1); // Length
+        illyria.access_reader().source.push_back(3); // Payload 0
+        illyria.access_reader().source.push_back(0xFF); // Checksum 0 (bad)
+        illyria.access_reader().source.push_back(0xC8); // Checksum 1
+        illyria.access_reader().source.push_back(0); // COBS delimiter
+
+        run_until_idle(&mut illyria, 20);
+
+        // Should be a COBS-encoded NACK frame
+        illyria.access_writer().check(&[
+            0,           // COBS delimiter
+            2,           // Gap to next zero
+            HEADER_NACK, // Frame type
+            3,           // Length (zero, replaced with gap to next zero)
+            0x25,        // Checksum 0
+            0x2F,        // Checksum 1
+            0,           // COBS delimiter
+        ]);
+    }
+
+    #[test]
+    fn ack_message() {
+        let mut illyria = new_illyria(50);
+
+        illyria.send(&Message::A).unwrap();
+        run_until_idle(&mut illyria, 17);
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            3,    // Gap to next zero
+            1,    // Frame type
+            1,    // Length
+            3,    // Payload 0
+            0x85, // Checksum 0
+            0xC8, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+        // Send an ACK
+        illyria.access_reader().source.push_back(0);
+        illyria.access_reader().source.push_back(2);
+        illyria.access_reader().source.push_back(2);
+        illyria.access_reader().source.push_back(3);
+        illyria.access_reader().source.push_back(0x3C);
+        illyria.access_reader().source.push_back(0xF7);
+        illyria.access_writer().out_tx_buffer.truncate(0);
+        // This should not cause a retry because it's been acked
+        run_until_idle(&mut illyria, 50);
+        illyria.access_writer().check(&[]);
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut illyria = new_illyria(50);
+        for &expected_frame in &[
+            // purple = 01, blue = 11, red = 21
+            [0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0],
+            [0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0],
+            [0, 3, HEADER_RED_IFRAME, 1, 3, 0x86, 0xF3, 0],
+            [0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0],
+        ] {
+            illyria.send(&Message::A).unwrap();
+            run_until_idle(&mut illyria, 17);
+            illyria.access_writer().check(&expected_frame);
+
+            // Send an ACK
+            illyria.access_reader().source.push_back(0); // COBS delimiter
+            illyria.access_reader().source.push_back(2); // Gap to next zero
+            illyria.access_reader().source.push_back(HEADER_ACK); // Frame type
+            illyria.access_reader().source.push_back(3); // Length, actually zero but replaced with gap to next zero
+            illyria.access_reader().source.push_back(0x3C); // Checksum 0
+            illyria.access_reader().source.push_back(0xF7); // Checksum 1
+            illyria.access_writer().out_tx_buffer.truncate(0);
+            // This should not cause a retry because it's been acked
+            run_until_idle(&mut illyria, 50);
+            illyria.access_writer().check(&[]);
+        }
+    }
+
+    #[test]
+    fn nack_message() {
+        let mut illyria = new_illyria(50);
+
+        illyria.send(&Message::A).unwrap();
+        run_until_idle(&mut illyria, 17);
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            3,    // Gap to next zero
+            1,    // Frame type
+            1,    // Length
+            3,    // Payload 0
+            0x85, // Checksum 0
+            0xC8, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+        // Send a NACK
+        illyria.access_reader().source.push_back(0);
+        illyria.access_reader().source.push_back(2);
+        illyria.access_reader().source.push_back(3);
+        illyria.access_reader().source.push_back(3);
+        illyria.access_reader().source.push_back(0x25);
+        illyria.access_reader().source.push_back(0x2F);
+        illyria.access_writer().out_tx_buffer.truncate(0);
+        // This should cause a retry because it's been nacked
+        run_until_idle(&mut illyria, 50);
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            3,    // Gap to next zero
+            1,    // Frame type
+            1,    // Length
+            3,    // Payload 0
+            0x85, // Checksum 0
+            0xC8, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+    }
+
+    #[test]
+    fn encode_a() {
+        let mut illyria = new_illyria(100);
+
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            3,    // Gap to next zero
+            1,    // Frame type
+            1,    // Length
+            3,    // Payload 0
+            0x85, // Checksum 0
+            0xC8, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+    }
+
+    #[test]
+    fn encode_b() {
+        let mut illyria = new_illyria(100);
+
+        illyria.send(&Message::B(0x06070809)).unwrap();
+        send_frame_out(&mut illyria);
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            10,   // Gap to next zero
+            1,    // Frame type
+            5,    // Length
+            1,    // Payload 0
+            9,    // Payload 1
+            8,    // Payload 2
+            7,    // Payload 3
+            6,    // Payload 4
+            0x1B, // Checksum 0
+            0xF9, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+    }
+
+    #[test]
+    fn encode_c() {
+        let mut illyria = new_illyria(100);
+
+        illyria.send(&Message::C(true)).unwrap();
+        send_frame_out(&mut illyria);
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            7,    // Gap to next zero
+            1,    // Frame type
+            2,    // Length
+            2,    // Payload 0
+            1,    // Payload 1
+            0x77, // Checksum 0
+            0xE4, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+    }
+
+    #[test]
+    fn encode_full() {
+        let mut illyria = new_illyria(100);
+        illyria.send(&Message::E([0; 15])).unwrap();
+        send_frame_out(&mut illyria);
+        // Don't care what this looks like, just that it fits OK
+    }
+
+    #[test]
+    fn encode_too_big() {
+        let mut illyria = new_illyria(10);
+        assert!(illyria.send(&Message::D([0; 16])).is_err());
+    }
+
+    #[test]
+    fn encode_control() {
+        let mut illyria = new_illyria(10);
+
+        illyria.send_control(0x85, &[0x01, 0x00, 0x02]).unwrap();
+        for _ in 0..50 {
+            assert!(matches!(illyria.run_tx(), Ok(WaitingForAckNack::No)));
+        }
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            4,    // Gap to next zero
+            0x85, // Frame type
+            3,    // Length
+            1,    // Payload 0
+            4,    // Payload 1 (zero, replaced with gap to next zero)
+            2,    // Payload 2
+            0x3F, // Checksum 0
+            0x75, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+        // Control frames aren't retried, so we can send a message straight away
+        illyria.send(&Message::A).unwrap();
+    }
+
+    #[test]
+    fn encode_control_bad_type() {
+        let mut illyria = new_illyria(10);
+        assert!(matches!(
+            illyria.send_control(HEADER_ACK, &[]),
+            Err(Error::InvalidFrameType)
+        ));
+        assert!(matches!(
+            illyria.send_control(0x85, &[0; 63]),
+            Err(Error::MessageTooLarge)
+        ));
+    }
+
+    #[test]
+    fn rx_control() {
+        let mut illyria = new_illyria(10);
+
+        illyria.access_reader().source.push_back(0); // COBS delimiter
+        illyria.access_reader().source.push_back(7); // Gap to next zero
+        illyria.access_reader().source.push_back(0x90); // Frame type
+        illyria.access_reader().source.push_back(2); // Length
+        illyria.access_reader().source.push_back(0xAA); // Payload 0
+        illyria.access_reader().source.push_back(0xBB); // Payload 1
+        illyria.access_reader().source.push_back(0xFE); // Checksum 0
+        illyria.access_reader().source.push_back(0x7E); // Checksum 1
+        illyria.access_reader().source.push_back(0); // COBS delimiter
+
+        let mut seen = false;
+        for _ in 0..20 {
+            illyria.run_tx().unwrap();
+            match illyria.run_rx() {
+                Ok(None) => {}
+                Ok(Some(RxEvent::Control { type_byte, payload })) => {
+                    assert!(!seen);
+                    assert_eq!(type_byte, 0x90);
+                    assert_eq!(payload, &[0xAA, 0xBB]);
+                    seen = true;
+                }
+                Ok(Some(e)) => {
+                    panic!("Got unexpected event {:?}", e);
+                }
+                Err(Error::TransportWouldBlock) => {}
+                Err(e) => {
+                    panic!("Got error {:?}", e);
+                }
+            }
+        }
+        assert!(seen);
+
+        // Control frames are not acknowledged
+        illyria.access_writer().check(&[]);
+    }
+
+    #[test]
+    fn zero_poll_limit() {
+        let mut illyria = new_illyria(0);
+
+        illyria.send(&Message::A).unwrap();
+        // One call to leave Idle, then eight bytes to send the frame
+        for _ in 0..9 {
+            assert!(matches!(illyria.run_tx(), Ok(WaitingForAckNack::No)));
+        }
+        illyria.access_writer().out_tx_buffer.truncate(0);
+        // We must wait at least once before retrying
+        assert!(matches!(illyria.run_tx(), Ok(WaitingForAckNack::Yes)));
+        illyria.access_writer().check(&[]);
+        // Now we retry
+        poll_tx(&mut illyria, 9);
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            3,    // Gap to next zero
+            1,    // Frame type
+            1,    // Length
+            3,    // Payload 0
+            0x85, // Checksum 0
+            0xC8, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+    }
+
+    #[test]
+    fn rx_borrowed_payload() {
+        let mut illyria = new_illyria(10);
+
+        illyria.access_reader().source.extend(&[
+            0,
+            5,
+            HEADER_PURPLE_IFRAME,
+            5,
+            1,
+            5,
+            1,
+            1,
+            3,
+            0x62,
+            0x31,
+            0,
+        ]);
+
+        let mut seen = false;
+        for _ in 0..20 {
+            match illyria.run_rx() {
+                Ok(Some(RxEvent::Message(m))) => {
+                    assert_eq!(m, Message::B(5));
+                    seen = true;
+                    break;
+                }
+                Ok(None) => {
+                    assert!(illyria.received_payload().is_none());
+                }
+                Ok(Some(e)) => {
+                    panic!("Got unexpected event {:?}", e);
+                }
+                Err(Error::TransportWouldBlock) => {}
+                Err(e) => {
+                    panic!("Got error {:?}", e);
+                }
+            }
+        }
+        assert!(seen);
+        // The raw payload is available until we next call run_rx
+        let payload = illyria.received_payload().unwrap();
+        assert_eq!(payload, &[1, 5, 0, 0, 0]);
+        let m: Message = postcard::from_bytes(payload).unwrap();
+        assert_eq!(m, Message::B(5));
+        assert!(illyria.run_rx().is_ok());
+        assert!(illyria.received_payload().is_none());
+    }
+
+    #[test]
+    fn rx_truncated_frame() {
+        let mut illyria = new_illyria(10);
+
+        // A Message::B frame which has lost its last two payload bytes and
+        // its checksum, followed by a complete Message::A frame
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 5, 1, 5, 1, 5, 1, 0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
+
+        let mut events = Vec::new();
+        for _ in 0..20 {
+            match illyria.run_rx() {
+                Ok(Some(RxEvent::Message(m))) => events.push(Some(m)),
+                Ok(Some(RxEvent::TruncatedFrame)) => events.push(None),
+                Ok(Some(e)) => {
+                    panic!("Got unexpected event {:?}", e);
+                }
+                Ok(None) => {}
+                Err(Error::TransportWouldBlock) => {}
+                Err(e) => {
+                    panic!("Got error {:?}", e);
+                }
+            }
+        }
+        assert_eq!(events, vec![None, Some(Message::A)]);
+    }
+
+    #[test]
+    fn rx_idle_delimiters() {
+        let mut illyria = new_illyria(10);
+
+        // Repeated delimiters between frames are not truncated frames
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 0, 0, 3, 1, 1, 3, 0x85, 0xC8, 0, 0, 0]);
+
+        let mut seen = false;
+        for _ in 0..20 {
+            match illyria.run_rx() {
+                Ok(Some(RxEvent::Message(m))) => {
+                    assert!(!seen);
+                    assert_eq!(m, Message::A);
+                    seen = true;
+                }
+                Ok(Some(e)) => {
+                    panic!("Got unexpected event {:?}", e);
+                }
+                Ok(None) => {}
+                Err(Error::TransportWouldBlock) => {}
+                Err(e) => {
+                    panic!("Got error {:?}", e);
+                }
+            }
+        }
+        assert!(seen);
+    }
+
+    #[test]
+    fn poll_count() {
+        let mut illyria = new_illyria(100);
+        assert_eq!(illyria.current_poll_count(), None);
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        assert_eq!(illyria.current_poll_count(), Some(0));
+        poll_tx(&mut illyria, 5);
+        assert_eq!(illyria.current_poll_count(), Some(5));
+        illyria.access_writer().out_tx_buffer.truncate(0);
+        // Jump straight to the limit to force a retry
+        illyria.set_poll_count(100);
+        poll_tx(&mut illyria, 9);
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            3,    // Gap to next zero
+            1,    // Frame type
+            1,    // Length
+            3,    // Payload 0
+            0x85, // Checksum 0
+            0xC8, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+        assert_eq!(illyria.current_poll_count(), Some(0));
+        // Lowering the limit brings the next retry forward
+        illyria.set_poll_limit(2);
+        illyria.access_writer().out_tx_buffer.truncate(0);
+        for _ in 0..2 {
+            assert!(matches!(illyria.run_tx(), Ok(WaitingForAckNack::Yes)));
+        }
+        assert!(matches!(illyria.run_tx(), Ok(WaitingForAckNack::No)));
+        assert_eq!(illyria.current_poll_count(), None);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn encode_to_vec_matches_run_tx() {
+        let mut illyria = new_illyria(100);
+
+        illyria.send(&Message::B(0x06070809)).unwrap();
+        send_frame_out(&mut illyria);
+        let encoded = encode_to_vec(&Message::B(0x06070809), Colour::Purple).unwrap();
+        illyria.access_writer().check(&encoded);
+        assert_eq!(
+            encode_to_vec(&Message::A, Colour::Red).unwrap(),
+            vec![0, 3, HEADER_RED_IFRAME, 1, 3, 0x86, 0xF3, 0]
+        );
+        assert_eq!(
+            encode_to_vec(&Message::A, Colour::Blue).unwrap(),
+            vec![0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0]
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn encode_to_vec_long_run() {
+        // A 255 byte payload with no zeros in it needs an extra COBS block
+        let data = [0xAAu8; 253];
+        let encoded = encode_to_vec(&&data[..], Colour::Red).unwrap();
+        assert_eq!(encoded.len(), 263);
+        assert_eq!(&encoded[0..5], &[0, 0xFF, HEADER_RED_IFRAME, 0xFF, 0xFD]);
+        // 254 bytes, then a new block of six bytes
+        assert_eq!(encoded[256], 6);
+        assert_eq!(&encoded[257..], &[0xAA, 0xAA, 0xAA, 0x97, 0xB5, 0]);
+    }
+
+    #[test]
+    fn pause_tx() {
+        let mut illyria = new_illyria(5);
+
+        illyria.send(&Message::A).unwrap();
+        // Get part-way through the frame, then pause
+        poll_tx(&mut illyria, 4);
+        illyria.pause_tx();
+        poll_tx(&mut illyria, 20);
+        // The frame was finished, but not re-sent
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            3,    // Gap to next zero
+            1,    // Frame type
+            1,    // Length
+            3,    // Payload 0
+            0x85, // Checksum 0
+            0xC8, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+        illyria.access_writer().out_tx_buffer.truncate(0);
+        illyria.resume_tx();
+        poll_tx(&mut illyria, 9);
+        // The re-send happens straight away
+        illyria.access_writer().check(&[
+            0,    // COBS delimiter
+            3,    // Gap to next zero
+            1,    // Frame type
+            1,    // Length
+            3,    // Payload 0
+            0x85, // Checksum 0
+            0xC8, // Checksum 1
+            0,    // COBS delimiter
+        ]);
+    }
+
+    #[test]
+    fn count_frames_received() {
+        let mut illyria = new_illyria(10);
+        assert_eq!(illyria.frames_received(), 0);
+
+        illyria.access_reader().source.extend(&[
+            0, 3, 1, 1, 3, 0x85, 0xC8, // Good I-Frame
+            0, 3, 1, 1, 3, 0xFF, 0xC8, // Bad checksum
+            0, 2, 2, 3, 0x3C, 0xF7, // Good (unexpected) ACK
+            0, 7, 0x90, 2, 0xAA, 0xBB, 0xFE, 0x7E, // Good control frame
+            0,
+        ]);
+        loop {
+            match illyria.run_rx() {
+                Ok(_) => {}
+                Err(Error::TransportWouldBlock) => break,
+                Err(e) => {
+                    panic!("Got error {:?}", e);
+                }
+            }
+        }
+        assert_eq!(illyria.frames_received(), 3);
+    }
+
+    /// Sends and receives raw bytes, with no serialisation.
+    struct RawCodec;
+
+    impl PayloadCodec<Vec<u8>, Vec<u8>> for RawCodec {
+        fn encode(message: &Vec<u8>, buffer: &mut [u8]) -> Result<usize, CodecError> {
+            if message.len() > buffer.len() {
+                return Err(CodecError::BufferFull);
+            }
+            buffer[..message.len()].copy_from_slice(message);
+            Ok(message.len())
+        }
+
+        fn decode(buffer: &[u8]) -> Result<Vec<u8>, CodecError> {
+            Ok(buffer.to_vec())
+        }
+    }
+
+    type RawIllyria = Illyria<Vec<u8>, Vec<u8>, TestWriter, TestReader, 66, 66, RawCodec>;
+
+    #[test]
+    fn custom_codec() {
+        let mut illyria = RawIllyria::new(TestWriter::new(), TestReader::new(), 100);
+
+        illyria.send(&vec![0x31, 0x32, 0x33]).unwrap();
+        send_frame_out(&mut illyria);
+        // This is Example Frame 1 from the README
+        illyria
+            .access_writer()
+            .check(&[0, 8, 1, 3, 0x31, 0x32, 0x33, 0xBB, 0x86, 0]);
+        assert!(matches!(
+            illyria.send(&vec![0; 63]),
+            Err(Error::PacketInFlight)
+        ));
+
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 7, 1, 2, 0x34, 0x35, 0xE2, 0x31, 0]);
+        let mut seen = false;
+        for _ in 0..20 {
+            match illyria.run_rx() {
+                Ok(Some(RxEvent::Message(m))) => {
+                    assert!(!seen);
+                    assert_eq!(m, vec![0x34, 0x35]);
+                    seen = true;
+                }
+                Ok(Some(e)) => {
+                    panic!("Got unexpected event {:?}", e);
+                }
+                Ok(None) => {}
+                Err(Error::TransportWouldBlock) => {}
+                Err(e) => {
+                    panic!("Got error {:?}", e);
+                }
+            }
+        }
+        assert!(seen);
+    }
+
+    #[test]
+    fn custom_codec_too_big() {
+        let mut illyria = RawIllyria::new(TestWriter::new(), TestReader::new(), 100);
+        assert!(matches!(
+            illyria.send(&vec![0; 63]),
+            Err(Error::Codec(CodecError::BufferFull))
+        ));
+        illyria.send(&vec![0; 62]).unwrap();
+    }
+
+    #[test]
+    fn max_in_flight_time() {
+        let mut illyria = new_illyria(2);
+        illyria.set_clock(test_clock);
+        illyria.set_max_in_flight_time(Some(core::time::Duration::from_millis(100)));
+        set_test_time(1000);
+
+        illyria.send(&Message::A).unwrap();
+        // Keep on re-sending for as long as we're allowed to
+        poll_tx(&mut illyria, 100);
+        set_test_time(1100);
+        illyria.run_tx().unwrap();
+        set_test_time(1101);
+        // We might be part way through a re-send, which must finish first
+        let mut expired = false;
+        for _ in 0..20 {
+            match illyria.run_tx() {
+                Ok(_) => {}
+                Err(Error::FrameExpired) => {
+                    expired = true;
+                    break;
+                }
+                Err(e) => panic!("Got error {:?}", e),
+            }
+        }
+        assert!(expired);
+        assert_eq!(illyria.current_poll_count(), None);
+
+        // The next frame goes out Purple
+        illyria.access_writer().out_tx_buffer.clear();
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        illyria
+            .access_writer()
+            .check(&[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0]);
+    }
+
+    #[test]
+    fn send_reset() {
+        let mut illyria = new_illyria(100);
+
+        // Send a message and get it ACKed, so our colour moves on from Purple
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        deliver(&mut illyria, &expected_ack_frame(Colour::Purple));
+
+        illyria.send_reset();
+        send_frame_out(&mut illyria);
+        illyria
+            .access_writer()
+            .check(&[0, 2, HEADER_RESET, 3, 0x68, 0x27, 0]);
+        assert!(matches!(
+            illyria.send(&Message::A),
+            Err(Error::PacketInFlight)
+        ));
+
+        // ACK the RESET
+        deliver(&mut illyria, &expected_ack_frame(Colour::Purple));
+
+        // The next message goes out Purple
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        illyria
+            .access_writer()
+            .check(&[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0]);
+    }
+
+    #[test]
+    fn rx_reset() {
+        let mut illyria = new_illyria(100);
+
+        // Purple Message::A, then a Red one - which is a duplicate
+        let purple_a = [0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0];
+        let red_a = [0, 3, HEADER_RED_IFRAME, 1, 3, 0x86, 0xF3, 0];
+        let reset = [0, 2, HEADER_RESET, 3, 0x68, 0x27, 0];
+        let mut events = Vec::new();
+        for frame in &[&purple_a[..], &red_a[..], &reset[..], &red_a[..]] {
+            illyria.access_reader().source.extend(frame.iter());
+            while !illyria.access_reader().source.is_empty() {
+                match illyria.run_rx() {
+                    Ok(Some(RxEvent::Message(m))) => events.push(Some(m)),
+                    Ok(Some(RxEvent::Reset)) => events.push(None),
+                    Ok(Some(e)) => panic!("Got unexpected event {:?}", e),
+                    Ok(None) => {}
+                    Err(e) => panic!("Got error {:?}", e),
+                }
+            }
+        }
+        assert_eq!(events, vec![Some(Message::A), None, Some(Message::A)]);
+    }
+
+    #[test]
+    fn cobs_overhead() {
+        assert_eq!(cobs_max_overhead(0), 1);
+        assert_eq!(cobs_max_overhead(1), 2);
+        assert_eq!(cobs_max_overhead(254), 2);
+        assert_eq!(cobs_max_overhead(255), 3);
+        assert_eq!(cobs_max_overhead(508), 3);
+
+        let illyria = new_illyria(100);
+        assert_eq!(illyria.max_payload(), 62);
+        let frame_len = illyria.max_payload() + FRAME_OVERHEAD;
+        assert_eq!(frame_len + cobs_max_overhead(frame_len) + 2, 70);
+    }
+
+    thread_local! {
+        static ACKED: std::cell::RefCell<Vec<Colour>> = const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    fn record_ack(colour: Colour) {
+        ACKED.with(|acked| acked.borrow_mut().push(colour));
+    }
+
+    thread_local! {
+        static RAW_FRAMES: std::cell::RefCell<Vec<(Vec<u8>, u32)>> = const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    fn record_raw_frame(frame: &[u8], checksum: u32) {
+        RAW_FRAMES.with(|frames| frames.borrow_mut().push((frame.to_vec(), checksum)));
+    }
+
+    #[test]
+    fn on_raw_frame() {
+        let mut illyria = new_illyria(100);
+        illyria.set_on_raw_frame(Some(record_raw_frame));
+        deliver(
+            &mut illyria,
+            &[
+                0, 5, 0x11, 5, 1, 5, 1, 1, 3, 0xD7, 0xF8, 0, // Blue B(5)
+                0, 5, 0x11, 5, 1, 5, 1, 1, 3, 0xD7, 0xF9, 0, // ...corrupted
+                0, 5, 0x11, 5, 1, 0, // ...truncated
+            ],
+        );
+        RAW_FRAMES.with(|frames| {
+            assert_eq!(
+                *frames.borrow(),
+                vec![
+                    (vec![0x11, 5, 1, 5, 0, 0, 0], 0xD7F8),
+                    (vec![0x11, 5, 1, 5, 0, 0, 0], 0xD7F9),
+                ]
+            );
+        });
+    }
+
+    type RawClosureIllyria<'a> = Illyria<
+        Message,
+        Message,
+        TestWriter,
+        TestReader,
+        66,
+        66,
+        Postcard,
+        2,
+        0,
+        NoLogger,
+        0,
+        AckCallback,
+        &'a mut dyn FnMut(&[u8], u32),
+    >;
+
+    #[test]
+    fn on_raw_frame_closure() {
+        let mut checksums = Vec::new();
+        let mut record = |_: &[u8], checksum| checksums.push(checksum);
+        let mut illyria: RawClosureIllyria =
+            Illyria::new(TestWriter::new(), TestReader::new(), 100);
+        illyria.set_on_raw_frame(Some(&mut record));
+        deliver(&mut illyria, &[0, 5, 0x11, 5, 1, 5, 1, 1, 3, 0xD7, 0xF8, 0]);
+        drop(illyria);
+
+        assert_eq!(checksums, vec![0xD7F8]);
+    }
+
+    #[test]
+    fn on_ack() {
+        let mut illyria = new_illyria(100);
+        illyria.set_on_ack(Some(record_ack));
+
+        for _ in 0..3 {
+            illyria.send(&Message::A).unwrap();
+            send_frame_out(&mut illyria);
+            // An ACK, twice. The second one is stale and must be ignored.
+            for _ in 0..2 {
+                illyria
+                    .access_reader()
+                    .source
+                    .extend(&expected_ack_frame(Colour::Purple));
+            }
+            while !illyria.access_reader().source.is_empty() {
+                let _ = illyria.run_rx();
+            }
+        }
+
+        ACKED.with(|acked| {
+            assert_eq!(
+                *acked.borrow(),
+                vec![Colour::Purple, Colour::Blue, Colour::Red]
+            );
+        });
+    }
+
+    #[test]
+    fn on_ack_closure() {
+        let mut acked = Vec::new();
+        let mut record = |colour| acked.push(colour);
+        let mut illyria: Illyria<
+            Message,
+            Message,
+            TestWriter,
+            TestReader,
+            66,
+            66,
+            Postcard,
+            2,
+            0,
+            NoLogger,
+            0,
+            &mut dyn FnMut(Colour),
+        > = Illyria::new(TestWriter::new(), TestReader::new(), 100);
+        illyria.set_on_ack(Some(&mut record));
+
+        for _ in 0..2 {
+            illyria.send(&Message::A).unwrap();
+            send_frame_out(&mut illyria);
+            deliver(&mut illyria, &expected_ack_frame(Colour::Purple));
+        }
+        drop(illyria);
+
+        assert_eq!(acked, vec![Colour::Purple, Colour::Blue]);
+    }
+
+    #[test]
+    fn send_while_paused() {
+        let mut illyria = new_illyria(100);
+
+        illyria.pause_tx();
+        illyria.send(&Message::A).unwrap();
+        assert!(matches!(
+            illyria.send(&Message::B(5)),
+            Err(Error::PacketInFlight)
+        ));
+        poll_tx(&mut illyria, 20);
+        illyria.access_writer().check(&[]);
+
+        illyria.resume_tx();
+        poll_tx(&mut illyria, 20);
+        illyria
+            .access_writer()
+            .check(&[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0]);
+
+        // ACK it, and the next message follows on as normal
+        deliver(&mut illyria, &expected_ack_frame(Colour::Purple));
+        illyria.send(&Message::B(5)).unwrap();
+        send_frame_out(&mut illyria);
+        illyria
+            .access_writer()
+            .check(&[0, 5, HEADER_BLUE_IFRAME, 5, 1, 5, 1, 1, 3, 0xD7, 0xF8, 0]);
+    }
+
+    #[test]
+    fn tx_wrote_byte() {
+        let mut illyria = new_illyria(2);
+        illyria.run_tx().unwrap();
+        assert!(!illyria.tx_wrote_byte());
+
+        illyria.send(&Message::A).unwrap();
+        // Leaving Idle doesn't write anything
+        illyria.run_tx().unwrap();
+        assert!(!illyria.tx_wrote_byte());
+        // Then eight bytes of frame
+        for _ in 0..8 {
+            illyria.run_tx().unwrap();
+            assert!(illyria.tx_wrote_byte());
+        }
+        // Then we wait for the ACK
+        illyria.run_tx().unwrap();
+        assert!(!illyria.tx_wrote_byte());
+        assert_eq!(illyria.access_writer().out_tx_buffer.len(), 8);
+    }
+
+    #[test]
+    fn rx_full_cobs_block() {
+        let mut illyria: Illyria<Vec<u8>, Vec<u8>, TestWriter, TestReader, 66, 300, RawCodec> =
+            Illyria::new(TestWriter::new(), TestReader::new(), 100);
+
+        // The frame type, the length and 252 payload bytes make a run of
+        // exactly 254 non-zero bytes, which is then followed by a zero.
+        let mut payload = vec![0xAA; 252];
+        payload.push(0);
+        let source = &mut illyria.access_reader().source;
+        source.push_back(0); // COBS delimiter
+        source.push_back(0xFF); // A full block, with no zero at the end
+        source.push_back(HEADER_PURPLE_IFRAME);
+        source.push_back(253); // Length
+        source.extend(&payload[0..252]);
+        source.push_back(1); // An empty block, then a zero
+        source.push_back(3); // Gap to next zero
+        source.push_back(0x49); // Checksum 0
+        source.push_back(0x19); // Checksum 1
+        source.push_back(0); // COBS delimiter
+
+        let mut messages = Vec::new();
+        while !illyria.access_reader().source.is_empty() {
+            match illyria.run_rx() {
+                Ok(Some(RxEvent::Message(m))) => messages.push(m),
+                Ok(Some(e)) => panic!("Got unexpected event {:?}", e),
+                Ok(None) => {}
+                Err(e) => panic!("Got error {:?}", e),
+            }
+        }
+        assert_eq!(messages, vec![payload]);
+    }
+
+    #[test]
+    fn checksum_encoded() {
+        let covers = ChecksumCovers::Encoded;
+        let crc16 = |data: &[u8]| Checksum::generate(data, covers, 0, ChecksumWidth::Crc16).0;
+        assert_eq!(crc16(&[0x11; 300]), 0x32DB);
+        assert_eq!(crc16(&[0x11; 254]), 0x9CCD);
+        let mut data = [0x11; 11];
+        data[10] = 0;
+        assert_eq!(crc16(&data), 0x11C3);
+
+        let mut illyria = new_illyria(100);
+        illyria.set_checksum_covers(covers);
+
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        illyria
+            .access_writer()
+            .check(&[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x8B, 0x9E, 0]);
+
+        // An ACK with an encoded checksum clears the frame
+        deliver(&mut illyria, &[0, 2, HEADER_ACK, 3, 0x51, 0x4D, 0]);
+        assert_eq!(illyria.current_poll_count(), None);
+
+        // And our NACKs have an encoded checksum too
+        illyria.access_writer().out_tx_buffer.clear();
+        deliver(
+            &mut illyria,
+            &[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0],
+        );
+        poll_tx(&mut illyria, 8);
+        illyria
+            .access_writer()
+            .check(&[0, 2, HEADER_NACK, 3, 0x48, 0x95, 0]);
+    }
+
+    #[test]
+    fn rx_slice() {
+        let mut illyria: Illyria<Message, Message, TestWriter, TestReader, 66, 66, Postcard, 3> =
+            Illyria::new(TestWriter::new(), TestReader::new(), 100);
+
+        let bytes = [
+            0,
+            3,
+            HEADER_PURPLE_IFRAME,
+            1,
+            3,
+            0x85,
+            0xC8, // Message::A
+            0,
+            5,
+            HEADER_BLUE_IFRAME,
+            5,
+            1,
+            5,
+            1,
+            1,
+            3,
+            0xD7,
+            0xF8, // Message::B(5)
+            0,
+            3,
+            HEADER_RED_IFRAME,
+            1,
+            3,
+            0x86,
+            0xF3, // Message::A
+            0,
+        ];
+        // The queue fills up after the second frame
+        let used = illyria.run_rx_slice(&bytes);
+        assert_eq!(used, 18);
+
+        let mut messages = Vec::new();
+        loop {
+            match illyria.run_rx() {
+                Ok(Some(RxEvent::Message(m))) => messages.push(m),
+                Ok(Some(e)) => panic!("Got unexpected event {:?}", e),
+                Ok(None) => {}
+                Err(Error::TransportWouldBlock) => break,
+                Err(e) => panic!("Got error {:?}", e),
+            }
+            assert!(illyria.received_payload().is_none());
+        }
+        assert_eq!(messages, vec![Message::A, Message::B(5)]);
+
+        assert_eq!(illyria.run_rx_slice(&bytes[used..]), bytes.len() - used);
+        assert!(matches!(
+            illyria.run_rx(),
+            Ok(Some(RxEvent::Message(Message::A)))
+        ));
+    }
+
+    #[test]
+    fn time_since_last_rx_byte() {
+        let mut illyria = new_illyria(100);
+        illyria.set_clock(test_clock);
+        set_test_time(500);
+        assert_eq!(illyria.time_since_last_rx_byte(), None);
+        assert!(matches!(illyria.run_rx(), Err(Error::TransportWouldBlock)));
+        assert_eq!(illyria.time_since_last_rx_byte(), None);
+
+        // A corrupt byte still counts
+        illyria.access_reader().source.push_back(0x55);
+        illyria.run_rx().unwrap();
+        set_test_time(750);
+        assert!(matches!(illyria.run_rx(), Err(Error::TransportWouldBlock)));
+        assert_eq!(
+            illyria.time_since_last_rx_byte(),
+            Some(core::time::Duration::from_millis(250))
+        );
+    }
+
+    #[test]
+    fn tiny_tx_buffer() {
+        let mut illyria: Illyria<Message, Message, TestWriter, TestReader, 2, 66> =
+            Illyria::new(TestWriter::new(), TestReader::new(), 100);
+
+        assert_eq!(illyria.space(), 0);
+        assert_eq!(illyria.max_payload(), 0);
+        assert!(matches!(
+            illyria.send(&Message::A),
+            Err(Error::MessageTooLarge)
+        ));
+        assert!(matches!(
+            illyria.send_control(0x80, &[]),
+            Err(Error::MessageTooLarge)
+        ));
+        illyria.send_reset();
+        send_frame_out(&mut illyria);
+        illyria.access_writer().check(&[]);
+    }
+
+    #[test]
+    fn checksum_coverage() {
+        // Example Frame 1 from the README, with some junk on the end
+        let frame = [0x01, 0x03, 0x31, 0x32, 0x33, 0xBB, 0x86, 0xAA];
+        let region = checksummed_region(&frame);
+        assert_eq!(region, &frame[0..5]);
+        let checksum = Checksum::generate(region, ChecksumCovers::Decoded, 0, ChecksumWidth::Crc16);
+        let mut bytes = [0; 2];
+        checksum.write_to(ChecksumWidth::Crc16, &mut bytes);
+        assert_eq!(bytes, [0xBB, 0x86]);
+
+        // An empty frame is just the type and the length
+        assert_eq!(
+            checksummed_region(&[HEADER_ACK, 0, 0x3C, 0xF7]),
+            &[HEADER_ACK, 0]
+        );
+
+        let mut built = [0xAA; 8];
+        built[DATA_IDX..DATA_IDX + 3].copy_from_slice(b"123");
+        let len = build_frame(
+            &mut built,
+            0x01,
+            3,
+            ChecksumCovers::Decoded,
+            0,
+            ChecksumWidth::Crc16,
+        );
+        assert_eq!(len, 7);
+        assert_eq!(built, frame);
+    }
+
+    #[test]
+    fn ack_delay() {
+        let mut illyria = new_illyria(100);
+        illyria.set_clock(test_clock);
+        illyria.set_ack_delay(Some(core::time::Duration::from_millis(10)));
+        set_test_time(100);
+
+        // Purple Message::A
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0]);
+        assert_eq!(run_until_idle(&mut illyria, 50), vec![Message::A]);
+        illyria.access_writer().check(&[]);
+
+        // Once the delay is up, the ACK goes on its own
+        set_test_time(110);
+        run_until_idle(&mut illyria, 50);
+        illyria
+            .access_writer()
+            .check(&expected_ack_frame(Colour::Purple));
+
+        // Blue Message::A, and a response before the delay is up
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0]);
+        assert_eq!(run_until_idle(&mut illyria, 50), vec![Message::A]);
+        illyria.send(&Message::A).unwrap();
+        poll_tx(&mut illyria, 20);
+        illyria.access_writer().check(&[
+            0,
+            2,
+            HEADER_ACK,
+            3,
+            0x3C,
+            0xF7,
+            0, // The ACK goes first
+            0,
+            3,
+            HEADER_PURPLE_IFRAME,
+            1,
+            3,
+            0x85,
+            0xC8,
+            0, // Then our response
+        ]);
+    }
+
+    #[test]
+    fn ack_while_waiting() {
+        let mut illyria = new_illyria(20);
+
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        assert_eq!(illyria.current_poll_count(), Some(0));
+        illyria.access_writer().out_tx_buffer.clear();
+
+        // The far end sends us something rather than ACKing. We must ACK it
+        // even though we're waiting for an ACK ourselves, or we'd both wait
+        // forever.
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0]);
+        let mut messages = Vec::new();
+        for _ in 0..20 {
+            messages.extend(step(&mut illyria));
+        }
+        assert_eq!(messages, vec![Message::A]);
+        illyria
+            .access_writer()
+            .check(&expected_ack_frame(Colour::Purple));
+        // We went back to waiting, without re-sending
+        assert!(illyria.current_poll_count().is_some());
+
+        // And our ACK still counts
+        illyria
+            .access_reader()
+            .source
+            .extend(&expected_ack_frame(Colour::Purple));
+        run_until_idle(&mut illyria, 50);
+        assert_eq!(illyria.current_poll_count(), None);
+        illyria.send(&Message::A).unwrap();
+    }
+
+    #[test]
+    fn abort_rx_frame() {
+        let mut illyria = new_illyria(100);
+
+        // Half of a Purple Message::A
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, HEADER_PURPLE_IFRAME, 1]);
+        assert_eq!(run_until_idle(&mut illyria, 50), vec![]);
+        illyria.abort_rx_frame();
+
+        // The rest of it is ignored, and so is a Blue frame which arrives
+        // without a delimiter in front of it
+        illyria.access_reader().source.extend(&[
+            3,
+            0x85,
+            0xC8,
+            3,
+            HEADER_BLUE_IFRAME,
+            1,
+            1,
+            2,
+            0x5D,
+            0,
+        ]);
+        assert_eq!(run_until_idle(&mut illyria, 50), vec![]);
+        illyria.access_writer().check(&[]);
+
+        // The next complete frame is fine
+        illyria
+            .access_reader()
+            .source
+            .extend(&[3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0]);
+        assert_eq!(run_until_idle(&mut illyria, 50), vec![Message::A]);
+
+        // Aborting doesn't change the colour we want - this is a duplicate
+        illyria.abort_rx_frame();
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, HEADER_RED_IFRAME, 1, 3, 0x86, 0xF3, 0]);
+        assert_eq!(run_until_idle(&mut illyria, 50), vec![]);
+    }
+
+    #[test]
+    fn double_delimiters() {
+        let mut illyria = new_illyria(100);
+        illyria.set_double_delimiters(true);
+
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        illyria
+            .access_writer()
+            .check(&[0, 0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0, 0]);
+
+        // A stray zero in the middle of a frame is ignored
+        illyria.access_reader().source.extend(&[
+            0,
+            0,
+            3,
+            HEADER_PURPLE_IFRAME,
+            0,
+            1,
+            3,
+            0x85,
+            0xC8,
+            0,
+            0,
+        ]);
+        let mut messages = Vec::new();
+        while !illyria.access_reader().source.is_empty() {
+            match illyria.run_rx() {
+                Ok(Some(RxEvent::Message(m))) => messages.push(m),
+                Ok(Some(e)) => panic!("Got unexpected event {:?}", e),
+                Ok(None) => {}
+                Err(e) => panic!("Got error {:?}", e),
+            }
+        }
+        assert_eq!(messages, vec![Message::A]);
+
+        // But two in a row are a frame boundary
+        let events = collect_events(
+            &mut illyria,
+            &[
+                0,
+                0,
+                3,
+                HEADER_BLUE_IFRAME,
+                1,
+                0,
+                0, // Cut short
+                3,
+                HEADER_BLUE_IFRAME,
+                1,
+                1,
+                2,
+                0x5D,
+                0,
+                0,
+            ],
+        );
+        assert_eq!(events, vec!["TruncatedFrame", "Message(A)"]);
+    }
+
+    #[test]
+    fn state_debug() {
+        let mut illyria = new_illyria(100);
+        assert_eq!(illyria.tx_state_debug(), TxStateInfo::Idle);
+        assert_eq!(illyria.rx_state_debug(), RxStateInfo::WantFrameDelimiter);
+
+        illyria.send(&Message::B(5)).unwrap();
+        poll_tx(&mut illyria, 5);
+        assert_eq!(
+            illyria.tx_state_debug(),
+            TxStateInfo::SendingPayload {
+                frame: FrameKind::IFrame,
+                sent: 2
+            }
+        );
+        poll_tx(&mut illyria, 20);
+        assert_eq!(
+            illyria.tx_state_debug(),
+            TxStateInfo::WaitingForAckNack { num_polls: 12 }
+        );
+
+        deliver(&mut illyria, &[0, 5, HEADER_BLUE_IFRAME, 5, 1, 5]);
+        assert_eq!(
+            illyria.rx_state_debug(),
+            RxStateInfo::WantPayload {
+                frame_type: HEADER_BLUE_IFRAME,
+                length: 5,
+                received: 4
+            }
+        );
+    }
+
+    type LargeIllyria = Illyria<Vec<u8>, Vec<u8>, TestWriter, TestReader, 16, 16, RawCodec, 2, 128>;
+
+    #[test]
+    fn send_large() {
+        let new = || LargeIllyria::new(TestWriter::new(), TestReader::new(), 100);
+        let mut a = new();
+        let mut b = new();
+        let message: Vec<u8> = (1..=100).collect();
+        assert!(matches!(
+            a.send_large(&vec![0; 129]),
+            Err(Error::Codec(CodecError::BufferFull))
+        ));
+        a.send_large(&message).unwrap();
+        assert!(matches!(a.send(&vec![1]), Err(Error::PacketInFlight)));
+        let mut received = Vec::new();
+        for _ in 0..2000 {
+            let _ = a.run_tx();
+            let bytes = core::mem::take(&mut a.access_writer().out_tx_buffer);
+            b.access_reader().source.extend(bytes);
+            while let Ok(e) = b.run_rx() {
+                if let Some(RxEvent::Message(m)) = e {
+                    assert_eq!(b.received_payload(), Some(&message[..]));
+                    received.push(m);
+                }
+            }
+            let _ = b.run_tx();
+            let bytes = core::mem::take(&mut b.access_writer().out_tx_buffer);
+            a.access_reader().source.extend(bytes);
+            while a.run_rx().is_ok() {}
+        }
+        assert_eq!(received, vec![message]);
+        // Everything was ACKed, so we can send again
+        a.send_large(&vec![7, 8]).unwrap();
+    }
+
+    #[test]
+    fn crc_seed() {
+        let new = |seed| {
+            let mut illyria = new_illyria(100);
+            illyria.set_crc_seed(seed);
+            illyria
+        };
+        let mut a = new(0x1234);
+        let mut b = new(0x1234);
+        let mut c = new(0);
+        a.send(&Message::B(5)).unwrap();
+        send_frame_out(&mut a);
+        let wire = core::mem::take(&mut a.access_writer().out_tx_buffer);
+        b.access_reader().source.extend(wire.iter());
+        c.access_reader().source.extend(wire.iter());
+        assert_eq!(run_until_idle(&mut b, 20), vec![Message::B(5)]);
+        assert_eq!(run_until_idle(&mut c, 20), vec![]);
+        assert_eq!(c.frames_received(), 0);
+
+        // b's ACK carries the seeded checksum too, and a accepts it
+        let ack = core::mem::take(&mut b.access_writer().out_tx_buffer);
+        assert_ne!(ack, &[0, 2, HEADER_ACK, 3, 0x3C, 0xF7, 0][..]);
+        a.access_reader().source.extend(ack.iter());
+        run_until_idle(&mut a, 20);
+        a.send(&Message::A).unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn run_to_completion() {
+        let mut a = new_illyria(100);
+        let mut b = new_illyria(100);
+        a.send(&Message::B(5)).unwrap();
+        send_frame_out(&mut a);
+        let wire = core::mem::take(&mut a.access_writer().out_tx_buffer);
+        b.access_reader().source.extend(wire);
+
+        // b stops as soon as its queue is full
+        b.run_to_completion().unwrap();
+        assert!(!b.access_reader().source.is_empty());
+        assert!(matches!(
+            b.run_rx(),
+            Ok(Some(RxEvent::Message(Message::B(5))))
+        ));
+
+        // Now it can finish reading, and send the ACK
+        b.run_to_completion().unwrap();
+        assert!(b.access_reader().source.is_empty());
+        b.access_writer().check(&expected_ack_frame(Colour::Purple));
+
+        // a reads the ACK, and has nothing left in flight
+        let ack = core::mem::take(&mut b.access_writer().out_tx_buffer);
+        a.access_reader().source.extend(ack);
+        a.run_to_completion().unwrap();
+        assert!(matches!(a.tx_state, TxState::Idle));
+        a.send(&Message::A).unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn run_to_completion_windowed() {
+        let mut sender = WindowedIllyria::new(TestWriter::new(), TestReader::new(), 3);
+        sender.set_max_retries(Some(0));
+        sender.send(&Message::B(0)).unwrap();
+        sender.send(&Message::B(1)).unwrap();
+
+        // Both frames go out, and then we wait for the ACK which never comes
+        assert!(matches!(sender.run_to_completion(), Err(Error::LinkDead)));
+        assert_eq!(wire_frames(&sender.writer.out_tx_buffer).len(), 2);
+    }
+
+    #[test]
+    fn max_nacks() {
+        let mut illyria = new_illyria(100);
+        illyria.set_max_nacks(Some(2));
+        illyria.send(&Message::A).unwrap();
+        let mut result = Ok(WaitingForAckNack::No);
+        for nack in 1..=3 {
+            // Send (or re-send) the frame
+            poll_tx(&mut illyria, 9);
+            illyria
+                .access_writer()
+                .check(&[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0]);
+            illyria.access_writer().out_tx_buffer.clear();
+            collect_events(&mut illyria, &[0, 2, HEADER_NACK, 3, 0x25, 0x2F, 0]);
+            assert_eq!(illyria.consecutive_nacks(), nack);
+            result = illyria.run_tx();
+            if nack < 3 {
+                assert!(result.is_ok());
+            }
+        }
+        assert!(matches!(result, Err(Error::TooManyNacks)));
+        // The frame has gone, so we can send another
+        illyria.send(&Message::A).unwrap();
+        assert_eq!(illyria.consecutive_nacks(), 0);
+    }
+
+    #[test]
+    fn nack_unknown_frames() {
+        for &(nack, frame_type, wire) in &[
+            // An unknown frame type
+            (false, 0x06, &[0, 2, 6, 3, 0x5B, 0x97, 0][..]),
+            (true, 0x06, &[0, 2, 6, 3, 0x5B, 0x97, 0][..]),
+            // A stray ACK is understood, so is never NACKed
+            (true, HEADER_ACK, &[0, 2, HEADER_ACK, 3, 0x3C, 0xF7, 0][..]),
+        ] {
+            let mut illyria = new_illyria(100);
+            illyria.set_nack_unknown_frames(nack);
+            illyria.access_reader().source.extend(wire);
+            let mut unknown = None;
+            while !illyria.access_reader().source.is_empty() {
+                if let Some(RxEvent::UnknownFrame(frame)) = illyria.run_rx().unwrap() {
+                    unknown = Some(frame);
+                }
+            }
+            run_until_idle(&mut illyria, 50);
+            let sent = &illyria.access_writer().out_tx_buffer;
+            if frame_type != HEADER_ACK {
+                assert_eq!(unknown, Some(frame_type));
+            } else {
+                assert_eq!(unknown, None);
+            }
+            if nack && frame_type != HEADER_ACK {
+                // A NACK-unsupported, carrying the frame type
+                assert_eq!(sent, &[0, 6, HEADER_NACK_UNSUPPORTED, 1, 6, 0x36, 0x27, 0]);
+                assert_eq!(illyria.stats().nacks_sent, 1);
+            } else {
+                assert!(!sent.contains(&HEADER_NACK), "{:?}", sent);
+                assert!(!sent.contains(&HEADER_NACK_UNSUPPORTED), "{:?}", sent);
+            }
+        }
+    }
+
+    #[test]
+    fn expected_ack() {
+        assert_eq!(
+            expected_ack_frame(Colour::Purple),
+            [0, 2, HEADER_ACK, 3, 0x3C, 0xF7, 0]
+        );
+        // A zero byte in the checksum is encoded too
+        let mut out = [0xAA; 5];
+        assert_eq!(cobs::encode(&[HEADER_ACK, 0, 0x12, 0], &mut out), Ok(5));
+        assert_eq!(out, [2, 2, 2, 0x12, 1]);
+    }
+
+    #[test]
+    fn glitch_recovery() {
+        let mut illyria = new_illyria(100);
+        illyria.set_clock(test_clock);
+        illyria.set_glitch_recovery(Some(2), core::time::Duration::from_millis(10));
+        set_test_time(1000);
+
+        // Two bad frames in a row (Blue B(5) with a corrupt checksum)
+        let bad = [0, 5, 0x11, 5, 1, 5, 1, 1, 3, 0xD7, 0xF9, 0];
+        let good = [0, 5, 0x11, 5, 1, 5, 1, 1, 3, 0xD7, 0xF8, 0];
+        let events = collect_events(&mut illyria, &[bad, bad, good].concat());
+        // The good frame arrived straight away, so was ignored
+        assert_eq!(events, vec!["CorruptFrame", "GlitchRecovery"]);
+
+        // Once the line has been quiet, we listen again
+        set_test_time(1010);
+        let mut messages = Vec::new();
+        for &b in good.iter() {
+            illyria.access_reader().source.push_back(b);
+            if let Ok(Some(RxEvent::Message(m))) = illyria.run_rx() {
+                messages.push(m);
+            }
+        }
+        assert_eq!(messages, vec![Message::B(5)]);
+    }
+
+    #[test]
+    fn checksum_width() {
+        let new = |width| {
+            let mut illyria = new_illyria(100);
+            illyria.set_checksum_width(width);
+            illyria
+        };
+        let sent = |width| {
+            let mut illyria = new(width);
+            illyria.send(&Message::B(5)).unwrap();
+            send_frame_out(&mut illyria);
+            core::mem::take(&mut illyria.access_writer().out_tx_buffer)
+        };
+        assert_eq!(new(ChecksumWidth::Crc16).checksum_width(), 2);
+        assert_eq!(new(ChecksumWidth::Crc32).checksum_width(), 4);
+        assert_eq!(new(ChecksumWidth::Crc32).max_payload(), 60);
+        assert!(matches!(
+            new(ChecksumWidth::Crc32).send(&Message::E([0; 15])),
+            Err(Error::Codec(_))
+        ));
+
+        let crc16 = sent(ChecksumWidth::Crc16);
+        let crc32 = sent(ChecksumWidth::Crc32);
+        assert_eq!(crc16, &[0, 5, 0x01, 5, 1, 5, 1, 1, 3, 0x62, 0x31, 0][..]);
+        assert_eq!(crc32.len(), crc16.len() + 2);
+
+        for &(width, wire, expected) in &[
+            (ChecksumWidth::Crc32, &crc32, "Message(B(5))"),
+            (ChecksumWidth::Crc32, &crc16, "ChecksumWidthMismatch"),
+            (ChecksumWidth::Crc16, &crc32, "ChecksumWidthMismatch"),
+        ] {
+            let mut illyria = new(width);
+            let events = collect_events(&mut illyria, wire);
+            assert_eq!(events, vec![expected]);
+            // A mismatch isn't NACKed
+            if expected == "ChecksumWidthMismatch" {
+                assert_eq!(illyria.sframe_pending, None);
+            }
+        }
+
+        // A corrupt CRC-16 frame is still NACKed, once it ends
+        let mut corrupt = crc16.clone();
+        corrupt[10] ^= 1;
+        let mut illyria = new(ChecksumWidth::Crc16);
+        let (last, rest) = corrupt.split_last().unwrap();
+        for &b in rest.iter() {
+            illyria.access_reader().source.push_back(b);
+            assert!(matches!(illyria.run_rx(), Ok(None)));
+        }
+        illyria.access_reader().source.push_back(*last);
+        assert!(matches!(illyria.run_rx(), Ok(Some(RxEvent::CorruptFrame))));
+        assert_eq!(illyria.sframe_pending, Some(HEADER_NACK));
+    }
+
+    #[test]
+    fn send_broadcast() {
+        let mut tx = new_illyria(100);
+        let mut rx = new_illyria(100);
+        let red = [0, 3, HEADER_RED_BROADCAST, 1, 3, 0x40, 0x31, 0];
+        tx.send_broadcast(&Message::A, 3).unwrap();
+        assert!(matches!(tx.send(&Message::A), Err(Error::PacketInFlight)));
+        send_frame_out(&mut tx);
+        // Three copies, and then nothing is in flight
+        let wire = core::mem::take(&mut tx.access_writer().out_tx_buffer);
+        assert_eq!(wire, [red, red, red].concat());
+        tx.send_broadcast(&Message::B(5), 0).unwrap();
+        poll_tx(&mut tx, 40);
+        let blue = core::mem::take(&mut tx.access_writer().out_tx_buffer);
+        assert_eq!(blue[2], HEADER_BLUE_BROADCAST);
+        assert_eq!(blue.len(), 12);
+
+        rx.access_reader()
+            .source
+            .extend(wire.iter().chain(blue.iter()));
+        assert_eq!(
+            run_until_idle(&mut rx, 100),
+            vec![Message::A, Message::B(5)]
+        );
+        // Nothing was ACKed
+        rx.access_writer().check(&[]);
+    }
+
+    #[test]
+    fn corrupt_header_is_not_an_ack() {
+        let mut illyria = new_illyria(100);
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        assert!(illyria.current_poll_count().is_some());
+
+        // A Blue I-Frame, with its header corrupted to look like an ACK
+        let mut frame = [0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0];
+        frame[2] = HEADER_ACK;
+        let events = collect_events(&mut illyria, &frame);
+        assert_eq!(events, vec!["CorruptFrame"]);
+        // The checksum caught it, so our frame is still in flight
+        assert_eq!(illyria.frames_received(), 0);
+        assert_eq!(illyria.sframe_pending, Some(HEADER_NACK));
+        assert!(matches!(
+            illyria.send(&Message::A),
+            Err(Error::PacketInFlight)
+        ));
+    }
+
+    #[derive(Default)]
+    struct TestLogger {
+        lines: std::cell::RefCell<Vec<String>>,
+    }
+
+    impl Logger for TestLogger {
+        fn trace(&self, args: core::fmt::Arguments) {
+            self.lines.borrow_mut().push(format!("{}", args));
+        }
+    }
+
+    #[test]
+    fn logger() {
+        let mut illyria: Illyria<
+            Message,
+            Message,
+            TestWriter,
+            TestReader,
+            66,
+            66,
+            Postcard,
+            2,
+            0,
+            TestLogger,
+        > = Illyria::new(TestWriter::new(), TestReader::new(), 100);
+        illyria.set_logger(Some(TestLogger::default()));
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        // A Blue Message::A, with a bad checksum
+        for &b in &[0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5E, 0] {
+            illyria.access_reader().source.push_back(b);
+            illyria.run_rx().unwrap();
+        }
+        let lines = illyria.logger.as_ref().unwrap().lines.borrow().clone();
+        assert_eq!(
+            lines,
+            vec![
+                "TX SendingDelimiterStart { payload: IFrame }",
+                "TX SendingCobsHeader { payload: IFrame }",
+                "TX SendingPayload { payload: IFrame, sent: 0, left: 2, full: false }",
+                "TX SendingDelimiterEnd { payload: IFrame }",
+                "TX WaitingForAckNack { num_polls: 0 }",
+                "RX WantCobsHeader",
+                "RX WantFrameType",
+                "RX WantLength { frame: 17 }",
+                "RX WantPayload { frame: 17, length: 1 }",
+                "RX WantChecksum { frame: 17, csum: 0, received: 0 }",
+                "RX bad checksum",
+                "RX WantCobsHeader",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_suppressed() {
+        let mut illyria = new_illyria(50);
+        let blue = [0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0];
+        let red = [0, 3, HEADER_RED_IFRAME, 1, 3, 0x86, 0xF3, 0];
+        // Each frame arrives twice, as if our ACK was lost and the far end
+        // re-sent it. The colour goes Blue, Red, Blue.
+        for (frame, expected) in &[
+            (&blue, vec![Message::A]),
+            (&blue, vec![]),
+            (&red, vec![Message::A]),
+            (&red, vec![]),
+            (&blue, vec![Message::A]),
+            (&blue, vec![]),
+        ] {
+            illyria.access_reader().source.extend(frame.iter());
+            assert_eq!(&run_until_idle(&mut illyria, 50), expected);
+            // Every copy is ACKed, so the far end stops re-sending it
+            illyria
+                .access_writer()
+                .check(&[0, 2, HEADER_ACK, 3, 0x3C, 0xF7, 0]);
+            illyria.access_writer().out_tx_buffer.truncate(0);
+        }
+    }
+
+    #[test]
+    fn coloured_acks() {
+        let mut illyria = new_illyria(100);
+        illyria.set_coloured_acks(true);
+        assert_eq!(
+            expected_ack_frame(Colour::Blue),
+            [0, 2, HEADER_BLUE_ACK, 3, 0xA9, 0x66, 0]
+        );
+        assert_eq!(
+            expected_ack_frame(Colour::Red),
+            [0, 2, HEADER_RED_ACK, 3, 0x1F, 0xC4, 0]
+        );
+
+        // Purple A goes out, and is ACKed twice
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        deliver(&mut illyria, &expected_ack_frame(Colour::Purple));
+
+        // Blue A goes out. The duplicate Purple ACK doesn't clear it, and
+        // nor does a Red one.
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        deliver(&mut illyria, &expected_ack_frame(Colour::Purple));
+        deliver(&mut illyria, &expected_ack_frame(Colour::Red));
+        assert!(matches!(
+            illyria.send(&Message::A),
+            Err(Error::PacketInFlight)
+        ));
+        assert_eq!(illyria.next_tx_colour, Colour::Blue);
+
+        // The Blue ACK does
+        deliver(&mut illyria, &expected_ack_frame(Colour::Blue));
+        assert_eq!(illyria.next_tx_colour, Colour::Red);
+        illyria.access_writer().out_tx_buffer.clear();
+        illyria.send(&Message::A).unwrap();
+
+        // We ACK a Blue I-Frame with a Blue ACK
+        let mut illyria = new_illyria(100);
+        illyria.set_coloured_acks(true);
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0]);
+        assert_eq!(run_until_idle(&mut illyria, 50), vec![Message::A]);
+        illyria
+            .access_writer()
+            .check(&expected_ack_frame(Colour::Blue));
+    }
+
+    #[test]
+    fn rx_into() {
+        let mut illyria = new_illyria(50);
+        illyria.access_reader().source.extend(&[
+            0,
+            5,
+            HEADER_PURPLE_IFRAME,
+            5,
+            1,
+            5,
+            1,
+            1,
+            3,
+            0x62,
+            0x31,
+            0,
+        ]);
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0]);
+        let mut message = Message::C(true);
+        let mut received = Vec::new();
+        while !illyria.access_reader().source.is_empty() {
+            if let Ok(Some(RxEvent::Message(()))) = illyria.run_rx_into(&mut message) {
+                assert!(illyria.received_payload().is_some());
+                received.push(format!("{:?}", message));
+            }
+        }
+        assert_eq!(received, vec!["B(5)", "A"]);
+
+        // Arrays support in-place decoding
+        let mut array = [9u8; 3];
+        <Postcard as PayloadCodec<(), [u8; 3]>>::decode_into(&[1, 2, 3], &mut array).unwrap();
+        assert_eq!(array, [1, 2, 3]);
+    }
+
+    #[test]
+    fn link_stalled() {
+        let mut illyria = new_illyria(50);
+        illyria.set_max_duplicates(Some(2));
+        let blue = [0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0];
+        let mut events = Vec::new();
+        for _ in 0..4 {
+            illyria.access_reader().source.extend(blue.iter());
+            while !illyria.access_reader().source.is_empty() {
+                match illyria.run_rx() {
+                    Ok(Some(RxEvent::Message(m))) => events.push(format!("{:?}", m)),
+                    Ok(Some(RxEvent::LinkStalled)) => events.push("stalled".into()),
+                    _ => {}
+                }
+            }
+        }
+        // The first is new, and the third duplicate is one too many
+        assert_eq!(events, vec!["A", "stalled"]);
+        // The duplicate is still ACKed
+        assert_eq!(illyria.sframe_pending.map(is_ack), Some(true));
+    }
+
+    #[test]
+    fn forward_frame() {
+        let mut illyria = new_illyria(50);
+        assert!(matches!(
+            illyria.forward_frame(&[3, 1, 0, 3]),
+            Err(Error::InvalidFrameType)
+        ));
+        assert!(matches!(
+            illyria.forward_frame(&[0, 0]),
+            Err(Error::InvalidFrameType)
+        ));
+        // A Blue A from somewhere else, sent as-is
+        illyria
+            .forward_frame(&[0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0])
+            .unwrap();
+        assert!(matches!(
+            illyria.send(&Message::A),
+            Err(Error::PacketInFlight)
+        ));
+        while illyria.tx_state_debug() == TxStateInfo::Idle {
+            illyria.run_tx().unwrap();
+        }
+        while illyria.tx_state_debug() != TxStateInfo::Idle {
+            illyria.run_tx().unwrap();
+        }
+        illyria
+            .access_writer()
+            .check(&[0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0]);
+        // Our own ARQ state hasn't changed
+        assert_eq!(illyria.next_tx_colour, Colour::Purple);
+        illyria.access_writer().out_tx_buffer.clear();
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        illyria
+            .access_writer()
+            .check(&[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0]);
+    }
+
+    #[test]
+    fn forward_frame_not_piggybacked() {
+        let mut illyria = new_illyria(50);
+        illyria.set_piggyback_acks(true);
+        // We owe an ACK...
+        deliver(
+            &mut illyria,
+            &[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0],
+        );
+        assert_eq!(illyria.sframe_pending, Some(HEADER_ACK));
+        // ...and are forwarding a frame whose COBS header looks like a Blue
+        // I-Frame. It must go out untouched, after an ACK of its own.
+        let mut forwarded = vec![0x11];
+        forwarded.extend(&[0x55; 16]);
+        illyria.forward_frame(&forwarded).unwrap();
+        run_until_idle(&mut illyria, 50);
+        let mut expected = expected_ack_frame(Colour::Purple).to_vec();
+        expected.push(0);
+        expected.extend(&forwarded);
+        expected.push(0);
+        illyria.access_writer().check(&expected);
+    }
+
+    #[test]
+    fn idle_keepalive() {
+        let mut illyria = new_illyria(50);
+        illyria.set_clock(test_clock);
+        illyria.set_idle_keepalive(Some(core::time::Duration::from_millis(10)));
+        set_test_time(100);
+        poll_tx(&mut illyria, 5);
+        illyria.access_writer().check(&[0]);
+        illyria.access_writer().out_tx_buffer.clear();
+
+        // Not due again yet
+        set_test_time(105);
+        illyria.run_tx().unwrap();
+        illyria.access_writer().check(&[]);
+
+        // Nothing extra while sending, or waiting for the ACK
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        set_test_time(200);
+        poll_tx(&mut illyria, 5);
+        illyria
+            .access_writer()
+            .check(&[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0]);
+        illyria.access_writer().out_tx_buffer.clear();
+
+        // Idle again once it's ACKed
+        deliver(&mut illyria, &expected_ack_frame(Colour::Purple));
+        poll_tx(&mut illyria, 5);
+        illyria.access_writer().check(&[0]);
+    }
+
+    #[test]
+    fn rx_slice_budget() {
+        let mut illyria = new_illyria(50);
+        illyria.set_rx_slice_budget(Some(3));
+        let mut bytes: &[u8] = &[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0];
+        let mut used = Vec::new();
+        while !bytes.is_empty() {
+            let n = illyria.run_rx_slice(bytes);
+            bytes = &bytes[n..];
+            used.push(n);
+            if n == 0 {
+                // The queue is full
+                break;
+            }
+        }
+        // The message completes with the last checksum byte
+        assert_eq!(used, vec![3, 3, 1, 0]);
+        assert!(matches!(
+            illyria.run_rx(),
+            Ok(Some(RxEvent::Message(Message::A)))
+        ));
+        assert_eq!(illyria.run_rx_slice(bytes), 1);
+    }
+
+    #[test]
+    fn frame_type_round_trip() {
+        let mut known = 0;
+        for header in 0..=255u8 {
+            if let Some(frame_type) = FrameType::from_u8(header) {
+                assert_eq!(frame_type.to_u8(), header);
+                known += 1;
+            }
+        }
+        // Twenty-three protocol frame types, plus the control frames
+        assert_eq!(known, 23 + 128);
+        assert_eq!(FrameType::from_u8(0x06), None);
+        assert_eq!(
+            FrameType::Broadcast(Colour::Purple).to_u8(),
+            HEADER_BLUE_BROADCAST
+        );
+    }
+
+    #[test]
+    fn respond() {
+        let mut illyria = new_illyria(50);
+        // Half a request
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, HEADER_PURPLE_IFRAME, 1]);
+        assert!(!illyria.respond(|_| panic!("No request yet")).unwrap());
+        illyria.access_reader().source.extend(&[3, 0x85, 0xC8, 0]);
+        assert!(illyria
+            .respond(|request| {
+                assert_eq!(request, Message::A);
+                Message::B(5)
+            })
+            .unwrap());
+        poll_tx(&mut illyria, 30);
+        illyria.access_writer().check(&[
+            0,
+            2,
+            HEADER_ACK,
+            3,
+            0x3C,
+            0xF7,
+            0, // The ACK for the request
+            0,
+            5,
+            HEADER_PURPLE_IFRAME,
+            5,
+            1,
+            5,
+            1,
+            1,
+            3,
+            0x62,
+            0x31,
+            0, // The response
+        ]);
+        // Our response hasn't been ACKed yet
+        assert!(matches!(
+            illyria.respond(|_| Message::A),
+            Err(Error::PacketInFlight)
+        ));
+    }
+
+    #[test]
+    fn payload_once_per_frame() {
+        let mut illyria = new_illyria(50);
+        let blue = [0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0];
+        illyria.access_reader().source.extend(blue.iter());
+        illyria.access_reader().source.extend(blue.iter());
+        let mut payloads = Vec::new();
+        while !illyria.access_reader().source.is_empty() {
+            let message = matches!(illyria.run_rx(), Ok(Some(RxEvent::Message(_))));
+            let payload = illyria.received_payload().map(|p| p.to_vec());
+            // A payload goes with a message, and nothing else
+            assert_eq!(message, payload.is_some());
+            payloads.extend(payload);
+        }
+        // The duplicate was ACKed, but its payload is never offered
+        assert_eq!(payloads, vec![vec![0]]);
+        assert_eq!(illyria.sframe_pending, Some(HEADER_ACK));
+    }
+
+    #[test]
+    fn link_dead() {
+        let mut illyria = new_illyria(2);
+        illyria.set_max_retries(Some(2));
+        assert_eq!(illyria.link_state(), LinkState::Idle);
+        illyria.send(&Message::A).unwrap();
+        assert_eq!(illyria.link_state(), LinkState::InFlight);
+        let mut states = Vec::new();
+        let result = loop {
+            match illyria.run_tx() {
+                Ok(_) => {
+                    if states.last() != Some(&illyria.link_state()) {
+                        states.push(illyria.link_state());
+                    }
+                }
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(result, Error::LinkDead));
+        assert_eq!(
+            states,
+            vec![
+                LinkState::InFlight,
+                LinkState::Retrying(1),
+                LinkState::Retrying(2)
+            ]
+        );
+        assert_eq!(illyria.link_state(), LinkState::Dead);
+        // Sent once, and re-sent twice
+        let frame = [0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0];
+        assert_eq!(illyria.access_writer().out_tx_buffer, frame.repeat(3));
+
+        // We stay dead, and quiet, until we have something new to send
+        illyria.access_writer().out_tx_buffer.clear();
+        poll_tx(&mut illyria, 10);
+        illyria.access_writer().check(&[]);
+        assert_eq!(illyria.link_state(), LinkState::Dead);
+        illyria.send(&Message::A).unwrap();
+        assert_eq!(illyria.link_state(), LinkState::InFlight);
+    }
+
+    #[test]
+    fn tx_result() {
+        let mut illyria = new_illyria(2);
+        // An ACK out of the blue
+        deliver(&mut illyria, &expected_ack_frame(Colour::Purple));
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Ignored));
+        assert_eq!(illyria.take_tx_result(), None);
+
+        illyria.send(&Message::A).unwrap();
+        let result = loop {
+            illyria.run_tx().unwrap();
+            if let Some(result) = illyria.take_tx_result() {
+                break result;
+            }
+        };
+        assert_eq!(result, TxResult::TimedOut);
+        // It's being sent again. Let it finish.
+        while !matches!(
+            illyria.tx_state_debug(),
+            TxStateInfo::WaitingForAckNack { .. }
+        ) {
+            illyria.run_tx().unwrap();
+        }
+        deliver(&mut illyria, &[0, 2, HEADER_NACK, 3, 0x25, 0x2F, 0]);
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Nacked));
+        while !matches!(
+            illyria.tx_state_debug(),
+            TxStateInfo::WaitingForAckNack { .. }
+        ) {
+            illyria.run_tx().unwrap();
+        }
+        deliver(&mut illyria, &expected_ack_frame(Colour::Purple));
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Acked));
+    }
+
+    #[test]
+    fn elapsed_timeout() {
+        set_test_time(100);
+        let mut illyria: MyIllyria = Illyria::new_with_clock(
+            TestWriter::new(),
+            TestReader::new(),
+            test_clock,
+            core::time::Duration::from_millis(10),
+        );
+        illyria.send(&Message::A).unwrap();
+        let frame = [0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0];
+        // However often we poll, nothing is re-sent until the time is up
+        poll_tx(&mut illyria, 200);
+        set_test_time(109);
+        poll_tx(&mut illyria, 200);
+        illyria.access_writer().check(&frame);
+        set_test_time(110);
+        poll_tx(&mut illyria, 20);
+        assert_eq!(illyria.access_writer().out_tx_buffer, frame.repeat(2));
+    }
+
+    type LongIllyria = Illyria<Vec<u8>, Vec<u8>, TestWriter, TestReader, 264, 264, RawCodec>;
+
+    #[test]
+    fn long_cobs_runs() {
+        let mut rx = LongIllyria::new(TestWriter::new(), TestReader::new(), 50);
+        // The payload can't be more than 255 bytes, but with the header and
+        // length that's still a run of more than 254 non-zero bytes. The
+        // second has a zero straight after a full block.
+        let mut zero_after_full = vec![0x55; 252];
+        zero_after_full.extend(&[0, 0x55, 0x55]);
+        for message in &[vec![0x55; 255], zero_after_full] {
+            let mut tx = LongIllyria::new(TestWriter::new(), TestReader::new(), 50);
+            tx.send(message).unwrap();
+            while !matches!(tx.tx_state_debug(), TxStateInfo::WaitingForAckNack { .. }) {
+                tx.run_tx().unwrap();
+            }
+            let wire = tx.access_writer().out_tx_buffer.clone();
+
+            // Same as a standard COBS encoder
+            let mut encoded = vec![0; 300];
+            let len = cobs::encode(&tx.tx_buffer, &mut encoded).unwrap();
+            assert_eq!(&wire[1..wire.len() - 1], &encoded[..len]);
+
+            rx.access_reader().source.extend(wire);
+            let mut received = None;
+            while !rx.access_reader().source.is_empty() {
+                if let Ok(Some(RxEvent::Message(m))) = rx.run_rx() {
+                    received = Some(m);
+                }
+            }
+            assert_eq!(received.as_ref(), Some(message));
+        }
+    }
+
+    #[test]
+    fn long_payload_limit() {
+        // There's room for 260 bytes, but the length byte only goes to 255
+        let mut illyria = LongIllyria::new(TestWriter::new(), TestReader::new(), 50);
+        assert_eq!(illyria.space(), 260);
+        assert_eq!(illyria.max_payload(), 255);
+        assert!(matches!(
+            illyria.send_bytes(&[0x55; 256]),
+            Err(Error::MessageTooLarge)
+        ));
+        assert!(matches!(
+            illyria.send(&vec![0x55; 256]),
+            Err(Error::Codec(CodecError::BufferFull))
+        ));
+        assert!(matches!(
+            illyria.send_control(CONTROL_FRAME_FIRST, &[0x55; 256]),
+            Err(Error::MessageTooLarge)
+        ));
+        // Nothing went out
+        poll_tx(&mut illyria, 10);
+        illyria.access_writer().check(&[]);
+        illyria.send_bytes(&[0x55; 255]).unwrap();
+    }
+
+    #[test]
+    fn cobs_codec() {
+        let vectors: &[(&[u8], &[u8])] = &[
+            (&[], &[1]),
+            (&[0], &[1, 1]),
+            (&[0, 0], &[1, 1, 1]),
+            (&[0x11, 0x22, 0, 0x33], &[3, 0x11, 0x22, 2, 0x33]),
+            (&[0x11, 0x22, 0x33, 0x44], &[5, 0x11, 0x22, 0x33, 0x44]),
+            (&[0x11, 0, 0, 0], &[2, 0x11, 1, 1, 1]),
+        ];
+        for &(decoded, encoded) in vectors {
+            let mut out = [0xAA; 8];
+            let len = cobs::encode(decoded, &mut out).unwrap();
+            assert_eq!(&out[..len], encoded);
+            let len = cobs::decode(encoded, &mut out).unwrap();
+            assert_eq!(&out[..len], decoded);
+        }
+
+        // Lots of lengths, with zeros in various places
+        let mut seed = 1u32;
+        for len in 0..600 {
+            let data: Vec<u8> = (0..len)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    let b = (seed >> 16) as u8;
+                    if len % 3 == 0 && b < 0xF0 {
+                        b | 1
+                    } else {
+                        b
+                    }
+                })
+                .collect();
+            let mut encoded = vec![0; len + cobs_max_overhead(len)];
+            let encoded_len = cobs::encode(&data, &mut encoded).unwrap();
+            assert!(!encoded[..encoded_len].contains(&0));
+            let mut decoded = vec![0; encoded_len];
+            let decoded_len = cobs::decode(&encoded[..encoded_len], &mut decoded).unwrap();
+            assert_eq!(&decoded[..decoded_len], &data[..]);
+        }
+
+        let mut out = [0; 3];
+        assert_eq!(
+            cobs::encode(&[1, 2, 3], &mut out),
+            Err(cobs::CobsError::OutputTooSmall)
+        );
+        assert_eq!(
+            cobs::decode(&[3, 1, 0], &mut out),
+            Err(cobs::CobsError::Invalid)
+        );
+        assert_eq!(
+            cobs::decode(&[3, 1], &mut out),
+            Err(cobs::CobsError::Invalid)
+        );
+        assert_eq!(
+            cobs::decode(&[2, 1, 2, 3], &mut out[..2]),
+            Err(cobs::CobsError::OutputTooSmall)
+        );
+    }
+
+    #[test]
+    fn send_bytes() {
+        let mut illyria = new_illyria(50);
+        let too_big = vec![1; illyria.space() + 1];
+        assert!(matches!(
+            illyria.send_bytes(&too_big),
+            Err(Error::MessageTooLarge)
+        ));
+        // The postcard encoding of Message::B(5), so it looks just like `send`
+        illyria.send_bytes(&[1, 5, 0, 0, 0]).unwrap();
+        assert!(matches!(
+            illyria.send_bytes(&[0]),
+            Err(Error::PacketInFlight)
+        ));
+        poll_tx(&mut illyria, 20);
+        illyria.access_writer().check(&[
+            0,
+            5,
+            HEADER_PURPLE_IFRAME,
+            5,
+            1,
+            5,
+            1,
+            1,
+            3,
+            0x62,
+            0x31,
+            0,
+        ]);
+    }
+
+    #[test]
+    fn nack_resends_immediately() {
+        let mut illyria = new_illyria(100);
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        illyria.access_writer().out_tx_buffer.clear();
+        collect_events(&mut illyria, &[0, 2, HEADER_NACK, 3, 0x25, 0x2F, 0]);
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Nacked));
+        // The very next poll puts the re-send on the wire
+        for _ in 0..8 {
+            illyria.run_tx().unwrap();
+            assert!(illyria.tx_wrote_byte());
+        }
+        illyria
+            .access_writer()
+            .check(&[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0]);
+        assert!(matches!(illyria.run_tx(), Ok(WaitingForAckNack::Yes)));
+    }
+
+    #[test]
+    fn retransmits_counted_when_sent() {
+        const NACK: &[u8] = &[0, 2, HEADER_NACK, 3, 0x25, 0x2F, 0];
+        let mut illyria = new_illyria(100);
+        illyria.set_max_nacks(Some(1));
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        illyria.access_writer().out_tx_buffer.clear();
+
+        // We owe an ACK, so the re-send waits for it, but only counts once
+        let events = collect_events(
+            &mut illyria,
+            &[&[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0][..], NACK].concat(),
+        );
+        assert_eq!(events, vec!["Message(A)"]);
+        assert_eq!(illyria.stats().retransmits, 0);
+        poll_tx(&mut illyria, 20);
+        assert_eq!(illyria.stats().retransmits, 1);
+        illyria.access_writer().check(&[
+            0,
+            2,
+            HEADER_ACK,
+            3,
+            0x3C,
+            0xF7,
+            0,
+            0,
+            3,
+            HEADER_PURPLE_IFRAME,
+            1,
+            3,
+            0x85,
+            0xC8,
+            0,
+        ]);
+
+        // One NACK too many, so the frame is dropped rather than re-sent
+        collect_events(&mut illyria, NACK);
+        assert!(matches!(illyria.run_tx(), Err(Error::TooManyNacks)));
+        poll_tx(&mut illyria, 20);
+        assert_eq!(illyria.stats().retransmits, 1);
+        assert_eq!(illyria.stats().nacks_received, 2);
+    }
+
+    #[test]
+    fn stats() {
+        let mut illyria = new_illyria(5);
+        assert_eq!(*illyria.stats(), Stats::default());
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        assert_eq!(illyria.stats().frames_sent, 1);
+        // NACKed, so re-sent
+        collect_events(&mut illyria, &[0, 2, HEADER_NACK, 3, 0x25, 0x2F, 0]);
+        poll_tx(&mut illyria, 8);
+        // Not answered, so re-sent again
+        poll_tx(&mut illyria, 14);
+        collect_events(&mut illyria, &[0, 2, HEADER_ACK, 3, 0x3C, 0xF7, 0]);
+        // A stale ACK
+        collect_events(&mut illyria, &[0, 2, HEADER_ACK, 3, 0x3C, 0xF7, 0]);
+        // A bad checksum, which we NACK
+        collect_events(&mut illyria, &[0, 3, 1, 1, 3, 0xFF, 0xC8, 0]);
+        poll_tx(&mut illyria, 7);
+        // Two messages, and then a copy of the second
+        collect_events(&mut illyria, &[0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
+        collect_events(&mut illyria, &[0, 3, 0x11, 1, 1, 2, 0x5D, 0]);
+        collect_events(&mut illyria, &[0, 3, 0x11, 1, 1, 2, 0x5D, 0]);
+        assert_eq!(
+            *illyria.stats(),
+            Stats {
+                frames_sent: 3,
+                frames_acked: 1,
+                retransmits: 2,
+                crc_errors: 1,
+                duplicates_dropped: 1,
+                colour_mismatches: 1,
+                nacks_sent: 1,
+                nacks_received: 1,
+                acks_received: 2,
+                framing_errors: 0,
+            }
+        );
+        illyria.reset_stats();
+        assert_eq!(*illyria.stats(), Stats::default());
+    }
+
+    #[test]
+    fn last_rx_frame() {
+        let mut illyria = new_illyria(10);
+        assert_eq!(illyria.last_rx_frame(), None);
+        for &(wire, kind) in &[
+            (
+                &[0, 3, 1, 1, 3, 0x85, 0xC8, 0][..],
+                RxFrameKind::Known(FrameType::IFrame(Colour::Purple)),
+            ),
+            (
+                &[0, 2, 2, 3, 0x3C, 0xF7, 0][..],
+                RxFrameKind::Known(FrameType::Ack(Colour::Purple)),
+            ),
+            // A bad checksum doesn't count
+            (
+                &[0, 2, 3, 3, 0x25, 0xFF, 0][..],
+                RxFrameKind::Known(FrameType::Ack(Colour::Purple)),
+            ),
+            (
+                &[0, 2, 3, 3, 0x25, 0x2F, 0][..],
+                RxFrameKind::Known(FrameType::Nack),
+            ),
+            (&[0, 2, 6, 3, 0x5B, 0x97, 0][..], RxFrameKind::Unknown(6)),
+        ] {
+            collect_events(&mut illyria, wire);
+            assert_eq!(illyria.last_rx_frame(), Some(kind));
+        }
+    }
+
+    #[test]
+    fn checksum_algorithms() {
+        // The standard check values, for "123456789"
+        for &(width, check) in &[
+            (ChecksumWidth::Crc16, 0x906E),
+            (ChecksumWidth::Crc16CcittFalse, 0x29B1),
+            (ChecksumWidth::Crc32, 0xCBF4_3926),
+        ] {
+            let crc = Checksum::generate(b"123456789", ChecksumCovers::Decoded, 0, width);
+            assert_eq!(crc.0, check, "{:?}", width);
+            // Can be done a piece at a time
+            let crc = width.update(width.update(0, b"1234"), b"56789");
+            assert_eq!(width.finish(crc), check);
+        }
+        assert_eq!(Crc16X25::compute(b"123456789"), 0x906E);
+        assert_eq!(Crc16CcittFalse::compute(b"123456789"), 0x29B1);
+        assert_eq!(Crc32Ieee::compute(b"123456789"), 0xCBF4_3926);
+
+        let new = || {
+            let mut illyria = new_illyria(100);
+            illyria.set_checksum_width(ChecksumWidth::Crc16CcittFalse);
+            illyria
+        };
+        let mut tx = new();
+        let mut rx = new();
+        assert_eq!(tx.checksum_width(), 2);
+        tx.send(&Message::B(5)).unwrap();
+        send_frame_out(&mut tx);
+        let wire = core::mem::take(&mut tx.access_writer().out_tx_buffer);
+        assert_ne!(wire, &[0, 5, 0x01, 5, 1, 5, 1, 1, 3, 0x62, 0x31, 0][..]);
+        rx.access_reader().source.extend(wire);
+        let mut messages = Vec::new();
+        while !rx.access_reader().source.is_empty() {
+            if let Ok(Some(RxEvent::Message(m))) = rx.run_rx() {
+                messages.push(m);
+            }
+        }
+        assert_eq!(messages, vec![Message::B(5)]);
+    }
+
+    /// A 16-bit sum of the bytes, sent least significant byte first.
+    struct Sum16;
+
+    impl FrameChecksum for Sum16 {
+        type Value = u16;
+
+        fn width() -> usize {
+            2
+        }
+
+        fn update(crc: u16, data: &[u8]) -> u16 {
+            data.iter().fold(crc, |acc, &b| acc.wrapping_add(b as u16))
+        }
+
+        fn to_bytes(value: u16, dest: &mut [u8]) {
+            dest.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// A checksum which needs a whole `u32`.
+    struct Sum24;
+
+    impl FrameChecksum for Sum24 {
+        type Value = u32;
+
+        fn width() -> usize {
+            3
+        }
+
+        fn update(crc: u32, data: &[u8]) -> u32 {
+            data.iter().fold(crc, |acc, &b| acc + b as u32) & 0xFF_FFFF
+        }
+    }
+
+    #[test]
+    fn custom_checksum() {
+        assert_eq!(Sum16::compute(&[0xFF, 0xFF, 3]), 0x0201);
+        assert_eq!(
+            ChecksumWidth::custom::<Sum16>(),
+            ChecksumWidth::custom::<Sum16>()
+        );
+        assert_ne!(
+            ChecksumWidth::custom::<Sum16>(),
+            ChecksumWidth::custom::<Sum24>()
+        );
+        assert_ne!(ChecksumWidth::custom::<Crc16X25>(), ChecksumWidth::Crc16);
+
+        for &(width, len, tail) in &[
+            (
+                ChecksumWidth::custom::<Sum16>(),
+                2,
+                &[0x02, 0x0C, 0x01, 0x00][..],
+            ),
+            (
+                ChecksumWidth::custom::<Sum24>(),
+                3,
+                &[0x01, 0x01, 0x02, 0x0C, 0x00][..],
+            ),
+        ] {
+            let new = || {
+                let mut illyria = new_illyria(100);
+                illyria.set_checksum_width(width);
+                illyria
+            };
+            let mut tx = new();
+            let mut rx = new();
+            assert_eq!(tx.checksum_width(), len);
+            tx.send(&Message::B(5)).unwrap();
+            send_frame_out(&mut tx);
+            let wire = core::mem::take(&mut tx.access_writer().out_tx_buffer);
+            // The bytes add up to 0x0C, and the checksum's zeros are COBS
+            // encoded
+            assert!(wire.ends_with(tail), "{:02x?}", wire);
+            assert_eq!(collect_events(&mut rx, &wire), vec!["Message(B(5))"]);
+
+            // A corrupted frame is NACKed, as with the built-in checksums
+            let mut bad = wire.clone();
+            bad[5] ^= 0x10;
+            assert_eq!(collect_events(&mut rx, &bad), vec!["CorruptFrame"]);
+            assert_eq!(rx.stats().crc_errors, 1);
+        }
+    }
+
+    #[test]
+    fn abort_pending() {
+        let mut illyria = new_illyria(2);
+        assert_eq!(illyria.retries_remaining(), None);
+        illyria.set_max_retries(Some(3));
+        assert_eq!(illyria.retries_remaining(), Some(3));
+        illyria.send(&Message::A).unwrap();
+        // Sent, then re-sent once
+        poll_tx(&mut illyria, 20);
+        assert_eq!(illyria.retries_remaining(), Some(2));
+        assert!(matches!(
+            illyria.send(&Message::A),
+            Err(Error::PacketInFlight)
+        ));
+
+        // Abort it half-way through being re-sent
+        illyria.access_writer().out_tx_buffer.clear();
+        poll_tx(&mut illyria, 4);
+        illyria.abort_pending();
+        assert_eq!(illyria.link_state(), LinkState::Idle);
+        assert_eq!(illyria.retries_remaining(), Some(3));
+        assert_eq!(illyria.next_tx_colour, Colour::Purple);
+        illyria.access_writer().out_tx_buffer.clear();
+        poll_tx(&mut illyria, 10);
+        illyria.access_writer().check(&[]);
+
+        // An ACK we're sending isn't cut short
+        illyria.send(&Message::B(5)).unwrap();
+        collect_events(
+            &mut illyria,
+            &[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0],
+        );
+        poll_tx(&mut illyria, 3);
+        illyria.abort_pending();
+        poll_tx(&mut illyria, 10);
+        illyria
+            .access_writer()
+            .check(&[0, 2, HEADER_ACK, 3, 0x3C, 0xF7, 0]);
+        illyria.send(&Message::A).unwrap();
+    }
+
+    #[test]
+    fn stale_ack_after_resend() {
+        let mut illyria = new_illyria(2);
+        illyria.set_coloured_acks(true);
+        illyria.send(&Message::A).unwrap();
+        poll_tx(&mut illyria, 9);
+        deliver(&mut illyria, &expected_ack_frame(Colour::Purple));
+
+        // Blue A goes out, times out, and goes out again
+        illyria.send(&Message::A).unwrap();
+        poll_tx(&mut illyria, 20);
+        assert_eq!(illyria.link_state(), LinkState::Retrying(1));
+
+        // The (late) ACK for the first copy clears it
+        deliver(&mut illyria, &expected_ack_frame(Colour::Blue));
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Acked));
+        assert_eq!(illyria.next_tx_colour, Colour::Red);
+
+        // The ACK for the second copy doesn't clear Red A
+        illyria.send(&Message::A).unwrap();
+        poll_tx(&mut illyria, 9);
+        deliver(&mut illyria, &expected_ack_frame(Colour::Blue));
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Ignored));
+        assert_eq!(illyria.next_tx_colour, Colour::Red);
+        assert!(matches!(
+            illyria.send(&Message::A),
+            Err(Error::PacketInFlight)
+        ));
+        assert_eq!(illyria.stats().frames_acked, 2);
+    }
+
+    /// Plays the far end, by ACKing every I-Frame it sees written.
+    struct AckingWriter {
+        in_frame: bool,
+        far_end: std::rc::Rc<std::cell::RefCell<VecDeque<u8>>>,
+    }
+
+    /// Reads what an `AckingWriter` sends back.
+    struct SharedReader {
+        source: std::rc::Rc<std::cell::RefCell<VecDeque<u8>>>,
+    }
+
+    impl embedded_hal::serial::Write<u8> for AckingWriter {
+        type Error = ();
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+            if byte == 0 {
+                if self.in_frame {
+                    self.far_end
+                        .borrow_mut()
+                        .extend(&expected_ack_frame(Colour::Purple));
+                }
+                self.in_frame = !self.in_frame;
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    impl embedded_hal::serial::Read<u8> for SharedReader {
+        type Error = ();
+
+        fn read(&mut self) -> nb::Result<u8, Self::Error> {
+            self.source
+                .borrow_mut()
+                .pop_front()
+                .ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    #[test]
+    fn send_blocking() {
+        let wire = std::rc::Rc::new(std::cell::RefCell::new(VecDeque::new()));
+        let mut illyria: Illyria<Message, Message, AckingWriter, SharedReader, 66, 66> =
+            Illyria::new(
+                AckingWriter {
+                    in_frame: false,
+                    far_end: wire.clone(),
+                },
+                SharedReader { source: wire },
+                100,
+            );
+        illyria.send_blocking(&Message::A, 20).unwrap();
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Acked));
+        illyria.send_blocking(&Message::B(5), 20).unwrap();
+        assert_eq!(illyria.stats().frames_acked, 2);
+
+        // Nobody answers
+        let mut illyria = new_illyria(2);
+        assert!(matches!(
+            illyria.send_blocking(&Message::A, 10),
+            Err(Error::TransportWouldBlock)
+        ));
+        assert!(matches!(
+            illyria.send(&Message::A),
+            Err(Error::PacketInFlight)
+        ));
+        illyria.abort_pending();
+        illyria.set_max_retries(Some(1));
+        assert!(matches!(
+            illyria.send_blocking(&Message::A, 100),
+            Err(Error::LinkDead)
+        ));
+    }
+
+    #[test]
+    fn run_rx_until_block() {
+        let mut illyria = new_illyria(100);
+        assert_eq!(illyria.run_rx_until_block().unwrap(), 0);
+        illyria.access_reader().source.extend(&[
+            0, 2, 2, 3, 0x3C, 0xF7, // An (unexpected) ACK
+            0, 3, 1, 1, 3, 0xFF, 0xC8, // Bad checksum
+            0, 3, 1, 1, 3, 0x85, 0xC8, // Purple A
+            0, 3, 0x11, 1, 1, 2, 0x5D, // Blue A
+            0,
+        ]);
+        // Stops once Purple A fills the queue
+        assert_eq!(illyria.run_rx_until_block().unwrap(), 2);
+        assert!(matches!(
+            illyria.run_rx(),
+            Ok(Some(RxEvent::Message(Message::A)))
+        ));
+        assert_eq!(illyria.run_rx_until_block().unwrap(), 1);
+        assert!(matches!(
+            illyria.run_rx(),
+            Ok(Some(RxEvent::Message(Message::A)))
+        ));
+        assert_eq!(illyria.run_rx_until_block().unwrap(), 0);
+        assert!(illyria.access_reader().source.is_empty());
+    }
+
+    #[test]
+    fn tiny_rx_buffer() {
+        // Too small for the frame type and length
+        let mut illyria: Illyria<Message, Message, TestWriter, TestReader, 66, 1> =
+            Illyria::new(TestWriter::new(), TestReader::new(), 100);
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
+        while !illyria.access_reader().source.is_empty() {
+            assert!(matches!(illyria.run_rx(), Ok(None)));
+        }
+        assert_eq!(illyria.frames_received(), 0);
+
+        // A frame which claims a huge length, and then keeps on going
+        let mut illyria: Illyria<Message, Message, TestWriter, TestReader, 66, 8> =
+            Illyria::new(TestWriter::new(), TestReader::new(), 100);
+        let wire = [&[0][..], &[0xFF; 600], &[0, 3, 1, 1, 3, 0x85, 0xC8, 0]].concat();
+        let events = collect_events(&mut illyria, &wire);
+        // The good frame afterwards still gets through
+        assert_eq!(events, vec!["Message(A)"]);
+    }
+
+    #[test]
+    fn connect() {
+        let mut a = new_illyria(100);
+        let mut b = new_illyria(100);
+        assert!(!a.is_connected());
+        assert!(!b.is_connected());
+
+        // b has sent a message, which a never saw
+        b.send(&Message::A).unwrap();
+        send_frame_out(&mut b);
+        b.access_writer().out_tx_buffer.clear();
+
+        a.connect();
+        send_frame_out(&mut a);
+        let sync = core::mem::take(&mut a.access_writer().out_tx_buffer);
+        assert_eq!(sync[2], HEADER_SYNC);
+        assert!(matches!(a.send(&Message::A), Err(Error::PacketInFlight)));
+
+        // A stale ACK doesn't count, so the SYNC is re-sent
+        collect_events(&mut a, &expected_ack_frame(Colour::Purple));
+        assert_eq!(a.take_tx_result(), Some(TxResult::Ignored));
+        assert!(!a.is_connected());
+        poll_tx(&mut a, 110);
+        assert_eq!(a.access_writer().out_tx_buffer, sync);
+        a.access_writer().out_tx_buffer.clear();
+
+        // b throws away its message, and answers with a SYNC-ACK
+        assert_eq!(collect_events(&mut b, &sync), vec!["Reset"]);
+        assert!(b.is_connected());
+        assert!(!a.is_connected());
+        send_frame_out(&mut b);
+        let sync_ack = core::mem::take(&mut b.access_writer().out_tx_buffer);
+        assert_eq!(sync_ack[2], HEADER_SYNCACK);
+        collect_events(&mut a, &sync_ack);
+        assert!(a.is_connected());
+        assert_eq!(a.take_tx_result(), Some(TxResult::Acked));
+
+        // Both ends start again from Purple
+        assert_eq!(a.next_tx_colour, Colour::Purple);
+        assert_eq!(b.next_tx_colour, Colour::Purple);
+        a.send(&Message::A).unwrap();
+        send_frame_out(&mut a);
+        let wire = core::mem::take(&mut a.access_writer().out_tx_buffer);
+        assert_eq!(wire, [0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0]);
+        assert_eq!(collect_events(&mut b, &wire), vec!["Message(A)"]);
+
+        // A SYNC-ACK we weren't waiting for is ignored
+        collect_events(&mut b, &sync_ack);
+        assert_eq!(b.take_tx_result(), Some(TxResult::Ignored));
+
+        a.resync();
+        assert!(!a.is_connected());
+    }
+
+    #[test]
+    fn send_during_sframe() {
+        let mut illyria = new_illyria(100);
+        collect_events(
+            &mut illyria,
+            &[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0],
+        );
+        // Half-way through sending the ACK
+        poll_tx(&mut illyria, 4);
+        assert!(matches!(
+            illyria.tx_state_debug(),
+            TxStateInfo::SendingPayload {
+                frame: FrameKind::SFrame,
+                ..
+            }
+        ));
+        illyria.send(&Message::A).unwrap();
+        assert!(matches!(
+            illyria.send(&Message::A),
+            Err(Error::PacketInFlight)
+        ));
+        poll_tx(&mut illyria, 13);
+        // The send flushed the writer, so we only see the end of the ACK
+        illyria.access_writer().check(&[
+            3,
+            0x3C,
+            0xF7,
+            0,
+            0,
+            3,
+            HEADER_PURPLE_IFRAME,
+            1,
+            3,
+            0x85,
+            0xC8,
+            0,
+        ]);
+    }
+
+    #[test]
+    fn piggyback_acks() {
+        let new = || {
+            let mut illyria = new_illyria(100);
+            illyria.set_piggyback_acks(true);
+            illyria
+        };
+        let mut a = new();
+        let mut b = new();
+        let pump = |from: &mut MyIllyria, to: &mut MyIllyria| {
+            poll_tx(from, 20);
+            let wire = core::mem::take(&mut from.access_writer().out_tx_buffer);
+            to.access_reader().source.extend(wire);
+            let mut messages = Vec::new();
+            while !to.access_reader().source.is_empty() {
+                if let Ok(Some(RxEvent::Message(m))) = to.run_rx() {
+                    messages.push(m);
+                }
+            }
+            messages
+        };
+
+        a.send(&Message::A).unwrap();
+        assert_eq!(pump(&mut a, &mut b), vec![Message::A]);
+
+        // b's reply carries the ACK, so there's only one frame on the wire
+        b.send(&Message::A).unwrap();
+        send_frame_out(&mut b);
+        b.access_writer()
+            .check(&[0, 3, HEADER_PURPLE_IFRAME_ACK, 1, 3, 0x83, 0xBE, 0]);
+        assert_eq!(pump(&mut b, &mut a), vec![Message::A]);
+        assert_eq!(a.take_tx_result(), Some(TxResult::Acked));
+        assert_eq!(
+            a.last_rx_frame(),
+            Some(RxFrameKind::Known(FrameType::IFrameAck(Colour::Purple)))
+        );
+
+        // a has nothing to say, so its ACK goes on its own
+        assert!(pump(&mut a, &mut b).is_empty());
+        assert_eq!(b.take_tx_result(), Some(TxResult::Acked));
+        assert_eq!(b.stats().frames_acked, 1);
+
+        // If a Blue frame carrying an ACK has to be re-sent, the re-send
+        // doesn't carry the ACK again
+        b.send(&Message::B(5)).unwrap();
+        pump(&mut b, &mut a);
+        a.set_poll_limit(5);
+        a.send(&Message::A).unwrap();
+        send_frame_out(&mut a);
+        assert_eq!(a.access_writer().out_tx_buffer[2], HEADER_BLUE_IFRAME_ACK);
+        a.access_writer().out_tx_buffer.clear();
+        poll_tx(&mut a, 14);
+        assert_eq!(a.stats().retransmits, 1);
+        assert_eq!(a.access_writer().out_tx_buffer[2], HEADER_BLUE_IFRAME);
+    }
+
+    #[test]
+    fn empty_payload() {
+        let new = || RawIllyria::new(TestWriter::new(), TestReader::new(), 100);
+        let mut tx = new();
+        let mut rx = new();
+        tx.send_bytes(&[]).unwrap();
+        send_frame_out(&mut tx);
+        let wire = core::mem::take(&mut tx.access_writer().out_tx_buffer);
+        assert_eq!(wire, &[0, 2, HEADER_PURPLE_IFRAME, 3, 0x16, 0x9F, 0]);
+
+        // The codec gets an empty payload, and the frame is ACKed
+        let events = collect_events(&mut rx, &wire);
+        assert_eq!(events, vec!["Message([])"]);
+        poll_tx(&mut rx, 20);
+        rx.access_writer()
+            .check(&expected_ack_frame(Colour::Purple));
+
+        // An empty message goes the same way
+        collect_events(&mut tx, &expected_ack_frame(Colour::Purple));
+        tx.send(&vec![]).unwrap();
+        send_frame_out(&mut tx);
+        tx.access_writer()
+            .check(&[0, 2, HEADER_BLUE_IFRAME, 3, 0x83, 0x0E, 0]);
+    }
+
+    #[test]
+    fn full_tx_buffer() {
+        let new = || RawIllyria::new(TestWriter::new(), TestReader::new(), 100);
+        let mut tx = new();
+        let mut rx = new();
+        let payload = vec![0xFF; tx.space()];
+        assert_eq!(payload.len(), 62);
+        assert!(matches!(
+            tx.send_bytes(&[0xFF; 63]),
+            Err(Error::MessageTooLarge)
+        ));
+        tx.send_bytes(&payload).unwrap();
+        send_frame_out(&mut tx);
+        let wire = core::mem::take(&mut tx.access_writer().out_tx_buffer);
+        // The whole 66 byte frame, plus the COBS header and two delimiters
+        assert_eq!(wire.len(), 66 + 1 + 2);
+        assert!(wire.len() <= 66 + cobs_max_overhead(66) + 2);
+        rx.access_reader().source.extend(wire);
+        let mut messages = Vec::new();
+        while !rx.access_reader().source.is_empty() {
+            if let Some(RxEvent::Message(m)) = rx.run_rx().unwrap() {
+                messages.push(m);
+            }
+        }
+        assert_eq!(messages, vec![payload]);
+    }
+
+    #[test]
+    fn undecodable_message() {
+        let mut illyria = new_illyria(10);
+        // A Blue I-Frame with a good checksum, but no such message variant
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0x00, 0x06, 0x11, 0x01, 0x09, 0x9D, 0x9C, 0x00]);
+        let mut errors = 0;
+        while !illyria.access_reader().source.is_empty() {
+            match illyria.run_rx() {
+                Err(Error::Codec(CodecError::Postcard(_))) => errors += 1,
+                Ok(None) => {}
+                other => panic!("Unexpected {:?}", other),
+            }
+        }
+        assert_eq!(errors, 1);
+        // NACKed, and still expecting Blue
+        assert_eq!(illyria.sframe_pending, Some(HEADER_NACK));
+        assert_eq!(illyria.rx_colour, Colour::Blue);
+
+        // So the re-send isn't taken for a duplicate
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0x00, 0x03, 0x11, 0x01, 0x01, 0x02, 0x5D, 0x00]);
+        let mut messages = Vec::new();
+        while !illyria.access_reader().source.is_empty() {
+            if let Some(RxEvent::Message(m)) = illyria.run_rx().unwrap() {
+                messages.push(m);
+            }
+        }
+        assert_eq!(messages, vec![Message::A]);
+        assert_eq!(illyria.sframe_pending, Some(HEADER_ACK));
+    }
+
+    #[test]
+    fn poll() {
+        let mut illyria = new_illyria(10);
+        let blue_a = [0x00, 0x03, 0x11, 0x01, 0x01, 0x02, 0x5D, 0x00];
+        let red_b = [
+            0x00, 0x05, 0x21, 0x05, 0x01, 0x05, 0x01, 0x01, 0x03, 0x01, 0xB2, 0x00,
+        ];
+        for wire in &[&blue_a[..], &blue_a[..], &red_b[..]] {
+            illyria.access_reader().source.extend(wire.iter());
+        }
+        let mut received = Vec::new();
+        while !illyria.access_reader().source.is_empty() {
+            if let Some(m) = illyria.poll().unwrap() {
+                received.push(m);
+            }
+        }
+        assert_eq!(
+            received,
+            vec![
+                ReceivedMessage {
+                    message: Message::A,
+                    colour: Colour::Blue,
+                    duplicate: false,
+                },
+                ReceivedMessage {
+                    message: Message::A,
+                    colour: Colour::Blue,
+                    duplicate: true,
+                },
+                ReceivedMessage {
+                    message: Message::B(5),
+                    colour: Colour::Red,
+                    duplicate: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn poll_large_duplicate() {
+        let new = || LargeIllyria::new(TestWriter::new(), TestReader::new(), 100);
+        let mut a = new();
+        let mut b = new();
+        let message: Vec<u8> = (1..=30).collect();
+        a.send_large(&message).unwrap();
+        let mut received = Vec::new();
+        let mut last_fragment = Vec::new();
+        while received.is_empty() {
+            for _ in 0..100 {
+                let _ = a.run_tx();
+            }
+            last_fragment = core::mem::take(&mut a.access_writer().out_tx_buffer);
+            b.access_reader().source.extend(last_fragment.iter());
+            while let Ok(m) = b.poll() {
+                received.extend(m);
+            }
+            for _ in 0..20 {
+                let _ = b.run_tx();
+            }
+            let ack = core::mem::take(&mut b.access_writer().out_tx_buffer);
+            a.access_reader().source.extend(ack);
+            while a.run_rx().is_ok() {}
+        }
+        // The last fragment again, as if our ACK had been lost
+        b.access_reader().source.extend(last_fragment.iter());
+        while let Ok(m) = b.poll() {
+            received.extend(m);
+        }
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].message, message);
+        assert!(!received[0].duplicate);
+        assert_eq!(received[1].message, message);
+        assert!(received[1].duplicate);
+    }
+
+    #[test]
+    fn ack_checksums() {
+        // Our ACK of Purple A carries Purple A's checksum
+        let mut illyria = new_illyria(100);
+        illyria.set_ack_checksums(true);
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0]);
+        assert_eq!(run_until_idle(&mut illyria, 50), vec![Message::A]);
+        illyria
+            .access_writer()
+            .check(&[0x00, 0x07, 0x02, 0x02, 0x85, 0xC8, 0xC8, 0x20, 0x00]);
+
+        // An ACK for some other frame doesn't clear Purple A, even though
+        // we don't send checksums ourselves
+        let mut illyria = new_illyria(100);
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        deliver(
+            &mut illyria,
+            &[0x00, 0x07, 0x02, 0x02, 0x85, 0xC9, 0xD9, 0xA9, 0x00],
+        );
+        assert!(matches!(
+            illyria.send(&Message::A),
+            Err(Error::PacketInFlight)
+        ));
+
+        // The right one does
+        deliver(
+            &mut illyria,
+            &[0x00, 0x07, 0x02, 0x02, 0x85, 0xC8, 0xC8, 0x20, 0x00],
+        );
+        assert_eq!(illyria.next_tx_colour, Colour::Blue);
+
+        // And so does an empty one
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        deliver(&mut illyria, &expected_ack_frame(Colour::Purple));
+        assert_eq!(illyria.next_tx_colour, Colour::Red);
+    }
+
+    #[test]
+    fn nack_truncated_frames() {
+        for &nack in &[false, true] {
+            let mut illyria = new_illyria(10);
+            illyria.set_nack_truncated_frames(nack);
+
+            // An S-Frame cut short just after its frame type (a truncated
+            // I-Frame is always NACKed)
+            illyria
+                .access_reader()
+                .source
+                .extend(&[0, 2, HEADER_ACK, 0]);
+            let mut truncated = 0;
+            while !illyria.access_reader().source.is_empty() {
+                if let Some(RxEvent::TruncatedFrame) = illyria.run_rx().unwrap() {
+                    truncated += 1;
+                }
+            }
+            assert_eq!(truncated, 1);
+            assert_eq!(illyria.stats().framing_errors, 1);
+            let expected = if nack { Some(HEADER_NACK) } else { None };
+            assert_eq!(illyria.sframe_pending, expected);
+
+            // A good frame is received as normal
+            illyria
+                .access_reader()
+                .source
+                .extend(&[0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
+            assert_eq!(run_until_idle(&mut illyria, 50), vec![Message::A]);
+            assert_eq!(illyria.stats().framing_errors, 1);
+        }
+    }
+
+    #[test]
+    fn decode_and_encode_frame() {
+        // Purple A, without its COBS encoding
+        let raw = [HEADER_PURPLE_IFRAME, 1, 0, 0x85, 0xC8];
+        let frame = decode_frame(&raw, FrameFormat::default()).unwrap();
+        assert_eq!(
+            frame,
+            Frame {
+                kind: RxFrameKind::Known(FrameType::IFrame(Colour::Purple)),
+                payload: &[0],
+                checksum_ok: true,
+            }
+        );
+        let mut buffer = [0u8; 16];
+        let len = encode_frame(
+            FrameType::IFrame(Colour::Purple),
+            &[0],
+            FrameFormat::default(),
+            &mut buffer,
+        )
+        .unwrap();
+        assert_eq!(&buffer[..len], &raw[..]);
+
+        // A corrupted frame still comes apart
+        let raw = [HEADER_PURPLE_IFRAME, 1, 0, 0x85, 0xC9];
+        let frame = decode_frame(&raw, FrameFormat::default());
+        assert_eq!(frame.map(|f| f.checksum_ok), Ok(false));
+        assert_eq!(
+            decode_frame(&[6, 0, 0x5B, 0x97], FrameFormat::default()).map(|f| f.kind),
+            Ok(RxFrameKind::Unknown(6))
+        );
+
+        // Whatever the settings, what we encode decodes again
+        let format = FrameFormat {
+            covers: ChecksumCovers::Encoded,
+            seed: 0x1234,
+            width: ChecksumWidth::Crc32,
+        };
+        let len = encode_frame(
+            FrameType::Broadcast(Colour::Red),
+            &[1, 0, 2],
+            format,
+            &mut buffer,
+        )
+        .unwrap();
+        assert_eq!(len, 9);
+        let frame = decode_frame(&buffer[..len], format).unwrap();
+        assert_eq!(
+            frame.kind,
+            RxFrameKind::Known(FrameType::Broadcast(Colour::Red))
+        );
+        assert_eq!(frame.payload, &[1, 0, 2]);
+        assert!(frame.checksum_ok);
+        assert!(decode_frame(&buffer[..len], FrameFormat::default()).is_err());
+
+        // Errors
+        assert_eq!(
+            decode_frame(
+                &[HEADER_PURPLE_IFRAME, 2, 0, 0x85, 0xC8],
+                FrameFormat::default()
+            ),
+            Err(FrameError::BadLength)
+        );
+        assert_eq!(
+            decode_frame(&[1, 0], FrameFormat::default()),
+            Err(FrameError::BadLength)
+        );
+        assert_eq!(
+            encode_frame(
+                FrameType::Nack,
+                &[0; 256],
+                FrameFormat::default(),
+                &mut buffer
+            ),
+            Err(FrameError::PayloadTooLarge)
+        );
+        assert_eq!(
+            encode_frame(
+                FrameType::Nack,
+                &[0; 13],
+                FrameFormat::default(),
+                &mut buffer
+            ),
+            Err(FrameError::BufferFull)
+        );
+    }
+
+    /// Records where in the output each flush happened. Flushes return
+    /// `WouldBlock` while `busy` is non-zero.
+    struct FlushingWriter {
+        written: Vec<u8>,
+        flushed_at: Vec<usize>,
+        busy: u32,
+    }
+
+    impl embedded_hal::serial::Write<u8> for FlushingWriter {
+        type Error = ();
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+            self.written.push(byte);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Self::Error> {
+            if self.busy > 0 {
+                self.busy -= 1;
+                Err(nb::Error::WouldBlock)
+            } else {
+                self.flushed_at.push(self.written.len());
+                Ok(())
+            }
         }
-        Ok(result)
     }
 
-    pub fn access_writer(&mut self) -> &mut TXO {
-        &mut self.writer
+    #[test]
+    fn flush_at_frame_end() {
+        let mut illyria: Illyria<Message, Message, FlushingWriter, TestReader, 66, 66> =
+            Illyria::new(
+                FlushingWriter {
+                    written: Vec::new(),
+                    flushed_at: Vec::new(),
+                    busy: 0,
+                },
+                TestReader::new(),
+                100,
+            );
+        // By default, we only flush when a frame is queued
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        assert_eq!(illyria.access_writer().flushed_at, vec![0]);
+
+        // Now only when one has been written
+        illyria.resync();
+        illyria.set_flush_on_send(false);
+        illyria.set_flush_at_frame_end(true);
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        assert_eq!(illyria.access_writer().flushed_at, vec![0, 16]);
+
+        // If the writer can't flush straight away, we keep trying
+        illyria.resync();
+        illyria.access_writer().busy = 2;
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        assert_eq!(illyria.access_writer().flushed_at, vec![0, 16]);
+        assert!(matches!(illyria.run_tx(), Err(Error::TransportWouldBlock)));
+        assert!(illyria.run_tx().is_ok());
+        assert_eq!(illyria.access_writer().flushed_at, vec![0, 16, 24]);
     }
 
-    pub fn access_reader(&mut self) -> &mut RXI {
-        &mut self.reader
+    #[test]
+    fn backoff() {
+        let mut illyria = new_illyria(2);
+
+        // How many calls to run_tx until each of the next `count` re-sends
+        let resends = |illyria: &mut MyIllyria, count: u32| {
+            let mut gaps = Vec::new();
+            let mut calls = 0;
+            let start = illyria.stats().retransmits;
+            while illyria.stats().retransmits - start < count {
+                let before = illyria.stats().retransmits;
+                illyria.run_tx().unwrap();
+                calls += 1;
+                if illyria.stats().retransmits != before {
+                    gaps.push(calls);
+                    calls = 0;
+                }
+            }
+            gaps
+        };
+
+        // Compared to a constant wait of 2 polls, we wait 2, 4, 8 and then
+        // 8 again
+        illyria.set_backoff(BackoffPolicy::Constant);
+        illyria.send(&Message::A).unwrap();
+        let constant = resends(&mut illyria, 4);
+        illyria.resync();
+        illyria.set_backoff(BackoffPolicy::Exponential { base: 2, max: 4 });
+        illyria.send(&Message::A).unwrap();
+        let gaps = resends(&mut illyria, 4);
+        let extra: Vec<_> = gaps.iter().zip(&constant).map(|(g, c)| g - c).collect();
+        assert_eq!(extra, vec![0, 2, 6, 6]);
+
+        // Once a frame is ACKed, the next one starts from the first wait
+        while !matches!(illyria.tx_state, TxState::WaitingForAckNack { .. }) {
+            illyria.run_tx().unwrap();
+        }
+        deliver(&mut illyria, &expected_ack_frame(Colour::Purple));
+        illyria.send(&Message::A).unwrap();
+        assert_eq!(resends(&mut illyria, 1), vec![gaps[0]]);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use nb;
-    use serde::{Serialize, Deserialize};
-    use std::collections::VecDeque;
+    #[test]
+    fn byte_counts() {
+        let mut illyria = new_illyria(100);
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        assert_eq!(illyria.bytes_transmitted(), 8);
 
-    #[derive(Debug)]
-    struct TestWriter {
-        out_tx_buffer: Vec<u8>,
+        // Every byte counts, good frame or not
+        deliver(&mut illyria, &[0, 3, 1, 1, 3, 0x85, 0xFF, 0]);
+        assert_eq!(illyria.run_rx_slice(&expected_ack_frame(Colour::Purple)), 7);
+        assert_eq!(illyria.bytes_received(), 15);
+
+        illyria.reset_stats();
+        assert_eq!(illyria.bytes_transmitted(), 0);
+        assert_eq!(illyria.bytes_received(), 0);
     }
 
-    #[derive(Debug)]
-    struct TestReader {
-        source: VecDeque<u8>,
+    #[test]
+    fn length_prefixed_framing() {
+        let mut illyria = MyIllyria::new_length_prefixed(TestWriter::new(), TestReader::new(), 100);
+        // The zeros in the frame go out as they are
+        illyria.send(&Message::B(5)).unwrap();
+        send_frame_out(&mut illyria);
+        illyria
+            .access_writer()
+            .check(&[0xAA, 0x55, 1, 5, 1, 5, 0, 0, 0, 0x62, 0x31]);
+
+        // Bytes before the sync word are ignored
+        deliver(
+            &mut illyria,
+            &[0x00, 0xAA, 0x12, 0xAA, 0x55, 2, 0, 0x3C, 0xF7],
+        );
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Acked));
+
+        illyria.access_writer().out_tx_buffer.clear();
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0xAA, 0xAA, 0x55, 1, 5, 1, 5, 0, 0, 0, 0x62, 0x31]);
+        assert_eq!(run_until_idle(&mut illyria, 50), vec![Message::B(5)]);
+        illyria
+            .access_writer()
+            .check(&[0xAA, 0x55, 2, 0, 0x3C, 0xF7]);
+
+        // A corrupt frame is dropped, and the next one is found
+        illyria.access_reader().source.extend(&[
+            0xAA, 0x55, 0x11, 1, 0, 0, 0x5E, 0xAA, 0x55, 0x11, 1, 0, 0, 0x5D,
+        ]);
+        assert_eq!(run_until_idle(&mut illyria, 50), vec![Message::A]);
+        assert_eq!(illyria.stats().crc_errors, 1);
+
+        assert!(matches!(
+            illyria.forward_frame(&[3, 1, 1, 3, 0x85, 0xC8]),
+            Err(Error::InvalidFrameType)
+        ));
     }
 
-    impl TestWriter {
-        fn check(&self, expected: &[u8]) {
-            assert_eq!(self.out_tx_buffer, expected);
-        }
+    #[test]
+    fn run_tx_burst() {
+        let mut illyria = new_illyria(100);
+        illyria.send(&Message::B(5)).unwrap();
+        assert_eq!(illyria.run_tx_burst(5).unwrap().0, 5);
+        // The rest of the frame, and no more
+        assert_eq!(illyria.run_tx_burst(100).unwrap().0, 7);
+        illyria
+            .access_writer()
+            .check(&[0, 5, 1, 5, 1, 5, 1, 1, 3, 0x62, 0x31, 0]);
+        assert!(matches!(
+            illyria.tx_state,
+            TxState::WaitingForAckNack { num_polls: 0 }
+        ));
+
+        // Waiting for an ACK is one poll per call
+        assert_eq!(illyria.run_tx_burst(100).unwrap().0, 0);
+        assert!(matches!(
+            illyria.tx_state,
+            TxState::WaitingForAckNack { num_polls: 1 }
+        ));
+
+        // The ACK we owe goes out in one go
+        deliver(&mut illyria, &[0, 3, 0x11, 1, 1, 2, 0x5D, 0]);
+        illyria.access_writer().out_tx_buffer.clear();
+        assert_eq!(illyria.run_tx_burst(100).unwrap().0, 7);
+        illyria
+            .access_writer()
+            .check(&expected_ack_frame(Colour::Purple));
     }
 
-    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
-    enum Message {
-        A,
-        B(u32),
-        C(bool),
-        D([u32; 16]),
-        E([u32; 15]),
+    /// Returns `WouldBlock` for every other byte.
+    struct StutteringWriter {
+        written: Vec<u8>,
+        block_next: bool,
     }
 
-    impl embedded_hal::serial::Write<u8> for TestWriter {
+    impl embedded_hal::serial::Write<u8> for StutteringWriter {
         type Error = ();
 
         fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
-            self.out_tx_buffer.push(byte);
-            Ok(())
+            self.block_next = !self.block_next;
+            if self.block_next {
+                Err(nb::Error::WouldBlock)
+            } else {
+                self.written.push(byte);
+                Ok(())
+            }
         }
 
         fn flush(&mut self) -> nb::Result<(), Self::Error> {
-            self.out_tx_buffer = Vec::new();
             Ok(())
         }
     }
 
-    impl embedded_hal::serial::Read<u8> for TestReader {
-        type Error = ();
+    type StutteringIllyria = Illyria<Message, Message, StutteringWriter, TestReader, 66, 66>;
 
-        fn read(&mut self) -> nb::Result<u8, Self::Error> {
-            match self.source.pop_front() {
-                Some(b) => {
-                    Ok(b)
+    #[test]
+    fn writer_blocks_mid_frame() {
+        let mut values = [0u32; 15];
+        for (i, v) in values.iter_mut().enumerate() {
+            // Plenty of zeros, and gaps of different lengths between them
+            *v = (i as u32) << (8 * (i % 4));
+        }
+        for message in &[Message::A, Message::B(5), Message::E(values)] {
+            for &double in &[false, true] {
+                let mut smooth = new_illyria(100);
+                smooth.set_double_delimiters(double);
+                smooth.send(message).unwrap();
+                while !matches!(smooth.tx_state, TxState::WaitingForAckNack { .. }) {
+                    smooth.run_tx().unwrap();
                 }
-                None => {
-                    Err(nb::Error::WouldBlock)
+
+                let mut stuttering: StutteringIllyria = Illyria::new(
+                    StutteringWriter {
+                        written: Vec::new(),
+                        block_next: false,
+                    },
+                    TestReader::new(),
+                    100,
+                );
+                stuttering.set_double_delimiters(double);
+                stuttering.send(message).unwrap();
+                let mut blocked = 0;
+                while !matches!(stuttering.tx_state, TxState::WaitingForAckNack { .. }) {
+                    match stuttering.run_tx() {
+                        Ok(_) => {}
+                        Err(Error::TransportWouldBlock) => blocked += 1,
+                        Err(e) => panic!("Got error {:?}", e),
+                    }
                 }
+                let expected = &smooth.access_writer().out_tx_buffer;
+                assert_eq!(&stuttering.access_writer().written, expected);
+                assert_eq!(blocked, expected.len());
+            }
+        }
+    }
+
+    #[test]
+    fn in_progress() {
+        let mut illyria = new_illyria(100);
+        assert!(!illyria.tx_in_progress());
+        illyria.send(&Message::A).unwrap();
+        // Queued, but not started
+        assert!(!illyria.tx_in_progress());
+        let mut busy = 0;
+        for _ in 0..9 {
+            illyria.run_tx().unwrap();
+            if illyria.tx_in_progress() {
+                busy += 1;
             }
         }
+        // Waiting for the ACK
+        assert!(!illyria.tx_in_progress());
+        assert_eq!(busy, 8);
+
+        let mut busy = Vec::new();
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, 0x11, 1, 1, 2, 0x5D, 0]);
+        while !illyria.access_reader().source.is_empty() {
+            let _ = illyria.run_rx();
+            busy.push(illyria.rx_in_progress());
+        }
+        assert_eq!(
+            busy,
+            vec![false, true, true, true, true, true, false, false]
+        );
     }
 
-    type MyIllyria = Illyria<Message, Message, TestWriter, TestReader, heapless::consts::U66, heapless::consts::U66>;
+    type WindowedIllyria =
+        Illyria<Message, Message, TestWriter, TestReader, 66, 66, Postcard, 2, 0, NoLogger, 4>;
+
+    /// Split what went down the wire into COBS frames, delimiters and all.
+    fn wire_frames(bytes: &[u8]) -> Vec<Vec<u8>> {
+        bytes
+            .split(|&b| b == 0)
+            .filter(|f| !f.is_empty())
+            .map(|f| [&[0], f, &[0]].concat())
+            .collect()
+    }
 
     #[test]
-    fn timeout_message() {
-        let t = TestWriter {
-            out_tx_buffer: Vec::new(),
-        };
+    fn sliding_window() {
+        let mut sender = WindowedIllyria::new(TestWriter::new(), TestReader::new(), 3);
+        let mut receiver = new_illyria(3);
+        for i in 0..4 {
+            sender.send(&Message::B(i)).unwrap();
+        }
+        assert!(matches!(
+            sender.send(&Message::A),
+            Err(Error::PacketInFlight)
+        ));
+        assert_eq!(sender.window_in_flight(), 4);
 
-        let r = TestReader {
-            source: VecDeque::new(),
-        };
+        // All four go out before we wait for an ACK
+        while let WaitingForAckNack::No = sender.run_tx().unwrap() {}
+        let frames = wire_frames(&sender.writer.out_tx_buffer);
+        assert_eq!(frames.len(), 4);
+        sender.writer.out_tx_buffer.clear();
 
-        let mut illyria: MyIllyria = MyIllyria::new(t, r, 10);
+        // Lose the second one. The receiver only keeps the first, and ACKs
+        // up to there.
+        for i in [0, 2, 3] {
+            receiver.reader.source.extend(&frames[i]);
+        }
+        assert_eq!(run_until_idle(&mut receiver, 200), [Message::B(0)]);
+        sender
+            .reader
+            .source
+            .extend(receiver.writer.out_tx_buffer.drain(..));
+        while !sender.reader.source.is_empty() {
+            sender.run_rx().unwrap();
+        }
+        assert_eq!(sender.take_tx_result(), Some(TxResult::Acked));
+        assert_eq!(sender.window_in_flight(), 3);
 
-        illyria.send(&Message::A).unwrap();
-        for _ in 0..17 {
-            illyria.run_tx().unwrap();
+        // No more ACKs come, so we go back and send the rest again
+        while sender
+            .writer
+            .out_tx_buffer
+            .iter()
+            .filter(|&&b| b == 0)
+            .count()
+            < 6
+        {
+            sender.run_tx().unwrap();
         }
-        illyria.access_writer().check(&[
-            0,    // COBS delimiter
-            3,    // Gap to next zero
-            1,    // Frame type
-            1,    // Length
-            3,    // Payload 0
-            0x85, // Checksum 0
-            0xC8, // Checksum 1
-            0,    // COBS delimiter
-        ]);
-        illyria.access_writer().out_tx_buffer.truncate(0);
-        // This should cause a retry
-        for _ in 0..11 {
-            illyria.run_tx().unwrap();
+        assert_eq!(wire_frames(&sender.writer.out_tx_buffer), &frames[1..]);
+        assert_eq!(sender.take_tx_result(), Some(TxResult::TimedOut));
+        assert_eq!(sender.stats().retransmits, 1);
+
+        receiver
+            .reader
+            .source
+            .extend(sender.writer.out_tx_buffer.drain(..));
+        assert_eq!(
+            run_until_idle(&mut receiver, 200),
+            [Message::B(1), Message::B(2), Message::B(3)]
+        );
+        sender
+            .reader
+            .source
+            .extend(receiver.writer.out_tx_buffer.drain(..));
+        while !sender.reader.source.is_empty() {
+            sender.run_rx().unwrap();
         }
-        illyria.access_writer().check(&[
-            0,    // COBS delimiter
-            3,    // Gap to next zero
-            1,    // Frame type
-            1,    // Length
-            3,    // Payload 0
-            0x85, // Checksum 0
-            0xC8, // Checksum 1
-            0,    // COBS delimiter
-        ]);
+        assert_eq!(sender.window_in_flight(), 0);
+        assert_eq!(sender.link_state(), LinkState::Idle);
+        assert_eq!(sender.stats().frames_acked, 4);
     }
 
     #[test]
-    fn rx_message() {
-        let t = TestWriter {
-            out_tx_buffer: Vec::new(),
-        };
-
-        let r = TestReader {
-            source: VecDeque::new(),
-        };
+    fn nack_unsupported_drops_frame() {
+        let mut illyria = new_illyria(100);
+        illyria.send_reset();
+        run_until_idle(&mut illyria, 20);
 
-        let mut illyria = MyIllyria::new(t, r, 10);
+        // A NACK-unsupported for some other frame type is ignored
+        let wrong = [0x00, 0x06, 0x07, 0x01, 0x06, 0x36, 0x27, 0x00];
+        illyria.access_reader().source.extend(&wrong);
+        run_until_idle(&mut illyria, 20);
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Ignored));
+        assert_eq!(illyria.link_state(), LinkState::InFlight);
 
-        illyria.access_reader().source.push_back(0); // COBS delimiter
-        illyria.access_reader().source.push_back(3); // Gap to next zero
-        illyria.access_reader().source.push_back(1); // Frame type
-        illyria.access_reader().source.push_back(1); // Length
-        illyria.access_reader().source.push_back(3); // Payload 0
-        illyria.access_reader().source.push_back(0x85); // Checksum 0
-        illyria.access_reader().source.push_back(0xC8); // Checksum 1
-        illyria.access_reader().source.push_back(0); // COBS delimiter
+        // One for our RESET means the far end will never ACK it
+        let reset = [0x00, 0x06, 0x07, 0x01, 0x04, 0x15, 0x35, 0x00];
+        illyria.access_reader().source.extend(&reset);
+        run_until_idle(&mut illyria, 20);
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Unsupported));
+        assert_eq!(illyria.link_state(), LinkState::Idle);
+        assert_eq!(illyria.stats().nacks_received, 2);
+        illyria.send(&Message::A).unwrap();
+    }
 
-        let mut seen = false;
-        for _ in 0..20 {
+    #[test]
+    fn send_reuses_tx_buffer() {
+        let mut illyria = new_illyria(100);
+        illyria.send(&Message::E([0xAAAA_AAAA; 15])).unwrap();
+        while !matches!(illyria.tx_state, TxState::WaitingForAckNack { .. }) {
             illyria.run_tx().unwrap();
-            match illyria.run_rx() {
-                Ok(None) => {}
-                Ok(Some(m)) => {
-                    assert!(!seen);
-                    assert_eq!(m, Message::A);
-                    seen = true;
-                }
-                Err(Error::TransportWouldBlock) => {}
-                Err(e) => {
-                    panic!("Got error {:?}", e);
-                }
+        }
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 2, 2, 3, 0x3C, 0xF7, 0]);
+        run_until_idle(&mut illyria, 200);
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Acked));
+
+        // A one byte message only writes the bytes it needs
+        illyria.send(&Message::A).unwrap();
+        let frame_len = illyria.tx_buffer.len();
+        assert_eq!(frame_len, 5);
+        assert!(illyria.tx_buffer.bytes[frame_len..].contains(&0xAA));
+
+        // And the checksum only covers the new frame
+        run_until_idle(&mut illyria, 20);
+        illyria
+            .access_writer()
+            .check(&[0, 3, 0x11, 1, 1, 2, 0x5D, 0]);
+    }
+
+    /// Run a future to completion, polling it until it's ready.
+    #[cfg(feature = "async")]
+    fn block_on<F: core::future::Future>(future: F) -> F::Output {
+        fn noop_raw_waker() -> core::task::RawWaker {
+            fn clone(_: *const ()) -> core::task::RawWaker {
+                noop_raw_waker()
             }
+            fn noop(_: *const ()) {}
+            static VTABLE: core::task::RawWakerVTable =
+                core::task::RawWakerVTable::new(clone, noop, noop, noop);
+            core::task::RawWaker::new(core::ptr::null(), &VTABLE)
         }
-        assert!(seen);
+        // SAFETY: the waker's functions do nothing at all
+        let waker = unsafe { core::task::Waker::from_raw(noop_raw_waker()) };
+        let mut cx = core::task::Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let core::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
 
-        // Should have sent an ACK
-        illyria.access_writer().check(&[
-            0,    // COBS delimiter
-            2,    // Gap to next zero
-            2,    // Frame type
-            3,    // Length
-            0x3C, // Checksum 0
-            0xF7, // Checksum 1
-            0,    // COBS delimiter
-        ]);
+    #[cfg(feature = "async")]
+    #[test]
+    fn send_and_recv_frame() {
+        let wire = std::rc::Rc::new(std::cell::RefCell::new(VecDeque::new()));
+        let mut illyria: Illyria<Message, Message, AckingWriter, SharedReader, 66, 66> =
+            Illyria::new(
+                AckingWriter {
+                    in_frame: false,
+                    far_end: wire.clone(),
+                },
+                SharedReader { source: wire },
+                100,
+            );
+        block_on(illyria.send_frame(&Message::A)).unwrap();
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Acked));
+        block_on(illyria.send_frame(&Message::B(5))).unwrap();
+        assert_eq!(illyria.stats().frames_acked, 2);
+
+        let mut illyria = new_illyria(100);
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
+        assert_eq!(block_on(illyria.recv_frame()).unwrap(), Message::A);
+        // The ACK goes out as we wait for the next one
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, 0x11, 1, 1, 2, 0x5D, 0]);
+        assert_eq!(block_on(illyria.recv_frame()).unwrap(), Message::A);
+        illyria
+            .access_writer()
+            .check(&expected_ack_frame(Colour::Purple));
     }
 
+    #[cfg(feature = "async")]
     #[test]
-    fn rx_message2() {
-        let t = TestWriter {
-            out_tx_buffer: Vec::new(),
-        };
+    fn send_and_recv_frame_with() {
+        let wire = std::rc::Rc::new(std::cell::RefCell::new(VecDeque::new()));
+        let mut illyria: Illyria<Message, Message, AckingWriter, SharedReader, 66, 66> =
+            Illyria::new(
+                AckingWriter {
+                    in_frame: false,
+                    far_end: wire.clone(),
+                },
+                SharedReader { source: wire },
+                100,
+            );
+        let mut waits = 0;
+        block_on(illyria.send_frame_with(&Message::A, || {
+            waits += 1;
+            core::future::ready(())
+        }))
+        .unwrap();
+        assert!(waits > 0);
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Acked));
 
-        let r = TestReader {
-            source: VecDeque::new(),
-        };
+        // The message arrives while we're waiting
+        let wire = std::rc::Rc::new(std::cell::RefCell::new(VecDeque::new()));
+        let mut illyria: Illyria<Message, Message, TestWriter, SharedReader, 66, 66> = Illyria::new(
+            TestWriter::new(),
+            SharedReader {
+                source: wire.clone(),
+            },
+            100,
+        );
+        let mut waits = 0;
+        let message = block_on(illyria.recv_frame_with(|| {
+            waits += 1;
+            if waits == 1 {
+                wire.borrow_mut().extend(&[0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
+            }
+            core::future::ready(())
+        }))
+        .unwrap();
+        assert_eq!(message, Message::A);
+        assert_eq!(waits, 1);
+    }
 
-        let mut illyria = MyIllyria::new(t, r, 10);
+    /// A writer whose flush fails while `broken` is set.
+    struct FaultyWriter {
+        written: Vec<u8>,
+        broken: bool,
+    }
 
-        illyria.access_reader().source.push_back(0); // COBS delimiter
-        illyria.access_reader().source.push_back(5); // Gap to next zero
-        illyria.access_reader().source.push_back(1); // Frame type
-        illyria.access_reader().source.push_back(5); // Length
-        illyria.access_reader().source.push_back(1); // Payload 0 - Message type B
-        illyria.access_reader().source.push_back(5); // Payload 1
-        illyria.access_reader().source.push_back(1); // Payload 2
-        illyria.access_reader().source.push_back(1); // Payload 3
-        illyria.access_reader().source.push_back(3); // Payload 4
-        illyria.access_reader().source.push_back(0x62); // Checksum 0
-        illyria.access_reader().source.push_back(0x31); // Checksum 1
-        illyria.access_reader().source.push_back(0); // COBS delimiter
+    impl embedded_hal::serial::Write<u8> for FaultyWriter {
+        type Error = ();
 
-        let mut seen = false;
-        for _ in 0..20 {
-            illyria.run_tx().unwrap();
-            match illyria.run_rx() {
-                Ok(None) => {}
-                Ok(Some(m)) => {
-                    assert!(!seen);
-                    assert_eq!(m, Message::B(5));
-                    seen = true;
-                }
-                Err(Error::TransportWouldBlock) => {}
-                Err(e) => {
-                    panic!("Got error {:?}", e);
-                }
+        fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+            self.written.push(byte);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Self::Error> {
+            if self.broken {
+                Err(nb::Error::Other(()))
+            } else {
+                Ok(())
             }
         }
-        assert!(seen);
+    }
 
-        // Should have sent an ACK
-        illyria.access_writer().check(&[
-            0,    // COBS delimiter
-            2,    // Gap to next zero
-            2,    // Frame type
-            3,    // Length
-            0x3C, // Checksum 0
-            0xF7, // Checksum 1
-            0,    // COBS delimiter
-        ]);
+    #[test]
+    fn flush_failures() {
+        let mut illyria: Illyria<Message, Message, FaultyWriter, TestReader, 66, 66> = Illyria::new(
+            FaultyWriter {
+                written: Vec::new(),
+                broken: false,
+            },
+            TestReader::new(),
+            100,
+        );
+        illyria.set_max_flush_failures(Some(2));
+
+        // A flush which fails holds the frame up, but it goes out once the
+        // writer recovers, with only one end delimiter
+        illyria.send(&Message::A).unwrap();
+        illyria.access_writer().broken = true;
+        poll_tx(&mut illyria, 8);
+        assert!(matches!(illyria.run_tx(), Err(Error::Writer(()))));
+        assert_eq!(illyria.stats().frames_sent, 0);
+        illyria.access_writer().broken = false;
+        illyria.run_tx().unwrap();
+        assert_eq!(illyria.stats().frames_sent, 1);
+        assert_eq!(illyria.current_poll_count(), Some(0));
+        assert_eq!(
+            illyria.access_writer().written,
+            &[0, 3, 1, 1, 3, 0x85, 0xC8, 0]
+        );
+
+        // Too many failures in a row and the frame is dropped
+        illyria.abort_pending();
+        illyria.access_writer().written.clear();
+        illyria.send(&Message::A).unwrap();
+        illyria.access_writer().broken = true;
+        poll_tx(&mut illyria, 8);
+        assert!(matches!(illyria.run_tx(), Err(Error::Writer(()))));
+        assert!(matches!(illyria.run_tx(), Err(Error::Writer(()))));
+        assert!(matches!(illyria.run_tx(), Err(Error::WriterFault)));
+        assert_eq!(illyria.link_state(), LinkState::Idle);
+        assert_eq!(illyria.stats().frames_sent, 1);
+
+        // And a failed flush stops us queueing anything else
+        assert!(matches!(illyria.send(&Message::A), Err(Error::Writer(()))));
+        illyria.access_writer().broken = false;
+        illyria.send(&Message::A).unwrap();
+    }
+
+    #[test]
+    fn builder() {
+        let mut illyria = MyIllyria::builder(TestWriter::new(), TestReader::new())
+            .max_retries(2)
+            .initial_colour(Colour::Blue)
+            .poll_limit(0)
+            .build();
+        assert_eq!(illyria.poll_limit, 1);
+        assert_eq!(illyria.retries_remaining(), Some(2));
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
+        illyria
+            .access_writer()
+            .check(&[0, 3, 0x11, 1, 1, 2, 0x5D, 0]);
+
+        // The options do the same as their setters
+        let ms = core::time::Duration::from_millis;
+        let illyria = MyIllyria::builder(TestWriter::new(), TestReader::new())
+            .clock(test_clock)
+            .ack_delay(ms(5))
+            .idle_keepalive(ms(500))
+            .double_delimiters(true)
+            .crc_seed(0x1234)
+            .checksum_width(ChecksumWidth::Crc32)
+            .nack_unknown_frames(true)
+            .coloured_acks(true)
+            .max_nacks(4)
+            .duplicate_policy(DuplicatePolicy::Report)
+            .poll_limit(10)
+            .build();
+        assert!(illyria.clock.is_some());
+        assert_eq!(illyria.ack_delay_ms, Some(5));
+        assert_eq!(illyria.keepalive_ms, Some(500));
+        assert!(illyria.double_delimiters);
+        assert_eq!(illyria.frame_format().seed, 0x1234);
+        assert_eq!(illyria.checksum_width(), 4);
+        assert!(illyria.nack_unknown_frames);
+        assert!(illyria.coloured_acks);
+        assert_eq!(illyria.max_nacks, Some(4));
+        assert_eq!(illyria.duplicate_policy, DuplicatePolicy::Report);
+
+        // `new` gives the same defaults
+        let illyria = new_illyria(7);
+        assert_eq!(illyria.poll_limit, 7);
+        assert_eq!(illyria.retries_remaining(), None);
+        assert_eq!(illyria.next_tx_colour, Colour::Purple);
+        assert_eq!(illyria.ack_delay_ms, None);
+        assert_eq!(illyria.keepalive_ms, None);
+        assert!(!illyria.double_delimiters);
+        assert_eq!(illyria.frame_format(), FrameFormat::default());
     }
 
+    #[cfg(feature = "testing")]
     #[test]
-    fn rx_bad_message() {
-        let t = TestWriter {
-            out_tx_buffer: Vec::new(),
-        };
+    fn feed_and_drain() {
+        let mut illyria = new_illyria(100);
+        illyria
+            .feed_rx_frame(FrameType::IFrame(Colour::Purple), &[0])
+            .unwrap();
+        assert!(matches!(
+            illyria.run_rx(),
+            Ok(Some(RxEvent::Message(Message::A)))
+        ));
+        assert_eq!(illyria.drain_tx(), expected_ack_frame(Colour::Purple));
+        assert_eq!(illyria.drain_tx(), []);
 
-        let r = TestReader {
-            source: VecDeque::new(),
-        };
+        illyria.send(&Message::B(5)).unwrap();
+        assert_eq!(
+            illyria.drain_tx(),
+            [0, 5, 1, 5, 1, 5, 1, 1, 3, 0x62, 0x31, 0]
+        );
+        assert_eq!(illyria.current_poll_count(), Some(0));
+        illyria
+            .feed_rx_frame(FrameType::Ack(Colour::Purple), &[])
+            .unwrap();
+        assert_eq!(illyria.take_tx_result(), Some(TxResult::Acked));
+        assert_eq!(
+            illyria.feed_rx_frame(FrameType::IFrame(Colour::Blue), &[0; 256]),
+            Err(FrameError::PayloadTooLarge)
+        );
 
-        let mut illyria = MyIllyria::new(t, r, 10);
+        // The frame follows our framing
+        illyria.set_framing(Framing::LengthPrefixed);
+        illyria
+            .feed_rx_frame(FrameType::IFrame(Colour::Blue), &[0])
+            .unwrap();
+        assert!(matches!(
+            illyria.run_rx(),
+            Ok(Some(RxEvent::Message(Message::A)))
+        ));
+    }
 
-        illyria.access_reader().source.push_back(0); // COBS delimiter
-        illyria.access_reader().source.push_back(3); // Gap to next zero
+    #[test]
+    fn wire_layout() {
+        for &(colour, bits) in &[
+            (Colour::Purple, wire::COLOUR_PURPLE),
+            (Colour::Blue, wire::COLOUR_BLUE),
+            (Colour::Red, wire::COLOUR_RED),
+        ] {
+            let iframe = FrameType::IFrame(colour).to_u8();
+            assert_eq!(iframe & wire::COLOUR_MASK, bits);
+            assert_eq!(iframe & !wire::COLOUR_MASK, wire::HEADER_PURPLE_IFRAME);
+            assert_eq!(FrameType::Fragment(colour).to_u8(), iframe | 0x04);
+            assert_eq!(
+                FrameType::IFrameAck(colour).to_u8(),
+                iframe | wire::PIGGYBACK_ACK
+            );
+            assert_eq!(FrameType::Ack(colour).to_u8(), wire::HEADER_ACK | bits);
+            if colour != Colour::Purple {
+                assert_eq!(FrameType::Broadcast(colour).to_u8(), iframe | 0x08);
+            }
+        }
+        assert_eq!(
+            FrameType::from_u8(wire::CONTROL_FRAME_FIRST),
+            Some(FrameType::Control(0x80))
+        );
+    }
+
+    #[test]
+    fn poll_into() {
+        let mut illyria = new_illyria(100);
+        let mut dst = [0xFF; 8];
         illyria
             .access_reader()
             .source
-            .push_back(MyIllyria::HEADER_PURPLE_IFRAME); // Frame type
-        illyria.access_reader().source.push_back(1); // Length
-        illyria.access_reader().source.push_back(3); // Payload 0
-        illyria.access_reader().source.push_back(0xFF); // Checksum 0 (bad)
-        illyria.access_reader().source.push_back(0xC8); // Checksum 1
-        illyria.access_reader().source.push_back(0); // COBS delimiter
+            .extend(&[0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
+        let mut received = Vec::new();
+        while let Ok(r) = illyria.poll_into(&mut dst) {
+            received.extend(r);
+        }
+        assert_eq!(received, [1]);
+        assert_eq!(dst[0], 0);
+        run_until_idle(&mut illyria, 50);
+        illyria
+            .access_writer()
+            .check(&expected_ack_frame(Colour::Purple));
+        illyria.access_writer().out_tx_buffer.clear();
 
-        for _ in 0..20 {
-            illyria.run_tx().unwrap();
-            match illyria.run_rx() {
-                Ok(..) => {}
-                Err(Error::TransportWouldBlock) => {}
-                Err(e) => {
-                    panic!("Got error {:?}", e);
-                }
+        // Blue B(5) doesn't fit, so it's NACKed
+        let blue_b = [0, 5, 0x11, 5, 1, 5, 1, 1, 3, 0xD7, 0xF8, 0];
+        illyria.access_reader().source.extend(&blue_b);
+        let mut small = [0; 2];
+        let mut too_small = false;
+        loop {
+            match illyria.poll_into(&mut small) {
+                Ok(_) => {}
+                Err(Error::BufferTooSmall) => too_small = true,
+                Err(_) => break,
             }
         }
+        assert!(too_small);
+        run_until_idle(&mut illyria, 50);
+        illyria.access_writer().check(&[0, 2, 3, 3, 0x25, 0x2F, 0]);
 
-        // Should be a COBS-encoded NACK frame
-        illyria.access_writer().check(&[
-            0,                      // COBS delimiter
-            2,                      // Gap to next zero
-            MyIllyria::HEADER_NACK, // Frame type
-            3,                      // Length (zero, replaced with gap to next zero)
-            0x3C,                   // Checksum 0
-            0xF7,                   // Checksum 1
-            0,                      // COBS delimiter
-        ]);
+        // The re-send isn't taken as a duplicate
+        illyria.access_reader().source.extend(&blue_b);
+        let mut received = Vec::new();
+        while let Ok(r) = illyria.poll_into(&mut dst) {
+            received.extend(r);
+        }
+        assert_eq!(received, [5]);
+        assert_eq!(&dst[..5], &[1, 5, 0, 0, 0]);
+
+        // Queued messages have to be taken first
+        let mut illyria = new_illyria(100);
+        illyria.run_rx_slice(&[0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
+        assert!(matches!(
+            illyria.poll_into(&mut dst),
+            Err(Error::MessagesQueued)
+        ));
+        assert!(matches!(
+            illyria.run_rx(),
+            Ok(Some(RxEvent::Message(Message::A)))
+        ));
+        assert!(matches!(
+            illyria.poll_into(&mut dst),
+            Err(Error::TransportWouldBlock)
+        ));
     }
 
     #[test]
-    fn ack_message() {
-        let t = TestWriter {
-            out_tx_buffer: Vec::new(),
-        };
+    fn corrupt_and_truncated_iframes() {
+        // Purple B(5), with a bit flipped in its payload
+        let mut illyria = new_illyria(100);
+        let mut flipped = [0, 5, 1, 5, 1, 5, 1, 1, 3, 0x62, 0x31, 0];
+        flipped[5] ^= 0x10;
+        assert_eq!(collect_events(&mut illyria, &flipped), vec!["CorruptFrame"]);
+        assert_eq!(illyria.stats().crc_errors, 1);
+        assert_eq!(illyria.stats().framing_errors, 0);
+        assert_eq!(illyria.sframe_pending, Some(HEADER_NACK));
 
-        let r = TestReader {
-            source: VecDeque::new(),
-        };
-
-        let mut illyria = MyIllyria::new(t, r, 50);
+        // Purple B(5), cut short part way through its payload
+        let mut illyria = new_illyria(100);
+        let cut = [0, 5, 1, 5, 1, 0];
+        assert_eq!(collect_events(&mut illyria, &cut), vec!["TruncatedFrame"]);
+        assert_eq!(illyria.stats().crc_errors, 0);
+        assert_eq!(illyria.stats().framing_errors, 1);
+        assert_eq!(illyria.sframe_pending, Some(HEADER_NACK));
 
-        illyria.send(&Message::A).unwrap();
-        for _ in 0..17 {
-            illyria.run_tx().unwrap();
-            match illyria.run_rx() {
-                Ok(..) => {}
-                Err(Error::TransportWouldBlock) => {}
-                Err(e) => {
-                    panic!("Got error {:?}", e);
-                }
-            }
-        }
-        illyria.access_writer().check(&[
-            0,    // COBS delimiter
-            3,    // Gap to next zero
-            1,    // Frame type
-            1,    // Length
-            3,    // Payload 0
-            0x85, // Checksum 0
-            0xC8, // Checksum 1
-            0,    // COBS delimiter
-        ]);
-        // Send an ACK
-        illyria.access_reader().source.push_back(0);
-        illyria.access_reader().source.push_back(2);
-        illyria.access_reader().source.push_back(2);
-        illyria.access_reader().source.push_back(3);
-        illyria.access_reader().source.push_back(0x3C);
-        illyria.access_reader().source.push_back(0xF7);
-        illyria.access_writer().out_tx_buffer.truncate(0);
-        // This should not cause a retry because it's been acked
-        for _ in 0..50 {
-            illyria.run_tx().unwrap();
-            match illyria.run_rx() {
-                Ok(..) => {}
-                Err(Error::TransportWouldBlock) => {}
-                Err(e) => {
-                    panic!("Got error {:?}", e);
-                }
-            }
-        }
-        illyria.access_writer().check(&[]);
+        // The re-sent frame is received as normal
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 5, 1, 5, 1, 5, 1, 1, 3, 0x62, 0x31, 0]);
+        assert_eq!(run_until_idle(&mut illyria, 50), vec![Message::B(5)]);
     }
 
     #[test]
-    fn duplicates() {
-        let t = TestWriter {
-            out_tx_buffer: Vec::new(),
-        };
+    fn flush_tx() {
+        let mut illyria: Illyria<Message, Message, FlushingWriter, TestReader, 66, 66> =
+            Illyria::new(
+                FlushingWriter {
+                    written: Vec::new(),
+                    flushed_at: Vec::new(),
+                    busy: 0,
+                },
+                TestReader::new(),
+                100,
+            );
+        illyria.set_flush_on_send(false);
 
-        let r = TestReader {
-            source: VecDeque::new(),
-        };
+        // Nothing to do
+        illyria.flush_tx().unwrap();
+        assert!(illyria.access_writer().flushed_at.is_empty());
 
-        let mut illyria = MyIllyria::new(t, r, 50);
-        for &expected_frame in &[
-            // purple = 01, blue = 11, red = 21
-            [0, 3, MyIllyria::HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0],
-            [0, 3, MyIllyria::HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0],
-            [0, 3, MyIllyria::HEADER_RED_IFRAME, 1, 3, 0x86, 0xF3, 0],
-            [0, 3, MyIllyria::HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0],
-        ] {
-            illyria.send(&Message::A).unwrap();
-            for _ in 0..17 {
-                illyria.run_tx().unwrap();
-                match illyria.run_rx() {
-                    Ok(..) => {}
-                    Err(Error::TransportWouldBlock) => {}
-                    Err(e) => {
-                        panic!("Got error {:?}", e);
-                    }
-                }
-            }
-            illyria.access_writer().check(&expected_frame);
+        // The whole frame goes out, and we wait for the writer to flush it
+        illyria.send(&Message::A).unwrap();
+        illyria.run_tx().unwrap();
+        illyria.access_writer().busy = 3;
+        illyria.flush_tx().unwrap();
+        assert_eq!(
+            illyria.access_writer().written,
+            [0, 3, 1, 1, 3, 0x85, 0xC8, 0]
+        );
+        assert_eq!(illyria.access_writer().flushed_at, vec![8]);
+        assert_eq!(illyria.access_writer().busy, 0);
 
-            // Send an ACK
-            illyria.access_reader().source.push_back(0); // COBS delimiter
-            illyria.access_reader().source.push_back(2); // Gap to next zero
-            illyria
-                .access_reader()
-                .source
-                .push_back(MyIllyria::HEADER_ACK); // Frame type
-            illyria.access_reader().source.push_back(3); // Length, actually zero but replaced with gap to next zero
-            illyria.access_reader().source.push_back(0x3C); // Checksum 0
-            illyria.access_reader().source.push_back(0xF7); // Checksum 1
-            illyria.access_writer().out_tx_buffer.truncate(0);
-            // This should not cause a retry because it's been acked
-            for _ in 0..50 {
-                illyria.run_tx().unwrap();
-                match illyria.run_rx() {
-                    Ok(..) => {}
-                    Err(Error::TransportWouldBlock) => {}
-                    Err(e) => {
-                        panic!("Got error {:?}", e);
-                    }
-                }
-            }
-            illyria.access_writer().check(&[]);
-        }
+        // But not for the ACK
+        assert_eq!(illyria.current_poll_count(), Some(0));
+        assert!(matches!(
+            illyria.send(&Message::A),
+            Err(Error::PacketInFlight)
+        ));
     }
 
     #[test]
-    fn nack_message() {
-        let t = TestWriter {
-            out_tx_buffer: Vec::new(),
-        };
-
-        let r = TestReader {
-            source: VecDeque::new(),
-        };
-
-        let mut illyria = MyIllyria::new(t, r, 50);
+    fn duplicate_policy() {
+        for &policy in &[DuplicatePolicy::Drop, DuplicatePolicy::Report] {
+            let mut illyria = new_illyria(50);
+            illyria.set_duplicate_policy(policy);
 
-        illyria.send(&Message::A).unwrap();
-        for _ in 0..17 {
-            illyria.run_tx().unwrap();
-            match illyria.run_rx() {
-                Ok(..) => {}
-                Err(Error::TransportWouldBlock) => {}
-                Err(e) => {
-                    panic!("Got error {:?}", e);
-                }
-            }
-        }
-        illyria.access_writer().check(&[
-            0,    // COBS delimiter
-            3,    // Gap to next zero
-            1,    // Frame type
-            1,    // Length
-            3,    // Payload 0
-            0x85, // Checksum 0
-            0xC8, // Checksum 1
-            0,    // COBS delimiter
-        ]);
-        // Send a NACK
-        illyria.access_reader().source.push_back(0);
-        illyria.access_reader().source.push_back(2);
-        illyria.access_reader().source.push_back(3);
-        illyria.access_reader().source.push_back(3);
-        illyria.access_reader().source.push_back(0x25);
-        illyria.access_reader().source.push_back(0x2F);
-        illyria.access_writer().out_tx_buffer.truncate(0);
-        // This should cause a retry because it's been nacked
-        for _ in 0..50 {
-            illyria.run_tx().unwrap();
-            match illyria.run_rx() {
-                Ok(..) => {}
-                Err(Error::TransportWouldBlock) => {}
-                Err(e) => {
-                    panic!("Got error {:?}", e);
-                }
+            // Blue A, and then Blue A again because our ACK was lost
+            let blue = [0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0];
+            let mut events = Vec::new();
+            for _ in 0..2 {
+                events.extend(collect_events(&mut illyria, &blue));
+                // Each copy is ACKed
+                assert!(illyria.sframe_pending.is_some());
+                poll_tx(&mut illyria, 20);
+                assert_eq!(illyria.sframe_pending, None);
             }
+            let expected = match policy {
+                DuplicatePolicy::Drop => vec!["Message(A)"],
+                DuplicatePolicy::Report => vec!["Message(A)", "Duplicate { colour: Blue }"],
+            };
+            assert_eq!(events, expected);
+            assert_eq!(illyria.stats().duplicates_dropped, 1);
         }
-        illyria.access_writer().check(&[
-            0,    // COBS delimiter
-            3,    // Gap to next zero
-            1,    // Frame type
-            1,    // Length
-            3,    // Payload 0
-            0x85, // Checksum 0
-            0xC8, // Checksum 1
-            0,    // COBS delimiter
-        ]);
     }
 
     #[test]
-    fn encode_a() {
-        let t = TestWriter {
-            out_tx_buffer: Vec::new(),
-        };
+    fn send_after_nack() {
+        let mut illyria = new_illyria(100);
+        let nack = [0, 2, HEADER_NACK, 3, 0x25, 0x2F, 0];
+        illyria.send(&Message::A).unwrap();
+        send_frame_out(&mut illyria);
 
-        let r = TestReader {
-            source: VecDeque::new(),
-        };
+        // NACKed while paused, so the re-send has to wait
+        illyria.pause_tx();
+        deliver(&mut illyria, &nack);
+        assert_eq!(illyria.link_state(), LinkState::AwaitingResend);
+        assert!(matches!(
+            illyria.send(&Message::B(5)),
+            Err(Error::PacketInFlight)
+        ));
 
-        let mut illyria = MyIllyria::new(t, r, 100);
+        // Once it has gone again, it's in flight as normal
+        illyria.resume_tx();
+        illyria.access_writer().out_tx_buffer.truncate(0);
+        poll_tx(&mut illyria, 9);
+        illyria
+            .access_writer()
+            .check(&[0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
+        assert_eq!(illyria.link_state(), LinkState::InFlight);
+        assert!(matches!(
+            illyria.send(&Message::B(5)),
+            Err(Error::PacketInFlight)
+        ));
 
-        illyria.send(&Message::A).unwrap();
-        for _ in 0..50 {
-            illyria.run_tx().unwrap();
-        }
-        illyria.access_writer().check(&[
-            0,    // COBS delimiter
-            3,    // Gap to next zero
-            1,    // Frame type
-            1,    // Length
-            3,    // Payload 0
-            0x85, // Checksum 0
-            0xC8, // Checksum 1
-            0,    // COBS delimiter
-        ]);
+        // Only the ACK for the re-send makes room
+        deliver(&mut illyria, &expected_ack_frame(Colour::Purple));
+        assert_eq!(illyria.link_state(), LinkState::Idle);
+        illyria.send(&Message::B(5)).unwrap();
     }
 
     #[test]
-    fn encode_b() {
-        let t = TestWriter {
-            out_tx_buffer: Vec::new(),
-        };
+    fn run_rx_n() {
+        let mut illyria = new_illyria(100);
+        // Purple A, then Blue B(5)
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
+        illyria
+            .access_reader()
+            .source
+            .extend(&[0, 5, 0x11, 5, 1, 5, 1, 1, 3, 0xD7, 0xF8, 0]);
 
-        let r = TestReader {
-            source: VecDeque::new(),
-        };
+        assert!(matches!(
+            illyria.run_rx_n(3),
+            Ok(RxProgress::BytesConsumed(3))
+        ));
+        assert!(matches!(
+            illyria.run_rx_n(100),
+            Ok(RxProgress::FrameReady(RxEvent::Message(Message::A)))
+        ));
+        // The frame is complete as soon as its checksum is in
+        assert_eq!(illyria.access_reader().source.len(), 13);
+        assert!(matches!(
+            illyria.run_rx_n(100),
+            Ok(RxProgress::FrameReady(RxEvent::Message(Message::B(5))))
+        ));
+        assert!(matches!(illyria.run_rx_n(100), Ok(RxProgress::Blocked)));
+        assert!(matches!(
+            illyria.run_rx_n(0),
+            Ok(RxProgress::BytesConsumed(0))
+        ));
+    }
 
-        let mut illyria = MyIllyria::new(t, r, 100);
+    #[test]
+    fn pause_and_resume() {
+        let pause = [0, 2, HEADER_PAUSE, 3, 0xF2, 0x37, 0];
+        let resume = [0, 2, HEADER_RESUME, 3, 0xEB, 0xEF, 0];
 
-        illyria.send(&Message::B(0x06070809)).unwrap();
-        for _ in 0..50 {
-            illyria.run_tx().unwrap();
+        // A frame too big for the receiver gets a PAUSE...
+        let mut sender = new_illyria(100);
+        let mut receiver: Illyria<Message, Message, TestWriter, TestReader, 66, 8> =
+            Illyria::new(TestWriter::new(), TestReader::new(), 100);
+        sender.send(&Message::E([1; 15])).unwrap();
+        send_frame_out(&mut sender);
+        let wire = core::mem::take(&mut sender.access_writer().out_tx_buffer);
+        receiver.access_reader().source.extend(wire);
+        while !receiver.access_reader().source.is_empty() {
+            assert!(matches!(receiver.run_rx(), Ok(None)));
         }
-        illyria.access_writer().check(&[
-            0,    // COBS delimiter
-            10,   // Gap to next zero
-            1,    // Frame type
-            5,    // Length
-            1,    // Payload 0
-            9,    // Payload 1
-            8,    // Payload 2
-            7,    // Payload 3
-            6,    // Payload 4
-            0x1B, // Checksum 0
-            0xF9, // Checksum 1
-            0,    // COBS delimiter
-        ]);
+        assert_eq!(receiver.flow_pending, Some(HEADER_PAUSE));
+        poll_tx(&mut receiver, 20);
+        receiver.access_writer().check(&pause);
+
+        // ...and a RESUME once a frame arrives which fits, after its ACK
+        receiver.access_writer().out_tx_buffer.clear();
+        receiver
+            .access_reader()
+            .source
+            .extend(&[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0]);
+        assert_eq!(run_until_idle(&mut receiver, 50), vec![Message::A]);
+        receiver
+            .access_writer()
+            .check(&[&expected_ack_frame(Colour::Purple)[..], &resume[..]].concat());
+
+        // A frame which doesn't even have room for its header gets one too
+        let mut receiver: Illyria<Message, Message, TestWriter, TestReader, 66, 1> =
+            Illyria::new(TestWriter::new(), TestReader::new(), 100);
+        deliver(
+            &mut receiver,
+            &[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0],
+        );
+        assert_eq!(receiver.flow_pending, Some(HEADER_PAUSE));
+
+        // While paused, the sender doesn't start a new message
+        let mut sender = new_illyria(100);
+        deliver(&mut sender, &pause);
+        assert!(sender.is_paused());
+        sender.send(&Message::A).unwrap();
+        send_frame_out(&mut sender);
+        sender.access_writer().check(&[]);
+
+        // Until the RESUME arrives
+        deliver(&mut sender, &resume);
+        assert!(!sender.is_paused());
+        poll_tx(&mut sender, 9);
+        sender
+            .access_writer()
+            .check(&[0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
+
+        // If the RESUME is lost, the pause ends when the frame in flight
+        // times out
+        deliver(&mut sender, &pause);
+        assert!(sender.is_paused());
+        sender.access_writer().out_tx_buffer.clear();
+        poll_tx(&mut sender, 110);
+        assert!(!sender.is_paused());
+        sender
+            .access_writer()
+            .check(&[0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
+
+        // Or when it's aborted, so the next message isn't held forever
+        deliver(&mut sender, &pause);
+        assert!(sender.is_paused());
+        sender.abort_pending();
+        assert!(!sender.is_paused());
+        sender.send(&Message::A).unwrap();
+        send_frame_out(&mut sender);
+        sender
+            .access_writer()
+            .check(&[0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
+
+        // With nothing in flight, a new message is only held for as long as
+        // we'd wait for an ACK
+        let mut sender = new_illyria(100);
+        deliver(&mut sender, &pause);
+        sender.send(&Message::A).unwrap();
+        poll_tx(&mut sender, 100);
+        sender.access_writer().check(&[]);
+        send_frame_out(&mut sender);
+        assert!(!sender.is_paused());
+        sender
+            .access_writer()
+            .check(&[0, 3, 1, 1, 3, 0x85, 0xC8, 0]);
     }
 
     #[test]
-    fn encode_c() {
-        let t = TestWriter {
-            out_tx_buffer: Vec::new(),
-        };
+    fn pause_while_rx_queue_full() {
+        let pause = [0, 2, HEADER_PAUSE, 3, 0xF2, 0x37, 0];
+        let resume = [0, 2, HEADER_RESUME, 3, 0xEB, 0xEF, 0];
+        let mut sender = new_illyria(100);
+        let mut receiver = new_illyria(100);
 
-        let r = TestReader {
-            source: VecDeque::new(),
-        };
+        // The receiver queues A, but the application doesn't take it yet
+        sender.send(&Message::A).unwrap();
+        send_frame_out(&mut sender);
+        let wire = core::mem::take(&mut sender.access_writer().out_tx_buffer);
+        // The closing delimiter is left until there's room
+        assert_eq!(receiver.run_rx_slice(&wire), wire.len() - 1);
 
-        let mut illyria = MyIllyria::new(t, r, 100);
+        // Its queue is full, so the ACK is followed by a PAUSE
+        poll_tx(&mut receiver, 20);
+        let reply = core::mem::take(&mut receiver.access_writer().out_tx_buffer);
+        assert_eq!(
+            reply,
+            [&expected_ack_frame(Colour::Purple)[..], &pause[..]].concat()
+        );
+        deliver(&mut sender, &reply);
+        assert_eq!(sender.take_tx_result(), Some(TxResult::Acked));
+        assert!(sender.is_paused());
 
-        illyria.send(&Message::C(true)).unwrap();
-        for _ in 0..50 {
-            illyria.run_tx().unwrap();
-        }
-        illyria.access_writer().check(&[
-            0,    // COBS delimiter
-            7,    // Gap to next zero
-            1,    // Frame type
-            2,    // Length
-            2,    // Payload 0
-            1,    // Payload 1
-            0x77, // Checksum 0
-            0xE4, // Checksum 1
-            0,    // COBS delimiter
-        ]);
+        // So B is held...
+        sender.send(&Message::B(5)).unwrap();
+        poll_tx(&mut sender, 50);
+        sender.access_writer().check(&[]);
+        poll_tx(&mut receiver, 20);
+        receiver.access_writer().check(&[]);
+
+        // ...until the application takes A, and the receiver sends a RESUME
+        assert!(matches!(
+            receiver.run_rx(),
+            Ok(Some(RxEvent::Message(Message::A)))
+        ));
+        assert_eq!(receiver.run_rx_slice(&wire[wire.len() - 1..]), 1);
+        poll_tx(&mut receiver, 20);
+        receiver.access_writer().check(&resume);
+        let reply = core::mem::take(&mut receiver.access_writer().out_tx_buffer);
+        deliver(&mut sender, &reply);
+        assert!(!sender.is_paused());
+        send_frame_out(&mut sender);
+        sender
+            .access_writer()
+            .check(&[0, 5, 0x11, 5, 1, 5, 1, 1, 3, 0xD7, 0xF8, 0]);
+
+        // If the application takes its message before the PAUSE goes, the
+        // PAUSE isn't sent at all
+        let wire = core::mem::take(&mut sender.access_writer().out_tx_buffer);
+        assert_eq!(receiver.run_rx_slice(&wire), wire.len() - 1);
+        assert!(matches!(
+            receiver.run_rx(),
+            Ok(Some(RxEvent::Message(Message::B(5))))
+        ));
+        poll_tx(&mut receiver, 20);
+        receiver
+            .access_writer()
+            .check(&expected_ack_frame(Colour::Purple));
     }
 
     #[test]
-    fn encode_full() {
-        let t = TestWriter {
-            out_tx_buffer: Vec::new(),
-        };
+    fn cobs_find_zero() {
+        assert_eq!(super::cobs_find_zero(&[]), 0);
+        assert_eq!(super::cobs_find_zero(&[0; 10]), 0);
+        assert_eq!(super::cobs_find_zero(&[1, 2, 0, 3]), 2);
+        // No zero - the end of the frame counts as one
+        assert_eq!(super::cobs_find_zero(&[1, 2, 3]), 3);
 
-        let r = TestReader {
-            source: VecDeque::new(),
-        };
+        // A zero just after a full block isn't part of it
+        let mut source = vec![1u8; 255];
+        source[254] = 0;
+        assert_eq!(super::cobs_find_zero(&source), cobs::MAX_RUN);
+        source[253] = 0;
+        assert_eq!(super::cobs_find_zero(&source), 253);
 
-        let mut illyria = MyIllyria::new(t, r, 100);
-        illyria.send(&Message::E([0; 15])).unwrap();
-        for _ in 0..50 {
-            illyria.run_tx().unwrap();
-        }
-        // Don't care what this looks like, just that it fits OK
+        // Nor is anything after it
+        assert_eq!(super::cobs_find_zero(&[1u8; 254]), cobs::MAX_RUN);
+        assert_eq!(super::cobs_find_zero(&[1u8; 300]), cobs::MAX_RUN);
     }
 
     #[test]
-    fn encode_too_big() {
-        let t = TestWriter {
-            out_tx_buffer: Vec::new(),
-        };
+    fn duplicates_dropped() {
+        // Blue A twice - our ACK for the first was lost
+        let mut illyria = new_illyria(100);
+        let blue = [0, 3, HEADER_BLUE_IFRAME, 1, 1, 2, 0x5D, 0];
+        illyria.access_reader().source.extend(&blue);
+        assert_eq!(run_until_idle(&mut illyria, 20), [Message::A]);
+        illyria.access_reader().source.extend(&blue);
+        while !illyria.access_reader().source.is_empty() {
+            assert!(matches!(illyria.run_rx(), Ok(None)));
+        }
+        assert_eq!(illyria.stats().duplicates_dropped, 1);
+        assert_eq!(illyria.stats().colour_mismatches, 1);
+        // It is ACKed again
+        assert_eq!(illyria.sframe_pending, Some(HEADER_ACK));
 
-        let r = TestReader {
-            source: VecDeque::new(),
-        };
+        // Purple A, then Red A when we wanted Blue. The far end has skipped
+        // a colour, which says nothing about our ACKs.
+        let mut illyria = new_illyria(100);
+        collect_events(
+            &mut illyria,
+            &[0, 3, HEADER_PURPLE_IFRAME, 1, 3, 0x85, 0xC8, 0],
+        );
+        collect_events(
+            &mut illyria,
+            &[0, 3, HEADER_RED_IFRAME, 1, 3, 0x86, 0xF3, 0],
+        );
+        assert_eq!(illyria.stats().duplicates_dropped, 1);
+        assert_eq!(illyria.stats().colour_mismatches, 0);
 
-        let mut illyria = MyIllyria::new(t, r, 10);
-        assert!(illyria.send(&Message::D([0; 16])).is_err());
+        // A numbered frame we already have is a duplicate too
+        let mut illyria = new_illyria(100);
+        let numbered = [0, 3, HEADER_SEQ_IFRAME, 2, 1, 3, 0x19, 0x2F, 0];
+        collect_events(&mut illyria, &numbered);
+        collect_events(&mut illyria, &numbered);
+        assert_eq!(illyria.stats().duplicates_dropped, 1);
+        assert_eq!(illyria.stats().colour_mismatches, 0);
     }
 }